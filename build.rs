@@ -0,0 +1,5 @@
+// Embedded migrations (`sqlx::migrate!`) are read at compile
+// time, so rebuild whenever one is added or changed
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
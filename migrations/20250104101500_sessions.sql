@@ -0,0 +1,19 @@
+-- Server side sessions. Every refresh token belongs to a
+-- session, and every access token (JWT) carries the id of
+-- the session it was issued for, so revoking a session
+-- invalidates both of them.
+CREATE TABLE sessions (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+
+    -- SHA-256(refresh token secret). Replaced every time
+    -- the refresh token is used (rotation)
+    refresh_hash BYTEA NOT NULL,
+
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_used_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions(user_id);
@@ -0,0 +1,9 @@
+-- Argon2id PHC string ($argon2id$v=19$m=..,t=..,p=..$salt$hash)
+ALTER TABLE users
+ADD COLUMN password_hash TEXT;
+
+-- The legacy SHA-256(pass + salt + pepper) columns are only
+-- kept for accounts which haven't logged in since Argon2 was
+-- introduced. They are cleared on the next successful login.
+ALTER TABLE users ALTER COLUMN hash DROP NOT NULL;
+ALTER TABLE users ALTER COLUMN salt DROP NOT NULL;
@@ -0,0 +1,24 @@
+ALTER TABLE users
+ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT false;
+
+-- Single use tokens which are mailed to users, e.g for
+-- verifying their email address or resetting their password.
+CREATE TABLE user_tokens (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+
+    -- 'verify_email' or 'reset_password'
+    purpose TEXT NOT NULL,
+
+    -- SHA-256(token), the token itself is only ever in the email
+    token_hash BYTEA UNIQUE NOT NULL,
+
+    -- The address the token was sent to
+    email TEXT NOT NULL,
+
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX user_tokens_user_id_idx ON user_tokens(user_id);
@@ -0,0 +1,22 @@
+-- TOTP (RFC 6238) two factor authentication. The secret is
+-- set when enrollment begins, but only counts once the user
+-- has confirmed a code from their authenticator app.
+ALTER TABLE users
+ADD COLUMN totp_secret TEXT,
+ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT false,
+
+-- The 30 second time step of the last accepted code, so
+-- that the same code can't be used twice
+ADD COLUMN totp_last_step BIGINT;
+
+-- One time codes for when the authenticator app is lost
+CREATE TABLE recovery_codes (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+
+    -- SHA-256(code)
+    code_hash BYTEA NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes(user_id);
@@ -0,0 +1,20 @@
+-- Personal access tokens for bots and scripts, so they
+-- don't have to sign in with a password
+CREATE TABLE api_tokens (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    name VARCHAR(50) NOT NULL,
+
+    -- SHA-256(token), the token is only shown once on creation
+    token_hash BYTEA UNIQUE NOT NULL,
+
+    -- e.g {read, post:write}
+    scopes TEXT[] NOT NULL,
+
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires_at TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX api_tokens_user_id_idx ON api_tokens(user_id);
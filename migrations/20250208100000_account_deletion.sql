@@ -0,0 +1,27 @@
+-- Deleting a user should take their follows and posts
+-- with them, neither of these FKs had an ON DELETE action
+ALTER TABLE follows
+DROP CONSTRAINT IF EXISTS follows_follower_id_fkey,
+DROP CONSTRAINT IF EXISTS follows_followee_id_fkey;
+
+ALTER TABLE follows
+ADD CONSTRAINT follows_follower_id_fkey
+FOREIGN KEY (follower_id) REFERENCES users(id) ON DELETE CASCADE,
+ADD CONSTRAINT follows_followee_id_fkey
+FOREIGN KEY (followee_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE posts
+DROP CONSTRAINT IF EXISTS posts_poster_id_fkey;
+
+ALTER TABLE posts
+ADD CONSTRAINT posts_poster_id_fkey
+FOREIGN KEY (poster_id) REFERENCES users(id) ON DELETE CASCADE;
+
+-- Set when the user asks for their account to be deleted. The
+-- account is purged once the grace period has passed, unless
+-- they sign in again before that.
+ALTER TABLE users
+ADD COLUMN deletion_requested_at TIMESTAMPTZ;
+
+CREATE INDEX users_deletion_requested_at_idx ON users(deletion_requested_at)
+WHERE deletion_requested_at IS NOT NULL;
@@ -0,0 +1,69 @@
+-- Feeds are paginated by (created_at, id) or (total_likes, id)
+CREATE INDEX posts_created_at_id_idx ON posts(created_at DESC, id DESC);
+CREATE INDEX posts_total_likes_id_idx ON posts(total_likes DESC, id DESC);
+CREATE INDEX posts_poster_id_created_at_idx ON posts(poster_id, created_at DESC, id DESC);
+CREATE INDEX posts_replies_to_idx ON posts(replies_to);
+CREATE INDEX post_opinions_post_id_idx ON post_opinions(post_id);
+
+-- Same as before, but STABLE (functions are VOLATILE by default,
+-- which stops postgres from inlining them) and without the set
+-- returning is_not_null. Both made every query over it build the
+-- whole table before filtering and limiting it.
+CREATE OR REPLACE FUNCTION get_posts_default(user_id_input BIGINT)
+RETURNS TABLE (
+    id BIGINT, content TEXT, created_at TIMESTAMPTZ,
+    total_likes BIGINT, total_replies BIGINT,
+    poster_id BIGINT,
+    replies_to BIGINT,
+    citation JSONB,
+    
+    user_id BIGINT, displayname TEXT, handle TEXT,
+    liked BOOLEAN, bookmarked BOOLEAN, is_followed BOOLEAN,
+    top_opinions JSONB
+) AS $$
+    WITH ranked_opinions AS (
+        SELECT
+            po.post_id,
+            po.id AS opinion_id,
+            po.opinion,
+            po.votes,
+            EXISTS (
+                SELECT 1
+                FROM post_opinion_votes pov
+                WHERE pov.opinion_id = po.id AND pov.user_id = user_id_input
+            ) AS voted,
+            ROW_NUMBER() OVER (PARTITION BY po.post_id ORDER BY po.votes DESC) AS rank
+        FROM post_opinions po
+    ), top_opinions_cte AS (
+        SELECT
+            post_id,
+            jsonb_agg(jsonb_build_object(
+                'opinion', opinion,
+                'votes', votes,
+                'voted', voted,
+                'opinion_id', opinion_id
+            )) AS opinions
+        FROM ranked_opinions
+        WHERE rank <= 5
+        GROUP BY post_id
+    )
+    SELECT
+        posts.id, posts.content, posts.created_at,
+        posts.total_likes, posts.total_replies,
+        posts.poster_id, posts.replies_to, posts.citation,
+        users.id AS user_id, users.displayname, users.handle,
+        post_likes.user_id IS NOT NULL AS liked,
+        post_bookmarks.user_id IS NOT NULL AS bookmarked,
+        follows.follower_id IS NOT NULL AS is_followed,
+        COALESCE((
+            SELECT opinions
+            FROM top_opinions_cte top_op
+            WHERE top_op.post_id = posts.id
+        ), '[]') AS top_opinions
+    FROM
+        posts
+        JOIN users ON posts.poster_id = users.id
+        LEFT JOIN post_likes     ON post_likes.post_id     = posts.id AND post_likes.user_id     = user_id_input
+        LEFT JOIN post_bookmarks ON post_bookmarks.post_id = posts.id AND post_bookmarks.user_id = user_id_input
+        LEFT JOIN follows        ON follows.follower_id    = user_id_input AND follows.followee_id = posts.poster_id;
+$$ LANGUAGE sql STABLE;
@@ -0,0 +1,8 @@
+-- Full text search over post content. Kept up to date by
+-- postgres itself, and indexed so searching doesn't have
+-- to scan every post.
+ALTER TABLE posts
+ADD COLUMN search_vector TSVECTOR
+GENERATED ALWAYS AS (to_tsvector('english', content)) STORED;
+
+CREATE INDEX posts_search_vector_idx ON posts USING GIN (search_vector);
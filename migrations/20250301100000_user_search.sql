@@ -0,0 +1,8 @@
+-- Trigram indexes for fuzzy matching handles and display
+-- names in user search, and a prefix index on handles for
+-- @mention autocompletion
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX users_handle_trgm_idx ON users USING GIN (handle gin_trgm_ops);
+CREATE INDEX users_displayname_trgm_idx ON users USING GIN (displayname gin_trgm_ops);
+CREATE INDEX users_handle_prefix_idx ON users (handle varchar_pattern_ops);
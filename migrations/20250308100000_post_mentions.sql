@@ -0,0 +1,87 @@
+-- @mentions of users in posts. Only handles which belonged
+-- to someone when the post was published are stored. The
+-- offsets are grapheme offsets into posts.content (like
+-- citations), start being the @ and end exclusive.
+CREATE TABLE post_mentions (
+    post_id BIGINT REFERENCES posts(id) ON DELETE CASCADE NOT NULL,
+    user_id BIGINT REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    start_offset INT NOT NULL,
+    end_offset INT NOT NULL,
+
+    PRIMARY KEY (post_id, start_offset)
+);
+
+CREATE INDEX post_mentions_user_id_idx ON post_mentions(user_id, post_id);
+
+-- Posts now come with their mentions. The return type
+-- changes, so it has to be dropped first.
+DROP FUNCTION get_posts_default(BIGINT);
+CREATE FUNCTION get_posts_default(user_id_input BIGINT)
+RETURNS TABLE (
+    id BIGINT, content TEXT, created_at TIMESTAMPTZ,
+    total_likes BIGINT, total_replies BIGINT,
+    poster_id BIGINT,
+    replies_to BIGINT,
+    citation JSONB,
+    
+    user_id BIGINT, displayname TEXT, handle TEXT,
+    liked BOOLEAN, bookmarked BOOLEAN, is_followed BOOLEAN,
+    top_opinions JSONB, mentions JSONB
+) AS $$
+    WITH ranked_opinions AS (
+        SELECT
+            po.post_id,
+            po.id AS opinion_id,
+            po.opinion,
+            po.votes,
+            EXISTS (
+                SELECT 1
+                FROM post_opinion_votes pov
+                WHERE pov.opinion_id = po.id AND pov.user_id = user_id_input
+            ) AS voted,
+            ROW_NUMBER() OVER (PARTITION BY po.post_id ORDER BY po.votes DESC) AS rank
+        FROM post_opinions po
+    ), top_opinions_cte AS (
+        SELECT
+            post_id,
+            jsonb_agg(jsonb_build_object(
+                'opinion', opinion,
+                'votes', votes,
+                'voted', voted,
+                'opinion_id', opinion_id
+            )) AS opinions
+        FROM ranked_opinions
+        WHERE rank <= 5
+        GROUP BY post_id
+    )
+    SELECT
+        posts.id, posts.content, posts.created_at,
+        posts.total_likes, posts.total_replies,
+        posts.poster_id, posts.replies_to, posts.citation,
+        users.id AS user_id, users.displayname, users.handle,
+        post_likes.user_id IS NOT NULL AS liked,
+        post_bookmarks.user_id IS NOT NULL AS bookmarked,
+        follows.follower_id IS NOT NULL AS is_followed,
+        COALESCE((
+            SELECT opinions
+            FROM top_opinions_cte top_op
+            WHERE top_op.post_id = posts.id
+        ), '[]') AS top_opinions,
+        COALESCE((
+            SELECT jsonb_agg(jsonb_build_object(
+                'user_id', post_mentions.user_id,
+                'handle', mentioned.handle,
+                'start', post_mentions.start_offset,
+                'end', post_mentions.end_offset
+            ) ORDER BY post_mentions.start_offset)
+            FROM post_mentions
+                JOIN users mentioned ON mentioned.id = post_mentions.user_id
+            WHERE post_mentions.post_id = posts.id
+        ), '[]') AS mentions
+    FROM
+        posts
+        JOIN users ON posts.poster_id = users.id
+        LEFT JOIN post_likes     ON post_likes.post_id     = posts.id AND post_likes.user_id     = user_id_input
+        LEFT JOIN post_bookmarks ON post_bookmarks.post_id = posts.id AND post_bookmarks.user_id = user_id_input
+        LEFT JOIN follows        ON follows.follower_id    = user_id_input AND follows.followee_id = posts.poster_id;
+$$ LANGUAGE sql STABLE;
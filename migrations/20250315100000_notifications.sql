@@ -0,0 +1,29 @@
+-- Something someone (the actor) did which concerns a user.
+-- post_id is the user's own post it was done to (liked,
+-- replied to, quoted or voted on), source_post_id the post
+-- it was done with (the reply, quote or post mentioning
+-- them). Both are null for follows.
+CREATE TABLE notifications (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    actor_id BIGINT REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('like', 'reply', 'follow', 'mention', 'quote', 'opinion_vote')),
+    post_id BIGINT REFERENCES posts(id) ON DELETE CASCADE,
+    source_post_id BIGINT REFERENCES posts(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    read_at TIMESTAMPTZ
+);
+
+-- Liking, unliking and liking again only notifies once
+CREATE UNIQUE INDEX notifications_unique_idx ON notifications (
+    user_id, actor_id, kind, COALESCE(post_id, 0), COALESCE(source_post_id, 0)
+);
+CREATE INDEX notifications_user_id_created_at_idx ON notifications(user_id, created_at DESC);
+CREATE INDEX notifications_unread_idx ON notifications(user_id) WHERE read_at IS NULL;
+
+-- Kinds of notifications a user doesn't want
+CREATE TABLE muted_notification_kinds (
+    user_id BIGINT REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    kind TEXT NOT NULL,
+    PRIMARY KEY (user_id, kind)
+);
@@ -0,0 +1,60 @@
+-- Who can start a conversation with a user, everyone
+-- or only people they follow
+ALTER TABLE users
+ADD COLUMN dm_policy TEXT NOT NULL DEFAULT 'everyone'
+    CHECK (dm_policy IN ('everyone', 'following'));
+
+CREATE TABLE blocks (
+    blocker_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    blocked_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (blocker_id, blocked_id),
+    CHECK (blocker_id <> blocked_id)
+);
+
+CREATE INDEX blocks_blocked_id_idx ON blocks(blocked_id);
+
+CREATE TABLE conversations (
+    id BIGSERIAL PRIMARY KEY,
+    is_group BOOLEAN NOT NULL,
+
+    -- The two member ids of a one-to-one conversation,
+    -- lowest first, so that each pair only has one
+    direct_key TEXT UNIQUE,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    -- Conversations are listed by their latest message
+    last_message_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CHECK (is_group = (direct_key IS NULL))
+);
+
+CREATE TABLE conversation_members (
+    conversation_id BIGINT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    -- Members only see messages sent after they joined
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_read_message_id BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (conversation_id, user_id)
+);
+
+CREATE INDEX conversation_members_user_id_idx ON conversation_members(user_id);
+
+CREATE TABLE messages (
+    id BIGSERIAL PRIMARY KEY,
+    conversation_id BIGINT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    sender_id BIGINT REFERENCES users(id) ON DELETE SET NULL,
+    content TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX messages_conversation_id_idx ON messages(conversation_id, created_at DESC, id DESC);
+
+-- Messages a member has deleted for themselves
+CREATE TABLE hidden_messages (
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    message_id BIGINT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, message_id)
+);
@@ -0,0 +1,96 @@
+-- Deleting a post only marks it as deleted, so that the
+-- replies underneath survive. get_posts_default shows it
+-- as a tombstone, and its content is purged for good after
+-- a while (see Post::spawn_purge_task).
+ALTER TABLE posts
+ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE INDEX posts_deleted_at_idx ON posts(deleted_at)
+WHERE deleted_at IS NOT NULL;
+
+-- Posts now come with if they're deleted, in which case
+-- everything but the counters and where they are in the
+-- thread is hidden
+DROP FUNCTION get_posts_default(BIGINT);
+CREATE FUNCTION get_posts_default(user_id_input BIGINT)
+RETURNS TABLE (
+    id BIGINT, content TEXT, created_at TIMESTAMPTZ,
+    edited_at TIMESTAMPTZ, revision_count INT, deleted BOOLEAN,
+    total_likes BIGINT, total_replies BIGINT, total_reposts BIGINT,
+    poster_id BIGINT,
+    replies_to BIGINT,
+    citation JSONB,
+    
+    user_id BIGINT, displayname TEXT, handle TEXT,
+    liked BOOLEAN, bookmarked BOOLEAN, reposted BOOLEAN, is_followed BOOLEAN,
+    top_opinions JSONB, mentions JSONB
+) AS $$
+    WITH ranked_opinions AS (
+        SELECT
+            po.post_id,
+            po.id AS opinion_id,
+            po.opinion,
+            po.votes,
+            EXISTS (
+                SELECT 1
+                FROM post_opinion_votes pov
+                WHERE pov.opinion_id = po.id AND pov.user_id = user_id_input
+            ) AS voted,
+            ROW_NUMBER() OVER (PARTITION BY po.post_id ORDER BY po.votes DESC) AS rank
+        FROM post_opinions po
+    ), top_opinions_cte AS (
+        SELECT
+            post_id,
+            jsonb_agg(jsonb_build_object(
+                'opinion', opinion,
+                'votes', votes,
+                'voted', voted,
+                'opinion_id', opinion_id
+            )) AS opinions
+        FROM ranked_opinions
+        WHERE rank <= 5
+        GROUP BY post_id
+    )
+    SELECT
+        posts.id,
+        CASE WHEN deleted THEN 'This post was deleted' ELSE posts.content END,
+        posts.created_at,
+        CASE WHEN deleted THEN NULL ELSE posts.edited_at END,
+        CASE WHEN deleted THEN 0 ELSE posts.revision_count END,
+        deleted,
+        posts.total_likes, posts.total_replies, posts.total_reposts,
+        CASE WHEN deleted THEN NULL ELSE posts.poster_id END,
+        posts.replies_to,
+        CASE WHEN deleted THEN NULL ELSE posts.citation END,
+        CASE WHEN deleted THEN NULL ELSE users.id END AS user_id,
+        CASE WHEN deleted THEN NULL ELSE users.displayname END,
+        CASE WHEN deleted THEN NULL ELSE users.handle END,
+        post_likes.user_id IS NOT NULL AS liked,
+        post_bookmarks.user_id IS NOT NULL AS bookmarked,
+        reposts.user_id IS NOT NULL AS reposted,
+        follows.follower_id IS NOT NULL AND NOT deleted AS is_followed,
+        CASE WHEN deleted THEN '[]' ELSE COALESCE((
+            SELECT opinions
+            FROM top_opinions_cte top_op
+            WHERE top_op.post_id = posts.id
+        ), '[]') END AS top_opinions,
+        COALESCE((
+            SELECT jsonb_agg(jsonb_build_object(
+                'user_id', post_mentions.user_id,
+                'handle', mentioned.handle,
+                'start', post_mentions.start_offset,
+                'end', post_mentions.end_offset
+            ) ORDER BY post_mentions.start_offset)
+            FROM post_mentions
+                JOIN users mentioned ON mentioned.id = post_mentions.user_id
+            WHERE post_mentions.post_id = posts.id
+        ), '[]') AS mentions
+    FROM
+        posts
+        CROSS JOIN LATERAL (SELECT posts.deleted_at IS NOT NULL AS deleted) tombstone
+        JOIN users ON posts.poster_id = users.id
+        LEFT JOIN post_likes     ON post_likes.post_id     = posts.id AND post_likes.user_id     = user_id_input
+        LEFT JOIN post_bookmarks ON post_bookmarks.post_id = posts.id AND post_bookmarks.user_id = user_id_input
+        LEFT JOIN reposts        ON reposts.post_id        = posts.id AND reposts.user_id        = user_id_input
+        LEFT JOIN follows        ON follows.follower_id    = user_id_input AND follows.followee_id = posts.poster_id;
+$$ LANGUAGE sql STABLE;
@@ -0,0 +1,12 @@
+-- Deleting a post no longer takes the replies underneath with
+-- it. Posts are only ever hard-deleted once nothing replies to
+-- them anymore (see Post::purge_deleted), so anything else is a
+-- bug and should fail.
+ALTER TABLE posts
+DROP CONSTRAINT IF EXISTS posts_replies_to_fkey;
+
+ALTER TABLE posts
+ADD CONSTRAINT posts_replies_to_fkey
+FOREIGN KEY (replies_to)
+REFERENCES posts(id);
+
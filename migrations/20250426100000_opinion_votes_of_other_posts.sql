@@ -0,0 +1,13 @@
+-- Votes used to be stored with whatever post id the client sent,
+-- so one user could vote for the same opinion several times.
+-- Those votes are dropped and the counts recomputed.
+DELETE FROM post_opinion_votes
+USING post_opinions
+WHERE post_opinions.id = post_opinion_votes.opinion_id
+    AND post_opinions.post_id <> post_opinion_votes.post_id;
+
+UPDATE post_opinions
+SET votes = (
+    SELECT COUNT(*) FROM post_opinion_votes
+    WHERE post_opinion_votes.opinion_id = post_opinions.id
+);
@@ -0,0 +1,5 @@
+-- Quotes are looked up by the post they cite when that post is
+-- deleted, to empty their content_slice
+CREATE INDEX posts_cited_post_id_idx
+ON posts (((citation->>'post_id')::bigint))
+WHERE citation IS NOT NULL;
@@ -0,0 +1,224 @@
+//! Runtime configuration. Loaded once at startup from
+//! an optional TOML file (`CONFIG_FILE`, defaults to
+//! `config.toml` if it exists) and environment variables,
+//! which take precedence over the file. See
+//! `config.example.toml` for every setting.
+//!
+//! The only command line flag is `--migrate`, which
+//! is the same as `migrate = true`.
+
+/* Imports */
+use std::{env, fmt::Display, path::PathBuf, str::FromStr, sync::OnceLock};
+use argon2::Params;
+use serde::Deserialize;
+
+/* Constants */
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Every setting of the server
+#[derive(Debug)]
+pub struct Config {
+    pub database_url: String,
+
+    /// If pending migrations should be applied at startup,
+    /// see `utils::migrate`
+    pub migrate: bool,
+
+    /// Where the frontend lives, used for links in emails
+    pub frontend_url: String,
+
+    /// Secret mixed into every password hash
+    pub pepper: String,
+    pub jwt_token_key: String,
+
+    /// How many days a session (and its refresh token) lives
+    /// without being used before it expires
+    pub jwt_token_maxage: u64,
+
+    pub debug_log: bool,
+    pub debug_log_actix: bool,
+
+    pub bind_address: String,
+    pub port: u16,
+
+    /// Largest request body accepted, in bytes
+    pub max_request_size: usize,
+
+    /// Where uploaded profile images are stored
+    pub profile_image_dir: PathBuf,
+
+    /// If live updates (see `utils::hub`) should go through
+    /// postgres, needed when running more than one instance
+    pub stream_fanout: bool,
+
+    /// For how many minutes after publishing a post can
+    /// be edited
+    pub post_edit_window: u32,
+
+    /// See `utils::mailer::from_url`
+    pub mailer: String,
+    pub mail_from: String,
+
+    /// Argon2id cost parameters. Defaults follow the OWASP
+    /// recommendation (19 MiB memory, 2 iterations, 1 lane).
+    /// Changing them makes existing hashes get upgraded on
+    /// their next successful login.
+    pub argon2_m_cost: u32,
+    pub argon2_t_cost: u32,
+    pub argon2_p_cost: u32,
+}
+
+/// The TOML file, where everything is optional
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    database_url: Option<String>,
+    migrate: Option<bool>,
+    frontend_url: Option<String>,
+    pepper: Option<String>,
+    jwt_token_key: Option<String>,
+    jwt_token_maxage: Option<u64>,
+    debug_log: Option<bool>,
+    debug_log_actix: Option<bool>,
+    bind_address: Option<String>,
+    port: Option<u16>,
+    max_request_size: Option<usize>,
+    profile_image_dir: Option<PathBuf>,
+    stream_fanout: Option<bool>,
+    post_edit_window: Option<u32>,
+    mailer: Option<String>,
+    mail_from: Option<String>,
+    argon2_m_cost: Option<u32>,
+    argon2_t_cost: Option<u32>,
+    argon2_p_cost: Option<u32>,
+}
+
+/// Why the config couldn't be loaded
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl Config {
+    /// Loads and validates the config, and makes it available
+    /// through `config()`. Should be called first thing in main.
+    pub fn init() -> Result<&'static Self, ConfigError> {
+        let config = Self::load()?;
+        Ok(CONFIG.get_or_init(|| config))
+    }
+
+    fn load() -> Result<Self, ConfigError> {
+        let file = Self::read_file()?;
+        let config = Self {
+            database_url: required("DATABASE_URL", var("DATABASE_URL", file.database_url)?)?,
+            migrate: env::args().any(|e| e == "--migrate")
+                || var("MIGRATE", file.migrate)?.unwrap_or(false),
+            frontend_url: required("FRONTEND_URL", var("FRONTEND_URL", file.frontend_url)?)?,
+            pepper: required("PEPPER", var("PEPPER", file.pepper)?)?,
+            jwt_token_key: required("JWT_TOKEN_KEY", var("JWT_TOKEN_KEY", file.jwt_token_key)?)?,
+            jwt_token_maxage: var("JWT_TOKEN_MAXAGE", file.jwt_token_maxage)?.unwrap_or(7),
+            debug_log: var("DEBUG_LOG", file.debug_log)?.unwrap_or(false),
+            debug_log_actix: var("DEBUG_LOG_ACTIX", file.debug_log_actix)?.unwrap_or(false),
+            bind_address: var("BIND_ADDRESS", file.bind_address)?.unwrap_or(String::from("0.0.0.0")),
+            port: var("PORT", file.port)?.unwrap_or(8081),
+            max_request_size: var("MAX_REQUEST_SIZE", file.max_request_size)?.unwrap_or(1_048_576 * 3), // 3MB
+            profile_image_dir: var("PROFILE_IMAGE_DIR", file.profile_image_dir)?
+                .unwrap_or(PathBuf::from("/usr/src/app/assets/images/profile")),
+            stream_fanout: var("STREAM_FANOUT", file.stream_fanout)?.unwrap_or(false),
+            post_edit_window: var("POST_EDIT_WINDOW", file.post_edit_window)?.unwrap_or(30),
+            mailer: var("MAILER", file.mailer)?.unwrap_or(String::from("file://./outbox")),
+            mail_from: var("MAIL_FROM", file.mail_from)?.unwrap_or(String::from("Twitter2 <no-reply@twitter2.local>")),
+            argon2_m_cost: var("ARGON2_M_COST", file.argon2_m_cost)?.unwrap_or(19_456),
+            argon2_t_cost: var("ARGON2_T_COST", file.argon2_t_cost)?.unwrap_or(2),
+            argon2_p_cost: var("ARGON2_P_COST", file.argon2_p_cost)?.unwrap_or(1),
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// `CONFIG_FILE` has to exist if set, the default doesn't
+    fn read_file() -> Result<FileConfig, ConfigError> {
+        let (path, explicit) = match env::var("CONFIG_FILE") {
+            Ok(path) => (path, true),
+            Err(_) => (String::from(DEFAULT_CONFIG_FILE), false)
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|e| ConfigError(format!("Invalid config file {path}: {e}"))),
+            Err(e) if explicit || e.kind() != std::io::ErrorKind::NotFound =>
+                Err(ConfigError(format!("Could not read config file {path}: {e}"))),
+            Err(_) => Ok(FileConfig::default())
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |name: &str, reason: &str| Err(ConfigError(format!("Invalid {name}: {reason}")));
+
+        if !self.database_url.starts_with("postgres://") && !self.database_url.starts_with("postgresql://") {
+            return invalid("DATABASE_URL", "must be a postgres:// url");
+        }
+        if !self.frontend_url.starts_with("http://") && !self.frontend_url.starts_with("https://") {
+            return invalid("FRONTEND_URL", "must be an http:// or https:// url");
+        }
+        if self.frontend_url.ends_with('/') {
+            return invalid("FRONTEND_URL", "must not end with a /");
+        }
+        if self.pepper.is_empty() {
+            return invalid("PEPPER", "must not be empty");
+        }
+        if self.jwt_token_key.is_empty() {
+            return invalid("JWT_TOKEN_KEY", "must not be empty");
+        }
+        if self.jwt_token_maxage == 0 {
+            return invalid("JWT_TOKEN_MAXAGE", "must be at least 1 day");
+        }
+        if self.max_request_size == 0 {
+            return invalid("MAX_REQUEST_SIZE", "must be larger than 0");
+        }
+        if self.profile_image_dir.exists() && !self.profile_image_dir.is_dir() {
+            return invalid("PROFILE_IMAGE_DIR", "is not a directory");
+        }
+        if let Err(e) = self.argon2_params() {
+            return invalid("ARGON2_*_COST", &e.to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn argon2_params(&self) -> Result<Params, argon2::Error> {
+        Params::new(self.argon2_m_cost, self.argon2_t_cost, self.argon2_p_cost, None)
+    }
+}
+
+/// The loaded config. Loads it if `Config::init` hasn't been
+/// called yet (e.g in tests), which panics if it's invalid.
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(|| Config::load().unwrap_or_else(|e| panic!("{e}")))
+}
+
+/// The environment variable `name` if set, otherwise
+/// the value from the config file
+fn var<T: FromStr>(name: &str, file: Option<T>) -> Result<Option<T>, ConfigError>
+where T::Err: Display
+{
+    match env::var(name) {
+        Ok(value) => value.parse::<T>()
+            .map(Some)
+            .map_err(|e| ConfigError(format!("Invalid {name} \"{value}\": {e}"))),
+        Err(_) => Ok(file)
+    }
+}
+
+fn required<T>(name: &str, value: Option<T>) -> Result<T, ConfigError> {
+    value.ok_or_else(|| ConfigError(format!(
+        "Missing {name}, set it as an environment variable or as `{}` in the config file",
+        name.to_lowercase()
+    )))
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
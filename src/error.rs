@@ -2,34 +2,50 @@ use std::fmt::Display;
 
 use actix_web::{body::BoxBody, http::StatusCode, HttpResponse, Responder, ResponseError};
 use serde_json::json;
+use crate::middleware::rate_limit::FailedAttempt;
 
 
 /// The error struct we use in this application
 #[derive(Debug)]
 pub struct Error {
     code: StatusCode,
-    description: String
+    description: String,
+
+    /// See `failed_attempt`
+    failed_attempt: bool
 }
 
 impl Error {
     /// Creates a new error with code -1 (unspecified)
     pub fn new(description: impl ToString) -> Self {
-        Self { code: StatusCode::NOT_ACCEPTABLE, description: description.to_string() }
+        Self { code: StatusCode::NOT_ACCEPTABLE, description: description.to_string(), failed_attempt: false }
     }
 
     /// Creates a new error with code specified
     pub fn new_with_code(description: impl ToString, code: StatusCode) -> Self {
-        Self { code, description: description.to_string() }
+        Self { code, description: description.to_string(), failed_attempt: false }
+    }
+
+    /// A wrong password, code or token. Unlike other errors these
+    /// count as failed attempts in the rate limiter (see
+    /// `middleware::rate_limit`).
+    pub fn failed_attempt(description: impl ToString) -> Self {
+        Self { failed_attempt: true, ..Self::new(description) }
     }
 }
 
 impl ResponseError for Error {
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        HttpResponse::build(self.code)
+        let mut response = HttpResponse::build(self.code)
             .json(json!({
                 "status": self.code.as_str(),
                 "description": self.description
-            }))
+            }));
+
+        if self.failed_attempt {
+            response.extensions_mut().insert(FailedAttempt);
+        }
+        response
     }
     fn status_code(&self) -> StatusCode { self.code }
 }
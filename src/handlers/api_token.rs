@@ -0,0 +1,59 @@
+//! This file handles endpoints for managing personal
+//! access tokens (API tokens for bots and scripts)
+
+/* Imports */
+use actix_web::{get, post, web, HttpResponse, Responder};
+use serde::Deserialize;
+use serde_json::json;
+use crate::{error::Error, middleware::auth::{scope, RequireScope}, models::{api_token::{ApiScope, ApiToken}, user::UserIdReq}, AppData};
+
+/* Structs */
+#[derive(Deserialize)]
+struct CreateTokenRequest {
+    name: String,
+    scopes: Vec<ApiScope>,
+
+    /// Never expires if not set
+    expires_in_days: Option<i32>
+}
+#[derive(Deserialize)]
+struct RevokeTokenRequest { token_id: i64 }
+
+/// Creates a token, responds with it. It can't be
+/// retrieved again after this.
+#[post("/create")]
+pub async fn create(
+    data: web::Data<AppData>, body: web::Json<CreateTokenRequest>,
+    user_id: UserIdReq, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    let body = body.into_inner();
+    if body.expires_in_days.is_some_and(|e| e < 1) {
+        return Err(Error::new("Tokens must be valid for at least one day"));
+    }
+
+    ApiToken::create(&data.db, user_id.0, body.name, body.scopes, body.expires_in_days)
+        .await
+        .map(|e| HttpResponse::Ok().json(json!({ "token": e })))
+}
+
+/// Lists the tokens of the user requesting
+#[get("/list")]
+pub async fn list(
+    data: web::Data<AppData>,
+    user_id: UserIdReq, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    ApiToken::list(&data.db, user_id.0)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
+}
+
+/// Revokes one of the tokens of the user requesting
+#[post("/revoke")]
+pub async fn revoke(
+    data: web::Data<AppData>, body: web::Json<RevokeTokenRequest>,
+    user_id: UserIdReq, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    ApiToken::revoke(&data.db, user_id.0, body.token_id)
+        .await
+        .map(|_| HttpResponse::Ok().finish())
+}
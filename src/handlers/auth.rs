@@ -2,7 +2,7 @@
 use actix_web::{error::{ErrorInternalServerError, HttpError}, get, post, web, HttpRequest, HttpResponse, Responder};
 use serde::Deserialize;
 use serde_json::json;
-use crate::{error::Error, models::user::User, AppData};
+use crate::{error::Error, middleware::auth::{scope, RequireScope}, models::{session::Session, user::{User, UserIdReq}}, AppData};
 
 /* Structs */
 #[derive(Deserialize)]
@@ -12,35 +12,131 @@ struct SignUpRequest {
     email: String, password: String, handle: String,
     displayname: String
 }
+#[derive(Deserialize)]
+struct RefreshRequest { refresh_token: String }
+#[derive(Deserialize)]
+struct TokenRequest { token: String }
+#[derive(Deserialize)]
+struct ForgotPasswordRequest { email: String }
+#[derive(Deserialize)]
+struct ResetPasswordRequest { token: String, password: String }
 
-/// Responds with the JWT of the user
+/// Responds with the JWT and refresh token of the user
 #[post("/login")]
 pub async fn login(
     req: HttpRequest, data: web::Data<AppData>,
     body: web::Json<LoginRequest>
 ) -> impl Responder {
-    let jwt = User::login(&data.db, &body.email, &body.password)
+    let tokens = User::login(&data.db, &body.email, &body.password)
         .await?;
 
     Ok::<_, Error>(
         HttpResponse::Ok()
-            .json(json!({ "token": jwt }))
+            .json(tokens)
     )
 }
 
-/// Responds with the JWT of the user
+/// Responds with the JWT and refresh token of the user
 #[post("/sign-up")]
 pub async fn sign_up(
     req: HttpRequest, data: web::Data<AppData>,
     body: web::Json<SignUpRequest>
 ) -> impl Responder {
-    let jwt = User::create_account(
-        &data.db, body.handle.clone(), body.displayname.clone(),
-        body.email.clone(), body.password.clone()).await?;
+    let body = body.into_inner();
+    let tokens = User::create_account(
+        &data.db, data.mailer.as_ref(), body.handle,
+        body.displayname, body.email, body.password).await?;
 
     Ok::<_, Error>(
         HttpResponse::Ok()
-            .json(json!({ "token": jwt }))
+            .json(tokens)
     )
 }
 
+/// Swaps a refresh token for a new JWT and refresh
+/// token. The refresh token sent can't be used again.
+#[post("/refresh")]
+pub async fn refresh(
+    data: web::Data<AppData>,
+    body: web::Json<RefreshRequest>
+) -> impl Responder {
+    let tokens = Session::refresh(&data.db, &body.refresh_token)
+        .await?;
+
+    Ok::<_, Error>(
+        HttpResponse::Ok()
+            .json(tokens)
+    )
+}
+
+/// Revokes the session of the refresh token sent
+#[post("/logout")]
+pub async fn logout(
+    data: web::Data<AppData>,
+    body: web::Json<RefreshRequest>
+) -> impl Responder {
+    Session::revoke(&data.db, &body.refresh_token)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+/// Revokes every session of the user requesting
+#[post("/logout-all")]
+pub async fn logout_all(
+    data: web::Data<AppData>,
+    user_id: UserIdReq, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    Session::revoke_all(&data.db, user_id.0)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+/// Verifies the email address that the token was mailed to
+#[post("/verify-email")]
+pub async fn verify_email(
+    data: web::Data<AppData>,
+    body: web::Json<TokenRequest>
+) -> impl Responder {
+    User::verify_email(&data.db, &body.token)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+/// Mails a new verification link to the user requesting
+#[post("/send-verification")]
+pub async fn send_verification(
+    data: web::Data<AppData>,
+    user: User, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    if user.email_verified() {
+        return Err(Error::new("Email is already verified"));
+    }
+
+    User::send_verification_email(&data.db, data.mailer.as_ref(), user.id(), user.email())
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+/// Mails a password reset link if the email belongs to an
+/// account. Always responds with 200 so it can't be used to
+/// find out which emails are registered.
+#[post("/forgot-password")]
+pub async fn forgot_password(
+    data: web::Data<AppData>,
+    body: web::Json<ForgotPasswordRequest>
+) -> impl Responder {
+    User::request_password_reset(data.db.clone(), data.mailer.clone(), body.into_inner().email);
+    HttpResponse::Ok()
+}
+
+/// Sets a new password with a token from a password reset email
+#[post("/reset-password")]
+pub async fn reset_password(
+    data: web::Data<AppData>,
+    body: web::Json<ResetPasswordRequest>
+) -> impl Responder {
+    let body = body.into_inner();
+    User::reset_password(&data.db, &body.token, body.password)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
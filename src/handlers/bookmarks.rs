@@ -1,22 +1,26 @@
 /* Imports */
-use actix_web::{get, web, Responder};
-use crate::{error::Error, models::user::User, AppData, models::{post::{Post, PostWithUser}, user::UserInfo}};
+use actix_web::{get, web, HttpResponse, Responder};
+use crate::{error::Error, models::user::User, AppData, models::{page::{Page, PageRequest}, post::{Post, PostWithUser}, user::UserInfo}};
 
 #[get("/bookmarks")]
 pub async fn bookmarks(
     data: web::Data<AppData>,
-    user: User
+    user: User, page: web::Query<PageRequest>
 ) -> impl Responder {
-    sqlx::query_as!(PostWithUser, r#"
+    let (created_at, id) = page.cursor::<chrono::DateTime<chrono::Utc>>()?.unzip();
+    let posts = sqlx::query_as!(PostWithUser, r#"
         SELECT posts.* FROM get_posts_default($1) posts
             JOIN post_bookmarks bm ON posts.id = bm.post_id
         WHERE
-            bm.user_id = $1;
-    "#, user.id())
+            bm.user_id = $1
+            AND NOT posts.deleted
+            AND ($2::timestamptz IS NULL OR (posts.created_at, posts.id) < ($2, $3))
+        ORDER BY posts.created_at DESC, posts.id DESC
+        LIMIT $4;
+    "#, user.id(), created_at, id, page.fetch_limit())
         .fetch_all(&data.db)
         .await
-        .map_err(Error::new)
-        .map(|e|
-            serde_json::to_string(&e).unwrap()
-        )
+        .map_err(Error::new)?;
+
+    Ok::<_, Error>(HttpResponse::Ok().json(Page::new(posts, &page, PostWithUser::newest_key)))
 }
@@ -0,0 +1,97 @@
+//! Endpoints for direct messages: the inbox, sending
+//! and reading messages, and leaving conversations
+
+/* Imports */
+use actix_web::{get, post, web, HttpResponse, Responder};
+use serde::Deserialize;
+use crate::{error::Error, middleware::auth::{scope, RequireScope}, models::{conversation::Conversation, page::PageRequest, user::User}, AppData};
+
+/* Structs */
+#[derive(Deserialize)]
+struct SendRequest {
+    /// Either the conversation to send in, or who to
+    /// start one with (several users make a group)
+    conversation_id: Option<i64>,
+    to: Option<Vec<i64>>,
+    content: String
+}
+#[derive(Deserialize)]
+struct MarkReadRequest {
+    conversation_id: i64,
+
+    /// Id of the most recent message the user has seen,
+    /// everything is marked as read if omitted
+    up_to: Option<i64>
+}
+#[derive(Deserialize)]
+struct ConversationRequest { conversation_id: i64 }
+#[derive(Deserialize)]
+struct DeleteMessageRequest { message_id: i64 }
+
+/// Get conversations, most recently active first
+#[get("")]
+pub async fn list(
+    data: web::Data<AppData>, user: User, _scope: RequireScope<scope::Dm>,
+    page: web::Query<PageRequest>
+) -> impl Responder {
+    Conversation::list(&data.db, user.id(), &page)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
+}
+
+/// Send a message, responds with the message
+#[post("/send")]
+pub async fn send(
+    data: web::Data<AppData>, body: web::Json<SendRequest>,
+    user: User, _scope: RequireScope<scope::Dm>
+) -> impl Responder {
+    let body = body.into_inner();
+    match (body.conversation_id, body.to) {
+        (Some(conversation_id), None) => Conversation::send(&data.db, user.id(), conversation_id, body.content).await,
+        (None, Some(to)) => Conversation::start(&data.db, user.id(), to, body.content).await,
+        _ => Err(Error::new("Either conversation_id or to is required"))
+    }
+    .map(|e| HttpResponse::Ok().json(e))
+}
+
+/// Get the messages of a conversation, newest first
+#[get("/{id}/messages")]
+pub async fn messages(
+    data: web::Data<AppData>, user: User, _scope: RequireScope<scope::Dm>,
+    path: web::Path<i64>, page: web::Query<PageRequest>
+) -> impl Responder {
+    Conversation::messages(&data.db, user.id(), path.into_inner(), &page)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
+}
+
+#[post("/mark-read")]
+pub async fn mark_read(
+    data: web::Data<AppData>, body: web::Json<MarkReadRequest>,
+    user: User, _scope: RequireScope<scope::Dm>
+) -> impl Responder {
+    Conversation::mark_read(&data.db, user.id(), body.conversation_id, body.up_to)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+#[post("/leave")]
+pub async fn leave(
+    data: web::Data<AppData>, body: web::Json<ConversationRequest>,
+    user: User, _scope: RequireScope<scope::Dm>
+) -> impl Responder {
+    Conversation::leave(&data.db, user.id(), body.conversation_id)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+/// Delete a message for yourself, the others still see it
+#[post("/delete-message")]
+pub async fn delete_message(
+    data: web::Data<AppData>, body: web::Json<DeleteMessageRequest>,
+    user: User, _scope: RequireScope<scope::Dm>
+) -> impl Responder {
+    Conversation::delete_message(&data.db, user.id(), body.message_id)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
@@ -1,111 +1,123 @@
 /* Imports */
-use actix_web::{get, web, HttpRequest, Responder};
-use crate::{error::Error, models::{post::PostWithUser, user::{User, UserIdReq}}, AppData};
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+use crate::{error::Error, models::{feed::Feed, page::{Page, PageRequest}, post::PostWithUser, search::{PostSearchHit, SearchQuery}, user::{User, UserIdReq}}, AppData};
 
-/// Get the most recent posts
+/* Structs */
+#[derive(Deserialize)]
+struct SearchRequest {
+    q: String
+}
+
+/// Get the most recent posts, and posts reposted by
+/// the requesting user or people they follow
 #[get("/newest")]
 pub async fn newest(
     req: HttpRequest, data: web::Data<AppData>,
-    user_id: UserIdReq
+    user_id: UserIdReq, page: web::Query<PageRequest>
 ) -> impl Responder {
-    sqlx::query_as!(PostWithUser, r#"
-        SELECT * FROM get_posts_default($1)
-            WHERE (replies_to IS NULL
-                OR citation IS NOT NULL)
-            ORDER BY created_at DESC;
-    "#, user_id.0)
-        .fetch_all(&data.db).await
-        .map_err(Error::new)
-        .map(|e| serde_json::to_string(&e).unwrap())
+    Feed::newest(&data.db, user_id.0, &page)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
 }
 
 /// Get the most popular posts this week
 #[get("/popular")]
 pub async fn popular(
     req: HttpRequest, data: web::Data<AppData>,
-    user_id: UserIdReq
+    user_id: UserIdReq, page: web::Query<PageRequest>
 ) -> impl Responder {
-    sqlx::query_as!(PostWithUser, r#"
+    let (total_likes, id) = page.cursor::<i64>()?.unzip();
+    let posts = sqlx::query_as!(PostWithUser, r#"
         SELECT * FROM get_posts_default($1)
             WHERE (replies_to IS NULL
                 OR citation IS NOT NULL)
                 AND created_at > now() - interval '7 days'
-            ORDER BY total_likes DESC;
-    "#, user_id.0)
+                AND NOT deleted
+                AND ($2::bigint IS NULL OR (total_likes, id) < ($2, $3))
+            ORDER BY total_likes DESC, id DESC
+            LIMIT $4;
+    "#, user_id.0, total_likes, id, page.fetch_limit())
         .fetch_all(&data.db).await
-        .map_err(Error::new)
-        .map(|e| serde_json::to_string(&e).unwrap())
+        .map_err(Error::new)?;
+
+    Ok::<_, Error>(HttpResponse::Ok().json(Page::new(posts, &page, PostWithUser::likes_key)))
 }
 
 /// I might change this in the future but currently
 /// this will return some post from users that the 
-/// requesting user is following and some posts that
-/// these people have liked.
+/// requesting user is following, some posts that
+/// these people have liked and posts they've reposted.
 #[get("/for-you")]
 pub async fn for_you(
     req: HttpRequest, data: web::Data<AppData>,
-    user_id: UserIdReq
+    user_id: UserIdReq, page: web::Query<PageRequest>
 ) -> impl Responder {
-    sqlx::query_as!(PostWithUser, r#"
-        SELECT * FROM get_posts_default($1) posts
-            WHERE posts.poster_id IN (
-                SELECT follows.followee_id FROM follows
-                    WHERE follows.follower_id = $1
-            )
-            OR posts.id IN (
-                SELECT post_id FROM post_likes
-                    WHERE user_id IN (
-                        SELECT follows.followee_id FROM follows
-                            WHERE follows.follower_id = $1
-                    )
+    Feed::for_you(&data.db, user_id.0, &page)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
+}
+
+/// Posts which mention the requesting user
+#[get("/mentions")]
+pub async fn mentions(
+    req: HttpRequest, data: web::Data<AppData>,
+    user_id: UserIdReq, page: web::Query<PageRequest>
+) -> impl Responder {
+    let (created_at, id) = page.cursor::<chrono::DateTime<chrono::Utc>>()?.unzip();
+    let posts = sqlx::query_as!(PostWithUser, r#"
+        SELECT posts.* FROM get_posts_default($1) posts
+            WHERE posts.id IN (
+                SELECT post_mentions.post_id FROM post_mentions
+                    WHERE post_mentions.user_id = $1
             )
-            
-            AND (posts.replies_to IS NULL
-                OR posts.citation IS NOT NULL)
-            ORDER BY posts.created_at DESC;
-    "#, user_id.0)
+                AND ($2::timestamptz IS NULL OR (posts.created_at, posts.id) < ($2, $3))
+            ORDER BY posts.created_at DESC, posts.id DESC
+            LIMIT $4;
+    "#, user_id.0, created_at, id, page.fetch_limit())
         .fetch_all(&data.db).await
-        .map_err(Error::new)
-        .map(|e| serde_json::to_string(&e).unwrap())
+        .map_err(Error::new)?;
+
+    Ok::<_, Error>(HttpResponse::Ok().json(Page::new(posts, &page, PostWithUser::newest_key)))
 }
 
 /// Get replies for a specific post
 #[get("/replies/{post_id}")]
 pub async fn replies(
     req: HttpRequest, data: web::Data<AppData>,
-    user_id: UserIdReq, path: web::Path<i64>
+    user_id: UserIdReq, path: web::Path<i64>,
+    page: web::Query<PageRequest>
 ) -> impl Responder {
-    sqlx::query_as!(PostWithUser, r#"
+    let (total_likes, id) = page.cursor::<i64>()?.unzip();
+    let posts = sqlx::query_as!(PostWithUser, r#"
         SELECT posts.* FROM get_posts_default($1) posts
             WHERE posts.replies_to = $2
-            ORDER BY total_likes DESC;
-    "#, user_id.0, path.into_inner())
+                AND (NOT posts.deleted OR posts.total_replies > 0)
+                AND ($3::bigint IS NULL OR (posts.total_likes, posts.id) < ($3, $4))
+            ORDER BY total_likes DESC, id DESC
+            LIMIT $5;
+    "#, user_id.0, path.into_inner(), total_likes, id, page.fetch_limit())
         .fetch_all(&data.db).await
-        .map_err(Error::new)
-        .map(|e| serde_json::to_string(&e).unwrap())
+        .map_err(Error::new)?;
+
+    Ok::<_, Error>(HttpResponse::Ok().json(Page::new(posts, &page, PostWithUser::likes_key)))
 }
 
-/// Search for content
-#[get("/search/{query}")]
+/// Search for posts, see `models::search` for
+/// the query syntax
+#[get("/search")]
 pub async fn search(
     req: HttpRequest, data: web::Data<AppData>,
-    user_id: UserIdReq, query: web::Path<String>
+    user_id: UserIdReq, query: web::Query<SearchRequest>,
+    page: web::Query<PageRequest>
 ) -> impl Responder {
-    let array: Vec<String> = serde_json::from_str::<Vec<String>>(&query.into_inner())
-        .map_err(Error::new)?
-        .into_iter()
-        .map(|e| format!("%{e}%"))
-        .collect();
-dbg!(&array);
+    let query = SearchQuery::parse(&query.q)?;
+
     /* No search */
-    if array.is_empty() { return Ok(String::from("[]")) }
+    if query.is_empty() {
+        return Ok::<_, Error>(HttpResponse::Ok().json(Page::<PostSearchHit> { items: Vec::new(), next_cursor: None }));
+    }
 
-    sqlx::query_as!(PostWithUser, r#"
-        SELECT posts.* FROM get_posts_default($1) posts
-            WHERE posts.content ILIKE ALL($2)
-            ORDER BY total_likes DESC;
-    "#, user_id.0, &array)
-        .fetch_all(&data.db).await
-        .map_err(Error::new)
-        .map(|e| serde_json::to_string(&e).unwrap())
+    let posts = query.posts(&data.db, user_id.0, &page).await?;
+    Ok(HttpResponse::Ok().json(posts))
 }
@@ -4,9 +4,9 @@
 //! etc.
 
 /* Imports */
-use actix_web::{get, web, Responder};
+use actix_web::{get, web, HttpResponse, Responder};
 use serde::Serialize;
-use crate::{error::Error, models::{post::Post, user::{User, UserInfo}}, AppData, models::post::PostWithUser};
+use crate::{error::Error, models::{post::Post, user::{User, UserInfo}}, AppData, models::{page::{Page, PageRequest}, post::PostWithUser}};
 
 /* Structs */
 #[derive(Serialize)]
@@ -19,21 +19,24 @@ struct TrendingHashtag {
 pub async fn posts_by_hashtag(
     path: web::Path<String>,
     data: web::Data<AppData>,
-    user: User
+    user: User, page: web::Query<PageRequest>
 ) -> impl Responder {
-    sqlx::query_as!(PostWithUser, r#"
+    let (created_at, id) = page.cursor::<chrono::DateTime<chrono::Utc>>()?.unzip();
+    let posts = sqlx::query_as!(PostWithUser, r#"
         SELECT posts.* FROM get_posts_default($1) posts
             JOIN post_hashtags ph ON posts.id = ph.post_id
             JOIN hashtags h ON ph.hashtag_id = h.id
         WHERE
-            h.tag = $2;
-    "#, user.id(), path.into_inner())
+            h.tag = $2
+            AND ($3::timestamptz IS NULL OR (posts.created_at, posts.id) < ($3, $4))
+        ORDER BY posts.created_at DESC, posts.id DESC
+        LIMIT $5;
+    "#, user.id(), path.into_inner(), created_at, id, page.fetch_limit())
         .fetch_all(&data.db)
         .await
-        .map_err(Error::new)
-        .map(|e|
-            serde_json::to_string(&e).unwrap()
-        )
+        .map_err(Error::new)?;
+
+    Ok::<_, Error>(HttpResponse::Ok().json(Page::new(posts, &page, PostWithUser::newest_key)))
 }
 
 #[get("/trending-today")]
@@ -5,3 +5,9 @@ pub mod bookmarks;
 pub mod user;
 pub mod feed;
 pub mod opinion;
+pub mod two_factor;
+pub mod api_token;
+pub mod settings;
+pub mod notification;
+pub mod stream;
+pub mod conversation;
@@ -0,0 +1,69 @@
+//! Endpoints for listing notifications, marking them
+//! as read and choosing which kinds to get
+
+/* Imports */
+use actix_web::{get, post, web, HttpResponse, Responder};
+use serde::Deserialize;
+use serde_json::json;
+use crate::{error::Error, middleware::auth::{scope, RequireScope}, models::{notification::{Notification, NotificationKind}, page::PageRequest, user::UserIdReq}, AppData};
+
+/* Structs */
+#[derive(Deserialize)]
+struct MarkReadRequest {
+    /// Id of the most recent notification the user has
+    /// seen, everything is marked as read if omitted
+    up_to: Option<i64>
+}
+#[derive(Deserialize)]
+struct SetPreferenceRequest {
+    kind: NotificationKind,
+    enabled: bool
+}
+
+/// Get notifications, grouped and newest first
+#[get("")]
+pub async fn list(
+    data: web::Data<AppData>, user_id: UserIdReq,
+    page: web::Query<PageRequest>
+) -> impl Responder {
+    Notification::list(&data.db, user_id.0, &page)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
+}
+
+#[get("/unread-count")]
+pub async fn unread_count(data: web::Data<AppData>, user_id: UserIdReq) -> impl Responder {
+    Notification::unread_count(&data.db, user_id.0)
+        .await
+        .map(|count| HttpResponse::Ok().json(json!({ "count": count })))
+}
+
+#[post("/mark-read")]
+pub async fn mark_read(
+    data: web::Data<AppData>, body: web::Json<MarkReadRequest>,
+    user_id: UserIdReq, _scope: RequireScope<scope::PostWrite>
+) -> impl Responder {
+    Notification::mark_read(&data.db, user_id.0, body.up_to)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+/// Which kinds of notifications are enabled,
+/// e.g `{ "like": true, "follow": false, ... }`
+#[get("/preferences")]
+pub async fn preferences(data: web::Data<AppData>, user_id: UserIdReq) -> impl Responder {
+    Notification::preferences(&data.db, user_id.0)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
+}
+
+/// Mute or unmute a kind of notification
+#[post("/preferences")]
+pub async fn set_preference(
+    data: web::Data<AppData>, body: web::Json<SetPreferenceRequest>,
+    user_id: UserIdReq, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    Notification::set_preference(&data.db, user_id.0, body.kind, body.enabled)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
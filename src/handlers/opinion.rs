@@ -9,7 +9,7 @@
 /* Imports */
 use actix_web::{get, post, web, HttpResponse, Responder};
 use serde::Deserialize;
-use crate::{error::Error, models::{opinion::Opinion, user::{User, UserIdReq}}, AppData};
+use crate::{error::Error, middleware::auth::{scope, RequireScope}, models::{opinion::Opinion, user::{User, UserIdReq}}, AppData};
 
 /* Structs */
 #[derive(Deserialize)]
@@ -29,7 +29,7 @@ struct SetVoteRequest {
 pub async fn create(
     data: web::Data<AppData>,
     body: web::Json<CreateOpinionRequest>,
-    user_id: UserIdReq
+    user_id: UserIdReq, _scope: RequireScope<scope::PostWrite>
 ) -> impl Responder {
     let user_id = user_id.0;
     let content = Opinion::parse(&body.opinion)
@@ -59,7 +59,7 @@ pub async fn create(
 pub async fn set_vote(
     data: web::Data<AppData>,
     body: web::Json<SetVoteRequest>,
-    user_id: UserIdReq
+    user_id: UserIdReq, _scope: RequireScope<scope::PostWrite>
 ) -> impl Responder {
     Opinion::set_vote(&data.db, body.post_id, body.opinion_id, user_id.0, body.vote)
         .await.map(|_| HttpResponse::Ok())
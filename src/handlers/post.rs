@@ -1,15 +1,33 @@
 /* Imports */
 use actix_web::{get, post, route, web, HttpRequest, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sqlx::prelude::FromRow;
-use crate::{error::Error, middleware::auth::UserClaims, models::{post::{Post, PostBoolean, PostWithUser}, post_citation::PostCitation, user::{User, UserInfo}}, utils::logger::log, AppData};
+use crate::{error::Error, middleware::auth::{scope, RequireScope, UserClaims}, models::{edge::{Bookmark, Edge, Like, Repost}, post::{Post, PostWithUser}, page::PageRequest, post_citation::{CitationRequest, PostCitation}, thread::{Thread, ThreadRequest}, user::{User, UserIdReq, UserInfo}}, utils::logger::log, AppData};
 
 /* Structs */
 #[derive(Deserialize)]
 struct PublishRequest {
     content: String,
     replies_to: Option<i64>,
-    citation: Option<PostCitation>
+    citation: Option<CitationRequest>
+}
+#[derive(Deserialize)]
+struct PublishThreadRequest {
+    /// The posts of the thread in order, the first one
+    /// replying to `replies_to` if set
+    posts: Vec<ThreadPartRequest>,
+    replies_to: Option<i64>
+}
+#[derive(Deserialize)]
+struct ThreadPartRequest {
+    content: String,
+    citation: Option<CitationRequest>
+}
+#[derive(Deserialize)]
+struct EditRequest {
+    post_id: i64,
+    content: String
 }
 #[derive(Deserialize)]
 struct DeleteRequest {
@@ -18,58 +36,106 @@ struct DeleteRequest {
 #[derive(Deserialize)]
 struct SetBooleanRequest {
     to: bool,
-    post_id: i32
+    post_id: i64
 }
 
 /// Publish a new post
 #[post("/publish")]
 pub async fn publish(
     req: HttpRequest, data: web::Data<AppData>,
-    body: web::Json<PublishRequest>, user: User
+    body: web::Json<PublishRequest>, user: User, _scope: RequireScope<scope::PostWrite>
 ) -> impl Responder {
     let body = body.into_inner();
-    Post::new(user.id(), body.content, body.replies_to, body.citation)
+    let citation = match body.citation {
+        Some(e) => Some(PostCitation::new(&data.db, e).await?),
+        None => None
+    };
+
+    Post::new(user.id(), body.content, body.replies_to, citation)
         .insert_into(&data.db)
         .await
-        .map_err(Error::new)
         .map(|_| HttpResponse::Ok())
 }
 
-/// Delete a post
+/// Publish several posts as a thread, each replying to the
+/// one before it. Responds with the ids of the posts in order.
+#[post("/publish-thread")]
+pub async fn publish_thread(
+    data: web::Data<AppData>, body: web::Json<PublishThreadRequest>,
+    user_id: UserIdReq, _scope: RequireScope<scope::PostWrite>
+) -> impl Responder {
+    let body = body.into_inner();
+    let mut parts = Vec::with_capacity(body.posts.len());
+    for part in body.posts {
+        let citation = match part.citation {
+            Some(e) => Some(PostCitation::new(&data.db, e).await?),
+            None => None
+        };
+        parts.push(Post::new(user_id.0, part.content, None, citation));
+    }
+
+    Post::insert_thread(&data.db, body.replies_to, parts)
+        .await
+        .map(|ids| HttpResponse::Ok().json(json!({ "ids": ids })))
+}
+
+/// Edit the content of a post, see `Post::edit`
+#[post("/edit")]
+pub async fn edit(
+    data: web::Data<AppData>, body: web::Json<EditRequest>,
+    user_id: UserIdReq, _scope: RequireScope<scope::PostWrite>
+) -> impl Responder {
+    let body = body.into_inner();
+    Post::edit(&data.db, user_id.0, body.post_id, body.content)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+/// Delete a post, see `Post::delete`
 #[post("/delete")]
 pub async fn delete(
     req: HttpRequest, data: web::Data<AppData>,
-    body: web::Json<DeleteRequest>, user: User
+    body: web::Json<DeleteRequest>, user: User, _scope: RequireScope<scope::PostWrite>
 ) -> impl Responder {
-    sqlx::query!(r#"
-        DELETE FROM posts WHERE id = $1 AND poster_id = $2;
-    "#, body.post_id, user.id())
-        .execute(&data.db)
+    Post::delete(&data.db, user.id(), body.post_id)
         .await
-        .map_err(Error::new)
         .map(|_| HttpResponse::Ok())
 }
 
-/// Set a post to liked or not
+/// Set a post to liked or not, responds with
+/// if it's liked and its total likes
 #[post("/set-like")]
 pub async fn set_like(
     req: HttpRequest, data: web::Data<AppData>,
-    body: web::Json<SetBooleanRequest>, user: User
+    body: web::Json<SetBooleanRequest>, user: User, _scope: RequireScope<scope::PostWrite>
 ) -> impl Responder {
-    Post::set_boolean(PostBoolean::Like, &data.db, body.to, user.id(), body.post_id.clone())
+    Like::set(&data.db, user.id(), body.post_id, body.to)
         .await
-        .map(|_| HttpResponse::Ok())
+        .map(|e| HttpResponse::Ok().json(e))
 }
 
-/// Set a post to bookmarked or not for user
+/// Set a post to bookmarked or not for user, responds with
+/// if it's bookmarked and how many have bookmarked it
 #[post("/set-bookmark")]
 pub async fn set_bookmark(
     req: HttpRequest, data: web::Data<AppData>,
-    body: web::Json<SetBooleanRequest>, user: User
+    body: web::Json<SetBooleanRequest>, user: User, _scope: RequireScope<scope::PostWrite>
 ) -> impl Responder {
-    Post::set_boolean(PostBoolean::Bookmark, &data.db, body.to, user.id(), body.post_id.clone())
+    Bookmark::set(&data.db, user.id(), body.post_id, body.to)
         .await
-        .map(|_| HttpResponse::Ok())
+        .map(|e| HttpResponse::Ok().json(e))
+}
+
+/// Set a post to reposted or not, responds with
+/// if it's reposted and its total reposts
+#[post("/set-repost")]
+pub async fn set_repost(
+    req: HttpRequest, data: web::Data<AppData>,
+    body: web::Json<SetBooleanRequest>, user: User, _scope: RequireScope<scope::PostWrite>
+) -> impl Responder {
+    Repost::set(&data.db, user.id(), body.post_id, body.to)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
 }
 
 /// Get a specific post by ID
@@ -89,3 +155,26 @@ pub async fn post_by_id(
         serde_json::to_string(&e).unwrap()
     )
 }
+
+/// Get every version of a post, the current one first
+#[get("/id/{id}/history")]
+pub async fn history(
+    data: web::Data<AppData>, user_id: UserIdReq,
+    path: web::Path<i64>
+) -> impl Responder {
+    Post::history(&data.db, path.into_inner())
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
+}
+
+/// Get a post with the posts it replies to and the
+/// tree of replies underneath it, see `models::thread`
+#[get("/id/{id}/thread")]
+pub async fn thread(
+    data: web::Data<AppData>, user_id: UserIdReq, path: web::Path<i64>,
+    thread: web::Query<ThreadRequest>, page: web::Query<PageRequest>
+) -> impl Responder {
+    Thread::get(&data.db, user_id.0, path.into_inner(), thread.depth(), &page)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
+}
@@ -0,0 +1,98 @@
+//! This file handles endpoints for changing the
+//! account of the user requesting: password, email,
+//! handle, displayname and who can message them.
+
+/* Imports */
+use actix_web::{post, web, HttpResponse, Responder};
+use serde::Deserialize;
+use crate::{error::Error, middleware::auth::{scope, RequireScope}, models::{conversation::DmPolicy, user::{User, UserIdReq}}, AppData};
+
+/* Structs */
+#[derive(Deserialize)]
+struct PasswordRequest { current_password: String, new_password: String }
+#[derive(Deserialize)]
+struct EmailRequest { email: String, password: String }
+#[derive(Deserialize)]
+struct TokenRequest { token: String }
+#[derive(Deserialize)]
+struct HandleRequest { handle: String }
+#[derive(Deserialize)]
+struct DisplaynameRequest { displayname: String }
+#[derive(Deserialize)]
+struct DmPolicyRequest { dm_policy: DmPolicy }
+
+/// Changes the password, responds with a new JWT and refresh
+/// token since every other session gets logged out
+#[post("/password")]
+pub async fn set_password(
+    data: web::Data<AppData>, body: web::Json<PasswordRequest>,
+    user: User, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    let body = body.into_inner();
+    let tokens = user.change_password(&data.db, &body.current_password, body.new_password)
+        .await?;
+
+    Ok::<_, Error>(
+        HttpResponse::Ok()
+            .json(tokens)
+    )
+}
+
+/// Mails a confirmation link to the new email address,
+/// which is used once the link has been opened
+#[post("/email")]
+pub async fn set_email(
+    data: web::Data<AppData>, body: web::Json<EmailRequest>,
+    user: User, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    let body = body.into_inner();
+    user.request_email_change(&data.db, data.mailer.as_ref(), body.email, &body.password)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+/// Switches to the new email address with the token from
+/// the confirmation link. Doesn't require being signed in,
+/// the token is proof enough.
+#[post("/confirm-email")]
+pub async fn confirm_email(
+    data: web::Data<AppData>,
+    body: web::Json<TokenRequest>
+) -> impl Responder {
+    User::confirm_email_change(&data.db, data.mailer.as_ref(), &body.token)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+/// Changes the @handle
+#[post("/handle")]
+pub async fn set_handle(
+    data: web::Data<AppData>, body: web::Json<HandleRequest>,
+    user: User, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    user.set_handle(&data.db, body.into_inner().handle)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+/// Changes the displayname
+#[post("/displayname")]
+pub async fn set_displayname(
+    data: web::Data<AppData>, body: web::Json<DisplaynameRequest>,
+    user_id: UserIdReq, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    User::set_displayname(&data.db, user_id.0, body.into_inner().displayname)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
+
+/// Changes who can start conversations with the user
+#[post("/dm-policy")]
+pub async fn set_dm_policy(
+    data: web::Data<AppData>, body: web::Json<DmPolicyRequest>,
+    user_id: UserIdReq, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    User::set_dm_policy(&data.db, user_id.0, body.dm_policy)
+        .await
+        .map(|_| HttpResponse::Ok())
+}
@@ -0,0 +1,189 @@
+//! A WebSocket which pushes what's happening to the signed in
+//! user as it happens: their notifications, posts from people
+//! they follow, the like / reply / repost counts of the posts
+//! they're looking at, and direct messages if the token may
+//! read them. Every message is an `Event` as JSON.
+//!
+//! Browsers can't set headers on WebSockets, so an access token
+//! (JWT) can also be given as `?access_token=`. API tokens live
+//! for long and query strings end up in logs, so those are only
+//! taken from the `Authorization` header. The session or API
+//! token is checked again every `REVALIDATE_INTERVAL`, and the
+//! stream is closed once it has been revoked or has expired.
+//!
+//! The client tells which posts it's looking at by sending
+//! `{ "watch": [post ids] }`, which replaces what it watched
+//! before.
+
+/* Imports */
+use std::{collections::HashSet, time::Duration};
+use actix_web::{get, http::StatusCode, web, HttpRequest, Responder};
+use actix_ws::{AggregatedMessage, Session};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::PgPool;
+use tokio::sync::broadcast::error::RecvError;
+use crate::{error::Error, middleware::auth::Auth, models::api_token::{ApiScope, API_TOKEN_PREFIX}, utils::{hub::{hub, Event}, logger::log}, AppData};
+
+/* Constants */
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// How often we check that the stream's session or
+/// API token hasn't been revoked
+const REVALIDATE_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_WATCHED_POSTS: usize = 100;
+const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+
+/* Structs */
+#[derive(Deserialize)]
+struct StreamRequest {
+    access_token: Option<String>
+}
+#[derive(Deserialize)]
+struct ClientMessage {
+    watch: Vec<i64>
+}
+
+/// What a stream pushes to its user
+struct Subscription {
+    user_id: i64,
+    auth: Auth,
+
+    /// If direct messages are pushed
+    dms: bool,
+    following: HashSet<i64>,
+    watching: HashSet<i64>,
+}
+
+/// Open a stream of events
+#[get("")]
+pub async fn stream(
+    req: HttpRequest, data: web::Data<AppData>,
+    query: web::Query<StreamRequest>, body: web::Payload
+) -> impl Responder {
+    let token = match (Auth::bearer_token(&req), query.access_token.as_deref()) {
+        (Some(token), _) => token,
+        (None, Some(token)) if token.starts_with(API_TOKEN_PREFIX) => return Err(Error::new_with_code(
+            "API tokens have to be sent in the Authorization header",
+            StatusCode::UNAUTHORIZED
+        )),
+        (None, Some(token)) => token,
+        (None, None) => return Err(Error::new_with_code("Unauthorized", StatusCode::UNAUTHORIZED))
+    };
+    let auth = Auth::from_token(&data.db, token).await?;
+    auth.require(ApiScope::Read)?;
+
+    let following = sqlx::query_scalar!(
+        "SELECT followee_id FROM follows WHERE follower_id = $1",
+        auth.user_id
+    )
+    .fetch_all(&data.db)
+    .await
+    .map_err(Error::new)?;
+
+    // Subscribe before upgrading so nothing is missed in between
+    let events = hub().subscribe();
+    let (response, session, messages) = actix_ws::handle(&req, body).map_err(Error::new)?;
+    let subscription = Subscription {
+        user_id: auth.user_id,
+        dms: auth.has_scope(ApiScope::Dm),
+        auth,
+        following: following.into_iter().collect(),
+        watching: HashSet::new(),
+    };
+
+    actix_web::rt::spawn(run(
+        data.db.clone(), subscription, session, events,
+        messages.aggregate_continuations().max_continuation_size(MAX_MESSAGE_SIZE)
+    ));
+    Ok::<_, Error>(response)
+}
+
+/// Forwards events to the client until either side closes,
+/// or the stream's session or API token is no longer valid
+async fn run(
+    pool: PgPool, mut subscription: Subscription, mut session: Session,
+    mut events: tokio::sync::broadcast::Receiver<Event>,
+    mut messages: actix_ws::AggregatedMessageStream
+) {
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut revalidate = tokio::time::interval(REVALIDATE_INTERVAL);
+    revalidate.reset();
+    let mut alive = true;
+
+    loop {
+        let sent = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => match subscription.wants(&event) {
+                    true => match serde_json::to_string(&event) {
+                        Ok(e) => session.text(e).await,
+                        Err(_) => Ok(())
+                    },
+                    false => Ok(())
+                },
+
+                // The client has to refetch to catch up
+                Err(RecvError::Lagged(_)) => session.text(json!({ "type": "lagged" }).to_string()).await,
+                Err(RecvError::Closed) => break,
+            },
+
+            message = messages.recv() => match message {
+                Some(Ok(AggregatedMessage::Text(text))) => {
+                    if let Ok(message) = serde_json::from_str::<ClientMessage>(&text) {
+                        subscription.watching = message.watch.into_iter().take(MAX_WATCHED_POSTS).collect();
+                    }
+                    Ok(())
+                },
+                Some(Ok(AggregatedMessage::Ping(bytes))) => session.pong(&bytes).await,
+                Some(Ok(AggregatedMessage::Pong(_))) => {
+                    alive = true;
+                    Ok(())
+                },
+                Some(Ok(AggregatedMessage::Binary(_))) => Ok(()),
+                Some(Ok(AggregatedMessage::Close(_))) | Some(Err(_)) | None => break,
+            },
+
+            _ = heartbeat.tick() => {
+                // No pong since the last ping
+                if !alive { break; }
+                alive = false;
+                session.ping(b"").await
+            }
+
+            _ = revalidate.tick() => match subscription.auth.is_active(&pool).await {
+                Ok(true) => Ok(()),
+                Ok(false) => break,
+                // Not the client's fault, they get to keep the stream
+                Err(e) => {
+                    log::red("stream", e);
+                    Ok(())
+                }
+            }
+        };
+
+        if sent.is_err() { return; }
+    }
+
+    let _ = session.close(None).await;
+}
+
+impl Subscription {
+    /// If the event should be pushed to the user. Also keeps
+    /// track of who they follow.
+    fn wants(&mut self, event: &Event) -> bool {
+        match event {
+            Event::Notification { user_id, .. } => *user_id == self.user_id,
+            Event::Post { poster_id, .. } => self.following.contains(poster_id),
+            Event::Counters { post_id, .. } => self.watching.contains(post_id),
+            Event::Message { recipients, .. } => self.dms && recipients.contains(&self.user_id),
+            Event::Follow { follower_id, followee_id, follow } => {
+                if *follower_id == self.user_id {
+                    match follow {
+                        true => self.following.insert(*followee_id),
+                        false => self.following.remove(followee_id)
+                    };
+                }
+                false
+            }
+        }
+    }
+}
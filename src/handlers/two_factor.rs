@@ -0,0 +1,69 @@
+//! This file handles endpoints for TOTP two factor
+//! authentication: enrolling, turning it off, and the
+//! second step of logging in when it's enabled.
+
+/* Imports */
+use actix_web::{post, web, HttpResponse, Responder};
+use serde::Deserialize;
+use serde_json::json;
+use crate::{error::Error, middleware::auth::{scope, MfaClaims, RequireScope}, models::{session::Session, two_factor::TwoFactor, user::User}, AppData};
+
+/* Structs */
+#[derive(Deserialize)]
+struct CodeRequest { code: String }
+#[derive(Deserialize)]
+struct VerifyRequest { mfa_token: String, code: String }
+
+/// Begins enrollment, responds with the secret and an
+/// otpauth:// url for the authenticator app
+#[post("/setup")]
+pub async fn setup(
+    data: web::Data<AppData>,
+    user: User, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    TwoFactor::setup(&data.db, user.id(), user.handle())
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
+}
+
+/// Finishes enrollment with a code from the authenticator
+/// app, responds with the recovery codes
+#[post("/confirm")]
+pub async fn confirm(
+    data: web::Data<AppData>,
+    body: web::Json<CodeRequest>, user: User, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    TwoFactor::confirm(&data.db, user.id(), &body.code)
+        .await
+        .map(|e| HttpResponse::Ok().json(json!({ "recovery_codes": e })))
+}
+
+/// Turns two factor authentication off
+#[post("/disable")]
+pub async fn disable(
+    data: web::Data<AppData>,
+    body: web::Json<CodeRequest>, user: User, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    if !user.totp_enabled() {
+        return Err(Error::new("Two factor authentication is not enabled"));
+    }
+
+    TwoFactor::disable(&data.db, user.id(), &body.code)
+        .await
+        .map(|_| HttpResponse::Ok().finish())
+}
+
+/// Swaps the token from `/auth/login` and a TOTP or
+/// recovery code for the JWT and refresh token
+#[post("/verify")]
+pub async fn verify(
+    data: web::Data<AppData>,
+    body: web::Json<VerifyRequest>
+) -> impl Responder {
+    let claims = MfaClaims::is_valid(&body.mfa_token)?;
+    TwoFactor::verify(&data.db, claims.id, &body.code).await?;
+
+    Session::create(&data.db, claims.id, claims.handle)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
+}
@@ -1,11 +1,16 @@
 use actix_multipart::{form::{tempfile::TempFile, MultipartForm}, Multipart};
 /* Imports */
-use actix_web::{get, post, route, web, HttpRequest, HttpResponse, Responder};
+use actix_web::{get, http::header, post, route, web, HttpRequest, HttpResponse, Responder};
+use serde_json::json;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
-use crate::{error::Error, middleware::auth::UserClaims, models::{pfp::ProfileImageHandler, post::{Post, PostBoolean, PostWithUser}, post_citation::PostCitation, user::{User, UserIdReq, UserInfo}}, utils::logger::log, AppData};
+use crate::{error::Error, middleware::auth::{scope, RequireScope, UserClaims}, models::{account::Account, feed::Feed, page::{Page, PageRequest}, pfp::ProfileImageHandler, edge::{Block, Edge, Follow}, post::{Post, PostWithUser}, post_citation::PostCitation, user::{User, UserIdReq, UserInfo}}, utils::logger::log, AppData};
 use image::{self, imageops::resize, EncodableLayout};
 
+/* Constants */
+const MAX_USER_QUERY_LENGTH: usize = 50;
+const AUTOCOMPLETE_LIMIT: i64 = 8;
+
 /* Structs */
 #[derive(Deserialize)]
 struct SetFollowingRequest {
@@ -13,6 +18,15 @@ struct SetFollowingRequest {
     followee_id: i64,
     follow: bool
 }
+#[derive(Deserialize)]
+struct SetBlockedRequest {
+    user_id: i64,
+    block: bool
+}
+#[derive(Deserialize)]
+struct DeleteAccountRequest { password: String }
+#[derive(Deserialize)]
+struct UserSearchRequest { q: String }
 
 /// Get user by their id
 #[get("/id/{id}")]
@@ -81,54 +95,143 @@ pub async fn get_by_handle(
     )
 }
 
-/// Get user by their id
+/// Follow or unfollow a user, responds with if
+/// they're followed and their follower count
 #[post("/set-following")]
 pub async fn set_following(
     body: web::Json<SetFollowingRequest>,
     data: web::Data<AppData>,
-    user_id: UserIdReq
+    user_id: UserIdReq, _scope: RequireScope<scope::PostWrite>
 ) -> impl Responder {
-    User::set_following(
-        &data.db,
-        user_id.0, body.followee_id,
-        body.follow
-    ).await
-    .map(|_| HttpResponse::Ok())
-    .map_err(Error::new)
+    Follow::set(&data.db, user_id.0, body.followee_id, body.follow)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
+}
+
+/// Block or unblock a user, responds with if they're
+/// blocked and how many users are blocked in total
+#[post("/set-blocked")]
+pub async fn set_blocked(
+    body: web::Json<SetBlockedRequest>,
+    data: web::Data<AppData>,
+    user_id: UserIdReq, _scope: RequireScope<scope::PostWrite>
+) -> impl Responder {
+    Block::set(&data.db, user_id.0, body.user_id, body.block)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
 }
 
 /// Returns info about the user that sends the request
 #[get("/profile")]
 pub async fn profile(req: HttpRequest, user: User) -> impl Responder {
-    serde_json::to_string(&user.to_non_sensitive())
+    serde_json::to_string(&user.into_non_sensitive())
         .map_err(Error::new)
 }
 
-#[get("/debug-get-all-handles")]
-pub async fn all_handles(req: HttpRequest, data: web::Data<AppData>) -> impl Responder {
-    sqlx::query_scalar!(r#"
-        SELECT handle FROM users
-    "#)
-    .fetch_all(&data.db)
-    .await
-    .map_err(Error::new)
-    .map(|e: Vec<String>| serde_json::to_string(&e).unwrap())
+/// Search for users by handle or displayname. Matches
+/// prefixes and (using trigrams) similar spellings, with
+/// an exact handle match first, then people you follow,
+/// then by follower count.
+#[get("/search")]
+pub async fn search(
+    data: web::Data<AppData>, user_id: UserIdReq,
+    query: web::Query<UserSearchRequest>, page: web::Query<PageRequest>
+) -> impl Responder {
+    let query = query.q.trim().trim_start_matches('@').to_lowercase();
+
+    /* No search */
+    if query.is_empty() {
+        return Ok::<_, Error>(HttpResponse::Ok().json(Page::<UserInfo> { items: Vec::new(), next_cursor: None }));
+    }
+    if query.chars().count() > MAX_USER_QUERY_LENGTH {
+        return Err(Error::new("Search query too long"));
+    }
+
+    let prefix = format!("{}%", query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+    let (key, id) = page.cursor::<(bool, bool, i32)>()?.unzip();
+    let (exact, followed, followers) = (key.map(|e| e.0), key.map(|e| e.1), key.map(|e| e.2));
+    let users = sqlx::query_as!(UserInfo, r#"
+        SELECT
+            users.id as user_id,
+            users.displayname,
+            users.handle,
+            users.following,
+            users.followers,
+            follows.follower_id IS NOT NULL as "is_followed!: bool"
+        FROM users
+            LEFT JOIN follows ON follows.follower_id = $1
+                AND follows.followee_id = users.id
+        WHERE (users.handle LIKE $3
+                OR users.displayname ILIKE $3
+                OR users.handle % $2
+                OR $2 <% users.displayname)
+            AND ($4::bool IS NULL OR (
+                users.handle = $2, follows.follower_id IS NOT NULL, users.followers, users.id
+            ) < ($4, $5, $6, $7))
+        ORDER BY
+            users.handle = $2 DESC,
+            follows.follower_id IS NOT NULL DESC,
+            users.followers DESC,
+            users.id DESC
+        LIMIT $8;
+    "#, user_id.0, query, prefix, exact, followed, followers, id, page.fetch_limit())
+        .fetch_all(&data.db).await
+        .map_err(Error::new)?;
+
+    Ok(HttpResponse::Ok().json(Page::new(users, &page, |e| (
+        (e.handle == query, e.is_followed, e.followers),
+        e.user_id
+    ))))
+}
+
+/// Users whose handle starts with `q`, for completing
+/// @mentions while writing a post
+#[get("/autocomplete")]
+pub async fn autocomplete(
+    data: web::Data<AppData>, user_id: UserIdReq,
+    query: web::Query<UserSearchRequest>
+) -> impl Responder {
+    let prefix = query.q.trim().trim_start_matches('@').to_lowercase();
+
+    // Can't be the start of a handle
+    if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '.') {
+        return Ok::<_, Error>(HttpResponse::Ok().json(Vec::<UserInfo>::new()));
+    }
+
+    let users = sqlx::query_as!(UserInfo, r#"
+        SELECT
+            users.id as user_id,
+            users.displayname,
+            users.handle,
+            users.following,
+            users.followers,
+            follows.follower_id IS NOT NULL as "is_followed!: bool"
+        FROM users
+            LEFT JOIN follows ON follows.follower_id = $1
+                AND follows.followee_id = users.id
+        WHERE users.handle LIKE $2 || '%'
+        ORDER BY
+            follows.follower_id IS NOT NULL DESC,
+            users.followers DESC,
+            users.handle
+        LIMIT $3;
+    "#, user_id.0, prefix, AUTOCOMPLETE_LIMIT)
+        .fetch_all(&data.db).await
+        .map_err(Error::new)?;
+
+    Ok(HttpResponse::Ok().json(users))
 }
 
-/// Get all posts that a user has posted
+/// Get all posts that a user has posted or reposted
 #[get("/posts/{id}")]
 pub async fn posts(
     req: HttpRequest, data: web::Data<AppData>,
-    user_id: UserIdReq, path: web::Path<i64>
+    user_id: UserIdReq, path: web::Path<i64>,
+    page: web::Query<PageRequest>
 ) -> impl Responder {
-    sqlx::query_as!(PostWithUser, r#"
-        SELECT posts.* FROM get_posts_default($1) posts
-            WHERE posts.poster_id = $2
-            ORDER BY created_at DESC;
-    "#, user_id.0, path.into_inner())
-        .fetch_all(&data.db).await
-        .map_err(Error::new)
-        .map(|e| serde_json::to_string(&e).unwrap())
+    Feed::user_posts(&data.db, user_id.0, path.into_inner(), &page)
+        .await
+        .map(|e| HttpResponse::Ok().json(e))
 }
 
 /// Get profile image of some user
@@ -178,7 +281,7 @@ pub struct ProfileImageUpload {
 /// Set profile image of some user
 #[post("/profile-image")]
 pub async fn set_profile_image(
-    user_id: UserIdReq,
+    user_id: UserIdReq, _scope: RequireScope<scope::PostWrite>,
     MultipartForm(form): MultipartForm<ProfileImageUpload>,
 ) -> impl Responder {
     ProfileImageHandler::set_image(user_id.0, form)
@@ -188,8 +291,46 @@ pub async fn set_profile_image(
 /// Remove profile image of user requesting
 #[post("/delete-profile-image")]
 pub async fn delete_profile_image(
-    user_id: UserIdReq,
+    user_id: UserIdReq, _scope: RequireScope<scope::PostWrite>,
 ) -> impl Responder {
     ProfileImageHandler::remove_image(user_id.0)
         .await
 }
+
+/// Schedules the account of the user requesting for deletion
+/// and signs them out everywhere. Responds with when the account
+/// will be deleted - signing in before then cancels it.
+#[post("")]
+pub async fn delete_account(
+    data: web::Data<AppData>, body: web::Json<DeleteAccountRequest>,
+    user: User, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    let purge_at = Account::request_deletion(&data.db, data.mailer.as_ref(), &user, &body.password)
+        .await?;
+
+    Ok::<_, Error>(
+        HttpResponse::Ok()
+            .json(json!({ "deletion_scheduled_for": purge_at.timestamp_millis() }))
+    )
+}
+
+/// Responds with a ZIP archive of everything we
+/// store about the user requesting
+#[get("/export")]
+pub async fn export(
+    data: web::Data<AppData>,
+    user: User, _scope: RequireScope<scope::Admin>
+) -> impl Responder {
+    let archive = Account::export(&data.db, user.id())
+        .await?;
+
+    Ok::<_, Error>(
+        HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"twitter2-{}.zip\"", user.handle())
+            ))
+            .body(archive)
+    )
+}
@@ -6,36 +6,51 @@ mod middleware;
 mod utils;
 mod handlers;
 mod error;
+mod config;
+#[cfg(test)]
+mod tests;
 
 /* Imports */
 use actix_cors::Cors;
 use actix_web::{dev::Service, get, http::{header, KeepAlive}, middleware::Logger, web::{self, Data, PayloadConfig}, App, HttpServer, Responder};
-use std::error::Error;
+use std::{error::Error, sync::Arc};
 use sqlx::PgPool;
-use models::{post::Post, user::User};
-use utils::logger::log;
-use handlers::{auth, bookmarks, feed, post, hashtag, user, opinion};
-
-/* Constants */
-const DATABASE_URL: &'static str = env!("DATABASE_URL");
-const MAX_REQUEST_SIZE: usize = 1_048_576 * 3; // 3MB
-const FRONTEND_URL: &'static str = env!("FRONTEND_URL");
+use config::Config;
+use models::{account::Account, post::Post, user::User};
+use middleware::rate_limit::RateLimit;
+use utils::{hub::Hub, logger::log, mailer::{self, Mailer}, migrate};
+use handlers::{auth, bookmarks, feed, post, hashtag, user, opinion, two_factor, api_token, settings, notification, stream, conversation};
 
 pub struct AppData {
-    db: PgPool
+    db: PgPool,
+    mailer: Arc<dyn Mailer>
 }
 
 #[tokio::main]
-async fn main() -> () {
+async fn main() {
+    let config = match Config::init() {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(1);
+        }
+    };
+
     log::yellow("PgPool", "Initializing");
-    let pool = PgPool::connect(&DATABASE_URL).await.unwrap();
+    let pool = PgPool::connect(&config.database_url).await.unwrap();
+    let mailer = mailer::from_config().unwrap();
+    std::fs::create_dir_all(&config.profile_image_dir).unwrap();
+    if let Err(e) = migrate::run(&pool, config.migrate).await {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
 
-    // TODO THIS MIGHT BE GOOD TO DO
-    // sqlx::migrate!("./migrations")
-    //     .run(&pool)
-    //     .await.unwrap();
+    let rate_limit = RateLimit::new();
+    Account::spawn_purge_task(pool.clone());
+    Post::spawn_purge_task(pool.clone());
+    Hub::init(&pool, config.stream_fanout);
 
-    if env!("DEBUG_LOG_ACTIX").parse::<bool>().unwrap() {
+    if config.debug_log_actix {
         env_logger::init_from_env(
             env_logger::Env::default()
                 .default_filter_or("info")
@@ -52,12 +67,32 @@ async fn main() -> () {
                 .allow_any_origin()
             )
             .wrap(Logger::default())
-            .app_data(Data::new(AppData { db: pool.clone() }))
-            .app_data(PayloadConfig::new(MAX_REQUEST_SIZE))
+            .app_data(Data::new(AppData { db: pool.clone(), mailer: mailer.clone() }))
+            .app_data(PayloadConfig::new(config.max_request_size))
             .service(ping)
             .service(web::scope("/auth")
+                .wrap(rate_limit.clone())
                 .service(auth::login)
                 .service(auth::sign_up)
+                .service(auth::refresh)
+                .service(auth::logout)
+                .service(auth::logout_all)
+                .service(auth::verify_email)
+                .service(auth::send_verification)
+                .service(auth::forgot_password)
+                .service(auth::reset_password)
+
+                .service(web::scope("/2fa")
+                    .service(two_factor::setup)
+                    .service(two_factor::confirm)
+                    .service(two_factor::disable)
+                    .service(two_factor::verify)
+                )
+                .service(web::scope("/tokens")
+                    .service(api_token::create)
+                    .service(api_token::list)
+                    .service(api_token::revoke)
+                )
             )
             .service(web::scope("/user")
                 .service(user::get_by_id)
@@ -66,17 +101,40 @@ async fn main() -> () {
                 .service(user::set_profile_image)
                 .service(user::delete_profile_image)
                 .service(user::set_following)
+                .service(user::set_blocked)
                 .service(user::posts)
                 .service(user::profile)
-                .service(user::all_handles)
+                .service(user::search)
+                .service(user::autocomplete)
                 .service(user::popular)
+                .service(user::export)
+
+                .service(web::scope("/delete-account")
+                    .wrap(rate_limit.clone())
+                    .service(user::delete_account)
+                )
+
+                .service(web::scope("/settings")
+                    .wrap(rate_limit.clone())
+                    .service(settings::set_password)
+                    .service(settings::set_email)
+                    .service(settings::confirm_email)
+                    .service(settings::set_handle)
+                    .service(settings::set_displayname)
+                    .service(settings::set_dm_policy)
+                )
             )
             .service(web::scope("/post")
                 .service(post::publish)
+                .service(post::publish_thread)
+                .service(post::edit)
                 .service(post::delete)
                 .service(post::set_like)
                 .service(post::set_bookmark)
+                .service(post::set_repost)
                 .service(post::post_by_id)
+                .service(post::history)
+                .service(post::thread)
                 .service(bookmarks::bookmarks)
 
                 .service(web::scope("/opinion")
@@ -85,11 +143,30 @@ async fn main() -> () {
                     .service(opinion::get_opinions)
                 )
             )
+            .service(web::scope("/notifications")
+                .service(notification::list)
+                .service(notification::unread_count)
+                .service(notification::mark_read)
+                .service(notification::preferences)
+                .service(notification::set_preference)
+            )
+            .service(web::scope("/conversations")
+                .service(conversation::list)
+                .service(conversation::send)
+                .service(conversation::messages)
+                .service(conversation::mark_read)
+                .service(conversation::leave)
+                .service(conversation::delete_message)
+            )
+            .service(web::scope("/stream")
+                .service(stream::stream)
+            )
             .service(web::scope("/feed")
                 .service(feed::newest)
                 .service(feed::for_you)
                 .service(feed::popular)
                 .service(feed::replies)
+                .service(feed::mentions)
                 .service(feed::search)
                 .service(web::scope("/hashtag")
                     .service(hashtag::posts_by_hashtag)
@@ -98,7 +175,7 @@ async fn main() -> () {
             )
     })
     .keep_alive(KeepAlive::Disabled)
-    .bind(("0.0.0.0", 8081))
+    .bind((config.bind_address.as_str(), config.port))
     .unwrap().run().await.unwrap();
 }
 
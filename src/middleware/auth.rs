@@ -1,15 +1,20 @@
 /* Imports */
-use std::time::{Duration, SystemTime};
+use std::{future::Future, marker::PhantomData, pin::Pin, time::{Duration, SystemTime}};
+use actix_web::{http::StatusCode, web, FromRequest, HttpMessage, HttpRequest};
 use jsonwebtoken::{encode, DecodingKey, EncodingKey, TokenData, Validation};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::PgPool;
 
-use crate::error::Error;
+use crate::{config::config, error::Error, models::{api_token::{ApiScope, ApiToken, API_TOKEN_PREFIX}, session::Session}, AppData};
 
 /* Constants */
-lazy_static::lazy_static! {
-    static ref JWT_TOKEN_MAXAGE: u64 = env!("JWT_TOKEN_MAXAGE").parse::<u64>().unwrap();
-    static ref JWT_TOKEN_KEY: &'static str = env!("JWT_TOKEN_KEY");
-}
+/// How many seconds an access token (JWT) is valid for. Kept short
+/// because access tokens are only checked against the session table,
+/// the refresh token is what keeps a client signed in.
+const ACCESS_TOKEN_MAXAGE: u64 = 15 * 60;
+/// How many seconds someone has to enter their two
+/// factor code after entering the correct password
+const MFA_TOKEN_MAXAGE: u64 = 5 * 60;
 
 /// We call them claims because it's up to the `is_valid`
 /// function to see if the claims are claims or facts.
@@ -17,34 +22,218 @@ lazy_static::lazy_static! {
 pub struct UserClaims {
     pub handle: String,
     pub id: i64,
+
+    /// The id of the session (row in `sessions`) which this
+    /// token was issued for. Revoking the session makes the
+    /// token invalid even before `exp`.
+    pub sid: i64,
     pub exp: usize
 }
 
 impl UserClaims {
     /// `id` needs to be the `SERIAL PRIMARY KEY` retrieved
-    /// from psotgresql
+    /// from psotgresql, and `sid` the id of the session
+    pub fn new(handle: String, id: i64, sid: i64) -> Self {
+        Self { handle, exp: expires_in(ACCESS_TOKEN_MAXAGE), id, sid }
+    }
+
+    /// Returns claims if valid. Does NOT check if the session
+    /// has been revoked, that requires a database lookup.
+    pub fn is_valid(token: &str) -> Result<TokenData<UserClaims>, Error> {
+        decode(token)
+    }
+
+    /// Encodes the claims into the JWT token string
+    pub fn encode(&self) -> Option<String> {
+        encode_claims(self)
+    }
+}
+
+/// Claims of the token `/auth/login` responds with when the
+/// user has two factor authentication enabled. It only proves
+/// that the password was correct, and can't be used for
+/// anything except `/auth/2fa/verify`.
+#[derive(Deserialize, Serialize)]
+pub struct MfaClaims {
+    pub handle: String,
+    pub id: i64,
+    pub mfa_pending: bool,
+    pub exp: usize
+}
+
+impl MfaClaims {
     pub fn new(handle: String, id: i64) -> Self {
-        // `JWT_TOKEN_MAXAGE` days
-        let next = SystemTime::now() + Duration::from_secs(*JWT_TOKEN_MAXAGE as u64 * 24 * 60 * 60);
-        let exp = next.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::default()).as_secs() as usize;
-        Self { handle, exp, id }
+        Self { handle, id, mfa_pending: true, exp: expires_in(MFA_TOKEN_MAXAGE) }
     }
 
     /// Returns claims if valid
-    pub fn is_valid(token: &str) -> Result<TokenData<UserClaims>, Error> {
-        jsonwebtoken::decode::<Self>(
-            &token,
-            &DecodingKey::from_secret((&*JWT_TOKEN_KEY).as_bytes()),
-            &Validation::new(jsonwebtoken::Algorithm::HS256)
-        ).map_err(Error::new)
+    pub fn is_valid(token: &str) -> Result<Self, Error> {
+        decode::<Self>(token)
+            .map(|e| e.claims)
+            .and_then(|e| match e.mfa_pending {
+                true => Ok(e),
+                false => Err(Error::new_with_code("Invalid token", StatusCode::UNAUTHORIZED))
+            })
     }
 
     /// Encodes the claims into the JWT token string
-    pub fn to_string(&self) -> Option<String> {
-        encode(
-            &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256),
-            &self,
-            &EncodingKey::from_secret((&*JWT_TOKEN_KEY).as_bytes())
-        ).ok()
+    pub fn encode(&self) -> Option<String> {
+        encode_claims(self)
     }
 }
+
+/// Who sent a request. Resolved from the `Authorization: Bearer ..`
+/// header which either contains a JWT or an API token.
+#[derive(Clone, Debug)]
+pub struct Auth {
+    pub user_id: i64,
+
+    /// `None` when signed in with a session, which can do
+    /// everything. API tokens can only do what their scopes
+    /// allow.
+    pub scopes: Option<Vec<ApiScope>>,
+
+    /// What the request was authenticated with
+    pub credential: Credential,
+}
+
+/// A session (signed in with a JWT) or an API token, by id
+#[derive(Clone, Copy, Debug)]
+pub enum Credential {
+    Session(i64),
+    ApiToken(i64),
+}
+
+impl Auth {
+    /// Authenticates a request. The result is cached in the
+    /// request extensions, so several extractors in the same
+    /// handler (e.g `User` and `RequireScope`) only look it up
+    /// once.
+    pub async fn from_request(req: &HttpRequest) -> Result<Self, Error> {
+        if let Some(auth) = req.extensions().get::<Self>().cloned() {
+            return Ok(auth);
+        }
+
+        let appdata = req.app_data::<web::Data<AppData>>()
+            .ok_or(Error::new_with_code(
+                "Internal server error (appdata retrieval from request)",
+                StatusCode::INTERNAL_SERVER_ERROR
+            ))?;
+        let token = Self::bearer_token(req)
+            .ok_or(Error::new_with_code("Unauthorized", StatusCode::UNAUTHORIZED))?;
+
+        let auth = Self::from_token(&appdata.db, token).await?;
+        req.extensions_mut().insert(auth.clone());
+        Ok(auth)
+    }
+
+    /// Authenticates a JWT or an API token. Only needed where the
+    /// token can't be sent in the `Authorization` header (e.g
+    /// WebSockets opened by browsers), otherwise use `from_request`.
+    pub async fn from_token(pool: &PgPool, token: &str) -> Result<Self, Error> {
+        if token.starts_with(API_TOKEN_PREFIX) {
+            ApiToken::authenticate(pool, token).await?
+                .ok_or(Error::new_with_code("Invalid or expired API token", StatusCode::UNAUTHORIZED))
+        }else {
+            let claims = UserClaims::is_valid(token)?.claims;
+            match Session::is_active(pool, claims.id, claims.sid).await? {
+                true => Ok(Self { user_id: claims.id, scopes: None, credential: Credential::Session(claims.sid) }),
+                false => Err(Error::new_with_code("Session expired", StatusCode::UNAUTHORIZED))
+            }
+        }
+    }
+
+    /// If what the request was authenticated with still is valid,
+    /// for connections which outlive the request (e.g WebSockets)
+    pub async fn is_active(&self, pool: &PgPool) -> Result<bool, Error> {
+        match self.credential {
+            Credential::Session(session_id) => Session::is_active(pool, self.user_id, session_id).await,
+            Credential::ApiToken(token_id) => ApiToken::is_active(pool, self.user_id, token_id).await
+        }
+    }
+
+    /// The token in the `Authorization: Bearer ..` header
+    pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+        req.headers().get("Authorization")
+            .and_then(|e| e.to_str().ok())
+            .and_then(|e| e.strip_prefix("Bearer "))
+    }
+
+    /// If the request is allowed to do things that need `scope`
+    pub fn has_scope(&self, scope: ApiScope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.contains(&scope),
+            None => true
+        }
+    }
+
+    /// Errors with 403 if the request doesn't have `scope`
+    pub fn require(&self, scope: ApiScope) -> Result<(), Error> {
+        match self.has_scope(scope) {
+            true => Ok(()),
+            false => Err(Error::new_with_code(
+                format!("This token is missing the \"{}\" scope", scope.as_str()),
+                StatusCode::FORBIDDEN
+            ))
+        }
+    }
+}
+
+/// Marker types for declaring which scope a handler needs,
+/// e.g `_: RequireScope<scope::PostWrite>`
+pub mod scope {
+    use crate::models::api_token::ApiScope;
+
+    pub trait Scope { const SCOPE: ApiScope; }
+
+    pub struct Read;
+    pub struct PostWrite;
+    pub struct Dm;
+    pub struct Admin;
+
+    impl Scope for Read { const SCOPE: ApiScope = ApiScope::Read; }
+    impl Scope for PostWrite { const SCOPE: ApiScope = ApiScope::PostWrite; }
+    impl Scope for Dm { const SCOPE: ApiScope = ApiScope::Dm; }
+    impl Scope for Admin { const SCOPE: ApiScope = ApiScope::Admin; }
+}
+
+/// Handler parameter which rejects requests made with an API
+/// token that lacks the scope `S`. Sessions have every scope.
+/// The `User` and `UserIdReq` extractors already require
+/// `read`, so this is only needed for anything beyond that.
+pub struct RequireScope<S: scope::Scope>(PhantomData<S>);
+
+impl<S: scope::Scope> FromRequest for RequireScope<S> {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            Auth::from_request(&req).await?.require(S::SCOPE)?;
+            Ok(Self(PhantomData))
+        })
+    }
+}
+
+/// Unix timestamp `seconds` from now
+fn expires_in(seconds: u64) -> usize {
+    let next = SystemTime::now() + Duration::from_secs(seconds);
+    next.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs() as usize
+}
+
+fn decode<T: DeserializeOwned>(token: &str) -> Result<TokenData<T>, Error> {
+    jsonwebtoken::decode::<T>(
+        token,
+        &DecodingKey::from_secret(config().jwt_token_key.as_bytes()),
+        &Validation::new(jsonwebtoken::Algorithm::HS256)
+    ).map_err(|e| Error::new_with_code(e, StatusCode::UNAUTHORIZED))
+}
+
+fn encode_claims<T: Serialize>(claims: &T) -> Option<String> {
+    encode(
+        &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256),
+        claims,
+        &EncodingKey::from_secret(config().jwt_token_key.as_bytes())
+    ).ok()
+}
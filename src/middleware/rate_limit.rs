@@ -0,0 +1,456 @@
+//! Brute force protection for endpoints which check
+//! passwords or codes (`/auth`, `/user/settings`,
+//! `/user/delete-account`). Every
+//! request is keyed by the IP it came from and, if the
+//! body targets an account (an `email`, or the `mfa_token`
+//! of a half finished login), by that account too. Signed
+//! in requests are also keyed by who they're signed in as,
+//! since what's in their body (e.g a new email address)
+//! can be changed on every attempt.
+//!
+//! Failed attempts (a wrong password, code or token, which
+//! handlers answer with `Error::failed_attempt`) make the
+//! client wait longer and longer before the next one is let
+//! through, and too many of them lock the key out for a
+//! while. Other errors, like invalid input or an expired
+//! login, don't count. Anything over the limit gets a 429
+//! with `Retry-After`.
+//!
+//! State is kept in memory, so limits are per process.
+
+/* Imports */
+use std::{collections::HashMap, future::{ready, Future, Ready}, net::IpAddr, pin::Pin, rc::Rc, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use actix_web::{body::EitherBody, dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform}, http::{header, StatusCode}, web, ResponseError};
+use crate::{error::Error, middleware::auth::{Auth, MfaClaims}};
+
+/* Constants */
+/// Requests an IP can make to the wrapped endpoints per `WINDOW`,
+/// no matter if they succeed or not
+const IP_MAX_REQUESTS: u32 = 60;
+const WINDOW: Duration = Duration::from_secs(60);
+/// Longest delay between two attempts before lockout kicks in
+const MAX_DELAY: Duration = Duration::from_secs(30);
+/// How long a key stays locked out
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Failures are forgotten once there's been none for this long
+const FAILURE_MEMORY: Duration = Duration::from_secs(60 * 60);
+/// How often stale entries are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// What signed in requests are keyed by instead of their path,
+/// since they all check the password (or code) of the same user
+const SIGNED_IN: &str = "signed_in";
+
+/// How many failures a key gets before it's slowed
+/// down, and before it's locked out
+struct Policy { free_attempts: u32, lockout_after: u32 }
+
+/// For a single account. Strict, since nobody mistypes
+/// their password ten times in a row.
+const ACCOUNT_POLICY: Policy = Policy { free_attempts: 3, lockout_after: 10 };
+/// For a single IP. Looser, since many people can
+/// share one (NAT, offices, schools)
+const IP_POLICY: Policy = Policy { free_attempts: 20, lockout_after: 100 };
+
+/// Failed attempts of one key
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Requests of one IP in the current window
+struct Requests { start: Instant, count: u32 }
+
+/// Put in the extensions of responses to failed attempts,
+/// see `Error::failed_attempt`
+pub struct FailedAttempt;
+
+#[derive(Default)]
+struct State {
+    requests: HashMap<IpAddr, Requests>,
+    ip_failures: HashMap<IpAddr, Failures>,
+
+    /// Keyed by `(path, account)` so that e.g failed sign ups
+    /// can't be used to lock someone out of logging in
+    account_failures: HashMap<(String, String), Failures>,
+    last_prune: Option<Instant>,
+}
+
+/// What a request is counted towards
+struct Keys {
+    ip: Option<IpAddr>,
+    accounts: Vec<(String, String)>,
+}
+
+/// Wrap a scope or resource with this to rate limit it. Create
+/// it once outside of `HttpServer::new` so that every worker
+/// shares the same state.
+#[derive(Clone, Default)]
+pub struct RateLimit {
+    state: Arc<Mutex<State>>
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    state: Arc<Mutex<State>>
+}
+
+impl Failures {
+    fn new(now: Instant) -> Self {
+        Self { count: 0, last: now, locked_until: None }
+    }
+
+    /// How long until the next attempt is let through
+    fn wait(&self, policy: &Policy, now: Instant) -> Option<Duration> {
+        if let Some(until) = self.locked_until.filter(|until| *until > now) {
+            return Some(until - now);
+        }
+        if self.count <= policy.free_attempts || self.is_stale(now) {
+            return None;
+        }
+
+        // 1s, 2s, 4s ... up to MAX_DELAY
+        let exponent = (self.count - policy.free_attempts - 1).min(16);
+        let delay = Duration::from_secs(1 << exponent).min(MAX_DELAY);
+        (self.last + delay).checked_duration_since(now)
+            .filter(|e| !e.is_zero())
+    }
+
+    fn record(&mut self, policy: &Policy, now: Instant) {
+        if self.is_stale(now) {
+            self.count = 0;
+        }
+
+        self.count += 1;
+        self.last = now;
+        if self.count >= policy.lockout_after {
+            self.locked_until = Some(now + LOCKOUT);
+        }
+    }
+
+    /// Takes back an attempt which turned out not to be a failure
+    fn refund(&mut self) {
+        self.count = self.count.saturating_sub(1);
+        self.locked_until = None;
+    }
+
+    fn is_stale(&self, now: Instant) -> bool {
+        now.duration_since(self.last) > FAILURE_MEMORY
+            && self.locked_until.is_none_or(|until| until <= now)
+    }
+}
+
+impl State {
+    /// Lets a request through, or returns how long the client
+    /// needs to wait. Requests let through are counted as failed
+    /// right away (and taken back in `settle`), otherwise many
+    /// concurrent attempts would all get past the check before
+    /// the first one has failed.
+    fn admit(&mut self, keys: &Keys, now: Instant) -> Result<(), Duration> {
+        self.prune(now);
+
+        if let Some(ip) = keys.ip {
+            let requests = self.requests.entry(ip)
+                .or_insert(Requests { start: now, count: 0 });
+            if now.duration_since(requests.start) >= WINDOW {
+                *requests = Requests { start: now, count: 0 };
+            }
+            if requests.count >= IP_MAX_REQUESTS {
+                return Err(requests.start + WINDOW - now);
+            }
+            requests.count += 1;
+        }
+
+        let ip_wait = keys.ip
+            .and_then(|ip| self.ip_failures.get(&ip))
+            .and_then(|e| e.wait(&IP_POLICY, now));
+        let account_wait = keys.accounts.iter()
+            .filter_map(|key| self.account_failures.get(key))
+            .filter_map(|e| e.wait(&ACCOUNT_POLICY, now))
+            .max();
+        if let Some(wait) = ip_wait.max(account_wait) {
+            return Err(wait);
+        }
+
+        if let Some(ip) = keys.ip {
+            self.ip_failures.entry(ip)
+                .or_insert_with(|| Failures::new(now))
+                .record(&IP_POLICY, now);
+        }
+        for key in &keys.accounts {
+            self.account_failures.entry(key.clone())
+                .or_insert_with(|| Failures::new(now))
+                .record(&ACCOUNT_POLICY, now);
+        }
+
+        Ok(())
+    }
+
+    /// Called with the status of a request that `admit` let
+    /// through, and if it was a failed attempt. Only those stay
+    /// counted as failures.
+    fn settle(&mut self, keys: &Keys, status: StatusCode, failed: bool) {
+        if failed {
+            return;
+        }
+
+        if let Some(failures) = keys.ip.and_then(|ip| self.ip_failures.get_mut(&ip)) {
+            failures.refund();
+        }
+        for key in &keys.accounts {
+            match status.is_success() {
+                true => { self.account_failures.remove(key); },
+                false => if let Some(failures) = self.account_failures.get_mut(key) {
+                    failures.refund();
+                }
+            }
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        if self.last_prune.is_some_and(|e| now.duration_since(e) < PRUNE_INTERVAL) {
+            return;
+        }
+
+        self.last_prune = Some(now);
+        self.requests.retain(|_, e| now.duration_since(e.start) < WINDOW);
+        self.ip_failures.retain(|_, e| !e.is_stale(now));
+        self.account_failures.retain(|_, e| !e.is_stale(now));
+    }
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service: Rc::new(service), state: self.state.clone() }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let state = self.state.clone();
+
+        Box::pin(async move {
+            // The body needs to be read to find out which account
+            // is targeted, and then put back for the handler
+            let body = req.extract::<web::Bytes>().await?;
+            let signed_in = match Auth::bearer_token(req.request()) {
+                Some(_) => Auth::from_request(req.request()).await.ok(),
+                None => None
+            };
+            let keys = Keys {
+                ip: req.peer_addr().map(|e| e.ip()),
+                accounts: account_key(&body).map(|e| (req.path().to_string(), e)).into_iter()
+                    .chain(signed_in.map(|e| (SIGNED_IN.to_string(), format!("user:{}", e.user_id))))
+                    .collect()
+            };
+            req.set_payload(bytes_to_payload(body));
+
+            let admitted = state.lock()
+                .map_err(|_| Error::new_with_code("Internal server error (rate limiter)", StatusCode::INTERNAL_SERVER_ERROR))?
+                .admit(&keys, Instant::now());
+            if let Err(retry_after) = admitted {
+                let mut response = Error::new_with_code(
+                    "Too many attempts, try again later",
+                    StatusCode::TOO_MANY_REQUESTS
+                ).error_response();
+
+                // Rounded up, so that retrying right when it says
+                // doesn't get another 429
+                let seconds = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+                response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(seconds.max(1)));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            let response = service.call(req).await;
+            if let Ok(mut state) = state.lock() {
+                let (status, failed) = match &response {
+                    Ok(response) => (
+                        response.status(),
+                        response.response().extensions().contains::<FailedAttempt>()
+                    ),
+                    Err(error) => (
+                        error.as_response_error().status_code(),
+                        error.error_response().extensions().contains::<FailedAttempt>()
+                    )
+                };
+                state.settle(&keys, status, failed);
+            }
+
+            response.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+/// Which account the body of a request targets, if any
+fn account_key(body: &[u8]) -> Option<String> {
+    let json = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+
+    if let Some(email) = json.get("email").and_then(|e| e.as_str()) {
+        Some(format!("email:{}", email.trim().to_lowercase()))
+    }else if let Some(token) = json.get("mfa_token").and_then(|e| e.as_str()) {
+        MfaClaims::is_valid(token).ok().map(|e| format!("user:{}", e.id))
+    }else {
+        None
+    }
+}
+
+fn bytes_to_payload(bytes: web::Bytes) -> Payload {
+    let (_, mut payload) = actix_http::h1::Payload::create(true);
+    payload.unread_data(bytes);
+    Payload::from(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(email: &str) -> Keys {
+        Keys { ip: None, accounts: vec![("/auth/login".to_string(), format!("email:{email}"))] }
+    }
+
+    /// A signed in request to change the email address to `email`
+    fn signed_in(user_id: i64, email: &str) -> Keys {
+        Keys { ip: None, accounts: vec![
+            ("/user/settings/email".to_string(), format!("email:{email}")),
+            (SIGNED_IN.to_string(), format!("user:{user_id}"))
+        ] }
+    }
+
+    fn ip() -> Keys {
+        Keys { ip: Some(IpAddr::from([127, 0, 0, 1])), accounts: Vec::new() }
+    }
+
+    /// Makes an attempt, returns how long it had to wait first
+    fn attempt(state: &mut State, keys: &Keys, now: &mut Instant, status: StatusCode, failed: bool) -> Duration {
+        let mut waited = Duration::ZERO;
+        while let Err(wait) = state.admit(keys, *now) {
+            waited += wait;
+            *now += wait;
+        }
+
+        state.settle(keys, status, failed);
+        waited
+    }
+
+    #[test]
+    fn failures_back_off_and_lock_out() {
+        let (mut state, keys, mut now) = (State::default(), account("a@x.com"), Instant::now());
+        let waits = (0..ACCOUNT_POLICY.lockout_after)
+            .map(|_| attempt(&mut state, &keys, &mut now, StatusCode::NOT_ACCEPTABLE, true).as_secs())
+            .collect::<Vec<u64>>();
+        assert_eq!(waits, [0, 0, 0, 0, 1, 2, 4, 8, 16, 30]);
+
+        assert_eq!(state.admit(&keys, now), Err(LOCKOUT));
+        assert!(state.admit(&keys, now + LOCKOUT).is_ok());
+    }
+
+    #[test]
+    fn other_errors_are_not_failures() {
+        let (mut state, keys, mut now) = (State::default(), account("a@x.com"), Instant::now());
+        for status in [StatusCode::NOT_ACCEPTABLE, StatusCode::UNAUTHORIZED, StatusCode::BAD_REQUEST] {
+            for _ in 0..ACCOUNT_POLICY.lockout_after {
+                assert_eq!(attempt(&mut state, &keys, &mut now, status, false), Duration::ZERO);
+            }
+        }
+    }
+
+    #[test]
+    fn success_forgives_account() {
+        let (mut state, keys, mut now) = (State::default(), account("a@x.com"), Instant::now());
+        for _ in 0..ACCOUNT_POLICY.free_attempts + 2 {
+            attempt(&mut state, &keys, &mut now, StatusCode::NOT_ACCEPTABLE, true);
+        }
+        attempt(&mut state, &keys, &mut now, StatusCode::OK, false);
+
+        for _ in 0..ACCOUNT_POLICY.free_attempts + 1 {
+            assert_eq!(attempt(&mut state, &keys, &mut now, StatusCode::NOT_ACCEPTABLE, true), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn accounts_are_separate() {
+        let (mut state, mut now) = (State::default(), Instant::now());
+        for _ in 0..ACCOUNT_POLICY.lockout_after {
+            attempt(&mut state, &account("a@x.com"), &mut now, StatusCode::NOT_ACCEPTABLE, true);
+        }
+
+        assert!(state.admit(&account("a@x.com"), now).is_err());
+        assert!(state.admit(&account("b@x.com"), now).is_ok());
+    }
+
+    /// Changing what's in the body doesn't get around the
+    /// lockout of who's signed in
+    #[test]
+    fn signed_in_user_is_locked_out() {
+        let (mut state, mut now) = (State::default(), Instant::now());
+        for attempt_index in 0..ACCOUNT_POLICY.lockout_after {
+            let keys = signed_in(1, &format!("{attempt_index}@x.com"));
+            attempt(&mut state, &keys, &mut now, StatusCode::NOT_ACCEPTABLE, true);
+        }
+
+        assert_eq!(state.admit(&signed_in(1, "new@x.com"), now), Err(LOCKOUT));
+        assert!(state.admit(&signed_in(2, "new@x.com"), now).is_ok());
+    }
+
+    #[test]
+    fn failures_are_forgotten() {
+        let (mut state, keys, mut now) = (State::default(), account("a@x.com"), Instant::now());
+        for _ in 0..ACCOUNT_POLICY.free_attempts + 3 {
+            attempt(&mut state, &keys, &mut now, StatusCode::NOT_ACCEPTABLE, true);
+        }
+
+        now += FAILURE_MEMORY + Duration::from_secs(1);
+        assert_eq!(attempt(&mut state, &keys, &mut now, StatusCode::NOT_ACCEPTABLE, true), Duration::ZERO);
+    }
+
+    /// Attempts are counted as failed until they're settled, so
+    /// concurrent ones can't all get past the check
+    #[test]
+    fn unsettled_attempts_count() {
+        let (mut state, keys, now) = (State::default(), account("a@x.com"), Instant::now());
+        for _ in 0..=ACCOUNT_POLICY.free_attempts {
+            assert!(state.admit(&keys, now).is_ok());
+        }
+
+        assert!(state.admit(&keys, now).is_err());
+    }
+
+    #[test]
+    fn ip_requests_are_capped() {
+        let (mut state, keys, now) = (State::default(), ip(), Instant::now());
+        for _ in 0..IP_MAX_REQUESTS {
+            assert!(state.admit(&keys, now).is_ok());
+            state.settle(&keys, StatusCode::OK, false);
+        }
+
+        assert_eq!(state.admit(&keys, now), Err(WINDOW));
+        assert!(state.admit(&keys, now + WINDOW).is_ok());
+    }
+}
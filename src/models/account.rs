@@ -0,0 +1,418 @@
+//! Deleting accounts and exporting everything we
+//! store about a user. Deletion has a grace period:
+//! the account is only purged once it has passed,
+//! and signing in before that cancels it.
+
+/* Imports */
+use std::{io::{Cursor, Write}, time::Duration};
+use actix_web::web;
+use chrono::serde::{ts_milliseconds, ts_milliseconds_option};
+use serde::Serialize;
+use sqlx::PgPool;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+use crate::{error::Error, utils::{logger::log, mailer::{Mail, Mailer}}};
+use super::{api_token::ApiToken, pfp::ProfileImageHandler, session::Session, user::User};
+
+/* Constants */
+/// How many days an account lives after deletion was requested
+const DELETION_GRACE_DAYS: i32 = 30;
+/// How often we look for accounts to purge
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/* Structs */
+#[derive(Serialize)]
+struct ExportProfile {
+    id: i64,
+    handle: String,
+    displayname: String,
+    email: String,
+    email_verified: bool,
+    totp_enabled: bool,
+    followers: i32,
+    following: i32,
+
+    #[serde(with = "ts_milliseconds")]
+    joined: chrono::DateTime<chrono::Utc>,
+}
+#[derive(Serialize)]
+struct ExportPost {
+    id: i64,
+    content: String,
+    replies_to: Option<i64>,
+    citation: Option<serde_json::Value>,
+    hashtags: Vec<String>,
+    total_likes: i64,
+    total_replies: i64,
+
+    #[serde(with = "ts_milliseconds")]
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+/// Someone else's post which the user liked or bookmarked
+#[derive(Serialize)]
+struct ExportPostRef {
+    post_id: i64,
+    handle: String,
+    content: String,
+
+    #[serde(with = "ts_milliseconds")]
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+#[derive(Serialize)]
+struct ExportFollow {
+    user_id: i64,
+    handle: String,
+
+    #[serde(with = "ts_milliseconds_option")]
+    follow_date: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Handles account deletion and data export
+pub struct Account;
+
+impl Account {
+    /// Schedules the account for deletion and signs the user
+    /// out everywhere (API tokens included). Returns when the
+    /// account will be purged.
+    pub async fn request_deletion(
+        pool: &PgPool, mailer: &dyn Mailer,
+        user: &User, password: &str
+    ) -> Result<chrono::DateTime<chrono::Utc>, Error> {
+        if !user.check_password(password).await {
+            return Err(Error::failed_attempt("Invalid password"));
+        }
+
+        let purge_at = sqlx::query_scalar!(r#"
+            UPDATE users SET deletion_requested_at = now()
+            WHERE users.id = $1
+            RETURNING deletion_requested_at + make_interval(days => $2) AS "purge_at!""#,
+            user.id(), DELETION_GRACE_DAYS
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(Error::new)?;
+
+        Session::revoke_all(pool, user.id()).await?;
+        ApiToken::revoke_all(pool, user.id()).await?;
+
+        if let Err(e) = mailer.send(Mail {
+            to: user.email().clone(),
+            subject: String::from("Your account will be deleted"),
+            body: format!(
+                "Your account @{} will be deleted on {}.\n\n\
+                Changed your mind? Sign in again before then and it won't be.",
+                user.handle(), purge_at.format("%Y-%m-%d")
+            )
+        }).await {
+            log::red("request_deletion", format!("Could not send deletion email: {e}"));
+        }
+
+        Ok(purge_at)
+    }
+
+    /// Looks for accounts to purge every `PURGE_INTERVAL`,
+    /// for as long as the server runs
+    pub fn spawn_purge_task(pool: PgPool) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PURGE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = Self::purge_due(&pool).await {
+                    log::red("account_purge", e);
+                }
+            }
+        });
+    }
+
+    /// Deletes every account whose grace period has passed
+    async fn purge_due(pool: &PgPool) -> Result<(), Error> {
+        let user_ids = sqlx::query_scalar!(r#"
+            SELECT id FROM users
+            WHERE deletion_requested_at < now() - make_interval(days => $1)"#,
+            DELETION_GRACE_DAYS
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        for user_id in user_ids {
+            if Self::purge(pool, user_id).await? {
+                log::yellow("account_purge", format!("Deleted account {user_id}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes a user and everything that belongs to them. Most
+    /// rows go with the `users` row (ON DELETE CASCADE), but the
+    /// counters they were part of on other rows need fixing up
+    /// first. Posts other people have replied to are kept as
+    /// empty tombstones, so that those replies survive. Returns
+    /// false if the deletion has been cancelled.
+    pub(crate) async fn purge(pool: &PgPool, user_id: i64) -> Result<bool, Error> {
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+
+        let due = sqlx::query_scalar!(r#"
+            SELECT 1 FROM users
+            WHERE users.id = $1
+                AND deletion_requested_at < now() - make_interval(days => $2)
+            FOR UPDATE"#,
+            user_id, DELETION_GRACE_DAYS
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(Error::new)?
+        .is_some();
+
+        if !due {
+            return Ok(false);
+        }
+
+        // Likes, reposts and replies on other people's posts
+        sqlx::query!(r#"
+            UPDATE posts SET total_likes = total_likes - 1
+            WHERE id IN (SELECT post_id FROM post_likes WHERE user_id = $1)"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+        sqlx::query!(r#"
+            UPDATE posts SET total_reposts = total_reposts - 1
+            WHERE id IN (SELECT post_id FROM reposts WHERE user_id = $1)"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+        sqlx::query!(r#"
+            UPDATE posts SET total_replies = total_replies - replies.count
+            FROM (
+                SELECT replies_to, COUNT(*) AS count FROM posts
+                WHERE poster_id = $1 AND replies_to IS NOT NULL AND deleted_at IS NULL
+                GROUP BY replies_to
+            ) AS replies
+            WHERE posts.id = replies.replies_to"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        // Votes on other people's opinions
+        sqlx::query!(r#"
+            UPDATE post_opinions SET votes = votes - 1
+            WHERE id IN (SELECT opinion_id FROM post_opinion_votes WHERE user_id = $1)"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        // Follower / following counts
+        sqlx::query!(r#"
+            UPDATE users SET followers = followers - 1
+            WHERE id IN (SELECT followee_id FROM follows WHERE follower_id = $1)"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+        sqlx::query!(r#"
+            UPDATE users SET following = following - 1
+            WHERE id IN (SELECT follower_id FROM follows WHERE followee_id = $1)"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        // Quotes of their posts keep pointing to them, but
+        // not with their content
+        sqlx::query!(r#"
+            UPDATE posts SET citation = jsonb_set(citation, '{content_slice}', '""')
+            WHERE citation IS NOT NULL
+                AND (citation->>'post_id')::bigint IN (SELECT id FROM posts WHERE poster_id = $1)"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        // Posts nobody replies to are deleted, a level at a time
+        // so that threads of their own go too. What's left has
+        // replies by others somewhere below it.
+        loop {
+            let deleted = sqlx::query!(r#"
+                DELETE FROM posts
+                WHERE poster_id = $1
+                    AND NOT EXISTS (SELECT 1 FROM posts replies WHERE replies.replies_to = posts.id)"#,
+                user_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::new)?
+            .rows_affected();
+
+            if deleted == 0 { break; }
+        }
+
+        let tombstones = sqlx::query_scalar!(r#"
+            UPDATE posts SET
+                deleted_at = COALESCE(deleted_at, now()),
+                content = '', citation = NULL, edited_at = NULL, revision_count = 0
+            WHERE poster_id = $1
+            RETURNING id"#,
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        for query in [
+            sqlx::query!("DELETE FROM post_revisions WHERE post_id = ANY($1)", &tombstones),
+            sqlx::query!("DELETE FROM post_opinions WHERE post_id = ANY($1)", &tombstones),
+            sqlx::query!("DELETE FROM post_hashtags WHERE post_id = ANY($1)", &tombstones),
+            sqlx::query!("DELETE FROM post_mentions WHERE post_id = ANY($1)", &tombstones),
+            sqlx::query!("DELETE FROM notifications WHERE post_id = ANY($1) OR source_post_id = ANY($1)", &tombstones),
+        ] {
+            query.execute(&mut *tx).await.map_err(Error::new)?;
+        }
+
+        // The tombstones lose their poster (ON DELETE SET NULL)
+        sqlx::query!("DELETE FROM users WHERE users.id = $1", user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::new)?;
+
+        // Hashtags which were only used by their posts
+        sqlx::query!(r#"
+            DELETE FROM hashtags
+            WHERE NOT EXISTS (SELECT 1 FROM post_hashtags WHERE post_hashtags.hashtag_id = hashtags.id)"#
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        tx.commit().await.map_err(Error::new)?;
+        ProfileImageHandler::purge_image(user_id).await?;
+
+        Ok(true)
+    }
+
+    /// Builds a ZIP archive of the user's profile, posts, likes,
+    /// bookmarks and follows (as JSON) and their profile image
+    pub async fn export(pool: &PgPool, user_id: i64) -> Result<Vec<u8>, Error> {
+        let profile = sqlx::query_as!(ExportProfile, r#"
+            SELECT
+                id, handle, displayname, email, email_verified,
+                totp_enabled, followers, following, joined
+            FROM users WHERE users.id = $1"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(Error::new)?;
+
+        let posts = sqlx::query_as!(ExportPost, r#"
+            SELECT
+                posts.id, posts.content, posts.replies_to, posts.citation,
+                posts.total_likes, posts.total_replies, posts.created_at,
+                ARRAY(
+                    SELECT hashtags.tag::TEXT FROM hashtags
+                        JOIN post_hashtags ON post_hashtags.hashtag_id = hashtags.id
+                    WHERE post_hashtags.post_id = posts.id
+                ) AS "hashtags!"
+            FROM posts
+            WHERE posts.poster_id = $1
+            ORDER BY posts.created_at"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        let likes = sqlx::query_as!(ExportPostRef, r#"
+            SELECT posts.id AS post_id, users.handle, posts.content, posts.created_at
+            FROM post_likes
+                JOIN posts ON posts.id = post_likes.post_id
+                JOIN users ON users.id = posts.poster_id
+            WHERE post_likes.user_id = $1
+            ORDER BY posts.created_at"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        let bookmarks = sqlx::query_as!(ExportPostRef, r#"
+            SELECT posts.id AS post_id, users.handle, posts.content, posts.created_at
+            FROM post_bookmarks
+                JOIN posts ON posts.id = post_bookmarks.post_id
+                JOIN users ON users.id = posts.poster_id
+            WHERE post_bookmarks.user_id = $1
+            ORDER BY posts.created_at"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        let following = sqlx::query_as!(ExportFollow, r#"
+            SELECT users.id AS user_id, users.handle, follows.follow_date AT TIME ZONE 'UTC' AS follow_date
+            FROM follows JOIN users ON users.id = follows.followee_id
+            WHERE follows.follower_id = $1
+            ORDER BY follows.follow_date"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        let followers = sqlx::query_as!(ExportFollow, r#"
+            SELECT users.id AS user_id, users.handle, follows.follow_date AT TIME ZONE 'UTC' AS follow_date
+            FROM follows JOIN users ON users.id = follows.follower_id
+            WHERE follows.followee_id = $1
+            ORDER BY follows.follow_date"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        let mut files = vec![
+            ("profile.json", serde_json::to_vec_pretty(&profile).map_err(Error::new)?),
+            ("posts.json", serde_json::to_vec_pretty(&posts).map_err(Error::new)?),
+            ("likes.json", serde_json::to_vec_pretty(&likes).map_err(Error::new)?),
+            ("bookmarks.json", serde_json::to_vec_pretty(&bookmarks).map_err(Error::new)?),
+            ("following.json", serde_json::to_vec_pretty(&following).map_err(Error::new)?),
+            ("followers.json", serde_json::to_vec_pretty(&followers).map_err(Error::new)?),
+        ];
+        if let Some(image) = ProfileImageHandler::read_image(user_id).await {
+            files.push(("profile-image.jpg", image));
+        }
+
+        web::block(move || Self::zip(files))
+            .await
+            .map_err(Error::new)?
+    }
+
+    fn zip(files: Vec<(&'static str, Vec<u8>)>) -> Result<Vec<u8>, Error> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, bytes) in files {
+            // JPEGs are already compressed
+            let method = match name.ends_with(".jpg") {
+                true => CompressionMethod::Stored,
+                false => CompressionMethod::Deflated
+            };
+
+            zip.start_file(name, SimpleFileOptions::default().compression_method(method))
+                .map_err(Error::new)?;
+            zip.write_all(&bytes).map_err(Error::new)?;
+        }
+
+        zip.finish()
+            .map(|e| e.into_inner())
+            .map_err(Error::new)
+    }
+}
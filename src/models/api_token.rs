@@ -0,0 +1,196 @@
+//! Personal access tokens, used by bots and scripts
+//! instead of signing in with a password. Every
+//! token has a set of scopes which limit what it
+//! can be used for.
+
+/* Imports */
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use unicode_segmentation::UnicodeSegmentation;
+use crate::{error::Error, middleware::auth::{Auth, Credential}, utils::token};
+
+/* Constants */
+/// Every API token starts with this, which is how we tell
+/// them apart from JWTs in the `Authorization` header
+pub const API_TOKEN_PREFIX: &str = "t2p_";
+const API_TOKEN_SECRET_LEN: usize = 40;
+const NAME_MAX_LEN: usize = 50;
+
+/// What an API token is allowed to do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiScope {
+    /// Reading feeds, posts and profiles
+    #[serde(rename = "read")]
+    Read,
+
+    /// Publishing, deleting, liking, following etc
+    #[serde(rename = "post:write")]
+    PostWrite,
+
+    /// Direct messages
+    #[serde(rename = "dm")]
+    Dm,
+
+    /// Managing the account itself (settings, sessions,
+    /// two factor authentication and API tokens)
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+/// An API token as shown to its owner (without the token)
+#[derive(Serialize)]
+pub struct ApiToken {
+    id: i64,
+    name: String,
+    scopes: Vec<String>,
+
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::PostWrite => "post:write",
+            Self::Dm => "dm",
+            Self::Admin => "admin",
+        }
+    }
+
+    pub fn parse(from: &str) -> Option<Self> {
+        match from {
+            "read" => Some(Self::Read),
+            "post:write" => Some(Self::PostWrite),
+            "dm" => Some(Self::Dm),
+            "admin" => Some(Self::Admin),
+            _ => None
+        }
+    }
+}
+
+impl ApiToken {
+    /// Creates a token and returns it. This is the only time
+    /// the token itself is available, we only store its hash.
+    pub async fn create(
+        pool: &PgPool, user_id: i64, name: String,
+        scopes: Vec<ApiScope>, expires_in_days: Option<i32>
+    ) -> Result<String, Error> {
+        let len = UnicodeSegmentation::graphemes(name.as_str(), true).count();
+        if len == 0 || len > NAME_MAX_LEN {
+            return Err(Error::new(format!("Token name must be between 1 and {} characters long", NAME_MAX_LEN)));
+        }
+        if scopes.is_empty() {
+            return Err(Error::new("A token needs at least one scope"));
+        }
+
+        let token = String::from(API_TOKEN_PREFIX) + &token::generate(API_TOKEN_SECRET_LEN);
+        let mut scopes = scopes.iter()
+            .map(|e| e.as_str().to_string())
+            .collect::<Vec<String>>();
+        scopes.sort();
+        scopes.dedup();
+
+        sqlx::query!(r#"
+            INSERT INTO api_tokens
+            (user_id, name, token_hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, now() + make_interval(days => $5))"#,
+            user_id, name, token::hash(&token), &scopes, expires_in_days
+        )
+        .execute(pool)
+        .await
+        .map_err(Error::new)?;
+
+        Ok(token)
+    }
+
+    /// Every token of a user which hasn't been revoked
+    pub async fn list(pool: &PgPool, user_id: i64) -> Result<Vec<Self>, Error> {
+        sqlx::query_as!(Self, r#"
+            SELECT id, name, scopes, created_at, expires_at, last_used_at
+            FROM api_tokens
+            WHERE user_id = $1 AND revoked_at IS NULL
+            ORDER BY created_at DESC"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)
+    }
+
+    /// Revokes a token owned by the user
+    pub async fn revoke(pool: &PgPool, user_id: i64, token_id: i64) -> Result<(), Error> {
+        let result = sqlx::query!(r#"
+            UPDATE api_tokens SET revoked_at = now()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL"#,
+            token_id, user_id
+        )
+        .execute(pool)
+        .await
+        .map_err(Error::new)?;
+
+        match result.rows_affected() {
+            0 => Err(Error::new("Token not found")),
+            _ => Ok(())
+        }
+    }
+
+    /// Revokes every token of a user
+    pub async fn revoke_all<'a, E>(executor: E, user_id: i64) -> Result<(), Error>
+    where E: sqlx::Executor<'a, Database = sqlx::Postgres>
+    {
+        sqlx::query!(r#"
+            UPDATE api_tokens SET revoked_at = now()
+            WHERE user_id = $1 AND revoked_at IS NULL"#,
+            user_id
+        )
+        .execute(executor)
+        .await
+        .map(|_| ())
+        .map_err(Error::new)
+    }
+
+    /// Looks up a token sent in the `Authorization` header,
+    /// and bumps its `last_used_at`
+    pub async fn authenticate(pool: &PgPool, token: &str) -> Result<Option<Auth>, Error> {
+        let row = sqlx::query!(r#"
+            UPDATE api_tokens SET last_used_at = now()
+            WHERE token_hash = $1
+                AND revoked_at IS NULL
+                AND (expires_at IS NULL OR expires_at > now())
+            RETURNING id, user_id, scopes"#,
+            token::hash(token)
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(Error::new)?;
+
+        Ok(row.map(|e| Auth {
+            user_id: e.user_id,
+            scopes: Some(e.scopes.iter().filter_map(|e| ApiScope::parse(e)).collect()),
+            credential: Credential::ApiToken(e.id)
+        }))
+    }
+
+    /// If the token exists, belongs to the user and
+    /// has neither expired nor been revoked
+    pub async fn is_active(pool: &PgPool, user_id: i64, token_id: i64) -> Result<bool, Error> {
+        sqlx::query_scalar!(r#"
+            SELECT EXISTS(
+                SELECT 1 FROM api_tokens
+                WHERE api_tokens.id = $1
+                AND api_tokens.user_id = $2
+                AND api_tokens.revoked_at IS NULL
+                AND (api_tokens.expires_at IS NULL OR api_tokens.expires_at > now())
+            ) AS "active!: bool""#,
+            token_id, user_id
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(Error::new)
+    }
+}
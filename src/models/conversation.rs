@@ -0,0 +1,480 @@
+//! Direct messages. A conversation is either between two
+//! users or a group, and is started by sending the first
+//! message to someone. Users can't message anyone who they
+//! have blocked or who has blocked them, nor start a
+//! conversation with anyone who only allows messages from
+//! people they follow (see `DmPolicy`).
+
+/* Imports */
+use actix_web::http::StatusCode;
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use unicode_segmentation::UnicodeSegmentation;
+use crate::{error::Error, utils::hub::{hub, Event}};
+use super::page::{Page, PageRequest};
+
+/* Constants */
+const MESSAGE_MAX_LEN: usize = 1000;
+
+/// Including whoever starts the group
+const GROUP_MAX_MEMBERS: usize = 50;
+
+/// Who can start a conversation with a user
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DmPolicy {
+    Everyone,
+
+    /// Only people they follow
+    Following,
+}
+
+/// A conversation as listed in the inbox
+#[derive(Serialize)]
+pub struct ConversationSummary {
+    pub id: i64,
+    pub is_group: bool,
+
+    /// Everyone in it except the user, `[{ user_id, handle, displayname }]`
+    pub members: serde_json::Value,
+
+    /// `{ id, sender_id, content, created_at }`, `None` if the
+    /// user hasn't got any messages in it (left and rejoined,
+    /// or deleted them)
+    pub last_message: Option<serde_json::Value>,
+
+    /// How many messages from others haven't been read
+    pub unread: i64,
+
+    #[serde(with = "ts_milliseconds")]
+    pub last_message_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct Message {
+    pub id: i64,
+    pub conversation_id: i64,
+
+    /// `None` if the sender has deleted their account
+    pub sender_id: Option<i64>,
+    pub content: String,
+
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Handles direct message related things
+pub struct Conversation;
+
+impl DmPolicy {
+    /// What it's stored as in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Everyone => "everyone",
+            Self::Following => "following",
+        }
+    }
+}
+
+impl Conversation {
+    /// Starts a conversation with `to` by sending the first message.
+    /// If `to` is one user and the two already have a conversation
+    /// the message is sent there instead (rejoining it if either
+    /// of them has left).
+    pub async fn start(pool: &PgPool, sender_id: i64, mut to: Vec<i64>, content: String) -> Result<Message, Error> {
+        Self::message_valid(&content)?;
+        to.sort_unstable();
+        to.dedup();
+        to.retain(|e| *e != sender_id);
+        if to.is_empty() {
+            return Err(Error::new("A conversation needs at least one other member"));
+        }
+        if to.len() + 1 > GROUP_MAX_MEMBERS {
+            return Err(Error::new(format!("A group can't have more than {} members", GROUP_MAX_MEMBERS)));
+        }
+
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        let direct_key = match to.as_slice() {
+            [user_id] => Some(format!("{}:{}", sender_id.min(*user_id), sender_id.max(*user_id))),
+            _ => None
+        };
+
+        // Messaging someone you already have a conversation with
+        // isn't starting one, so their `DmPolicy` doesn't apply
+        let exists = match &direct_key {
+            Some(direct_key) => sqlx::query_scalar!(
+                r#"SELECT EXISTS(SELECT 1 FROM conversations WHERE direct_key = $1) AS "exists!""#,
+                direct_key
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(Error::new)?,
+            None => false
+        };
+        Self::can_message(&mut tx, sender_id, &to, !exists).await?;
+
+        let conversation_id = match direct_key {
+            Some(direct_key) => sqlx::query_scalar!(r#"
+                INSERT INTO conversations (is_group, direct_key) VALUES (false, $1)
+                ON CONFLICT (direct_key) DO UPDATE SET direct_key = excluded.direct_key
+                RETURNING id"#,
+                direct_key
+            ).fetch_one(&mut *tx).await,
+            None => sqlx::query_scalar!(
+                "INSERT INTO conversations (is_group) VALUES (true) RETURNING id"
+            ).fetch_one(&mut *tx).await
+        }
+        .map_err(Error::new)?;
+
+        // Members who already are in it keep seeing what they saw before
+        sqlx::query!(r#"
+            INSERT INTO conversation_members (conversation_id, user_id)
+            SELECT $1, unnest($2::bigint[])
+            ON CONFLICT DO NOTHING"#,
+            conversation_id, &[to.as_slice(), &[sender_id]].concat()
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        let message = Self::insert_message(&mut tx, sender_id, conversation_id, content).await?;
+        tx.commit().await.map_err(Error::new)?;
+        Self::publish(pool, &message).await;
+        Ok(message)
+    }
+
+    /// Sends a message in a conversation the sender is in. Nobody
+    /// else in it may have blocked the sender (or been blocked by
+    /// them), but their `DmPolicy` only applies to starting one. In
+    /// one-to-one conversations the other user is brought back if
+    /// they have left.
+    pub async fn send(pool: &PgPool, sender_id: i64, conversation_id: i64, content: String) -> Result<Message, Error> {
+        Self::message_valid(&content)?;
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+
+        let conversation = sqlx::query!(r#"
+            SELECT conversations.is_group, conversations.direct_key
+            FROM conversations
+                JOIN conversation_members members ON members.conversation_id = conversations.id
+            WHERE conversations.id = $1 AND members.user_id = $2
+            FOR UPDATE OF conversations"#,
+            conversation_id, sender_id
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(Error::new)?
+        .ok_or(Self::not_found())?;
+
+        let other_id = conversation.direct_key.as_deref()
+            .and_then(|key| key.split(':')
+                .filter_map(|e| e.parse::<i64>().ok())
+                .find(|e| *e != sender_id)
+            );
+        let others = match other_id {
+            Some(other_id) => vec![other_id],
+            None => sqlx::query_scalar!(
+                "SELECT user_id FROM conversation_members WHERE conversation_id = $1 AND user_id <> $2",
+                conversation_id, sender_id
+            )
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(Error::new)?
+        };
+        Self::can_message(&mut tx, sender_id, &others, false).await?;
+
+        if let Some(other_id) = other_id {
+            sqlx::query!(r#"
+                INSERT INTO conversation_members (conversation_id, user_id) VALUES ($1, $2)
+                ON CONFLICT DO NOTHING"#,
+                conversation_id, other_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::new)?;
+        }
+
+        let message = Self::insert_message(&mut tx, sender_id, conversation_id, content).await?;
+        tx.commit().await.map_err(Error::new)?;
+        Self::publish(pool, &message).await;
+        Ok(message)
+    }
+
+    /// The conversations a user is in, most recently active first
+    pub async fn list(pool: &PgPool, user_id: i64, page: &PageRequest) -> Result<Page<ConversationSummary>, Error> {
+        let (last_message_at, id) = page.cursor::<DateTime<Utc>>()?.unzip();
+        let conversations = sqlx::query_as!(ConversationSummary, r#"
+            SELECT
+                conversations.id, conversations.is_group, conversations.last_message_at,
+                COALESCE((
+                    SELECT jsonb_agg(jsonb_build_object(
+                        'user_id', users.id,
+                        'handle', users.handle,
+                        'displayname', users.displayname
+                    ) ORDER BY others.joined_at, users.id)
+                    FROM conversation_members others
+                        JOIN users ON users.id = others.user_id
+                    WHERE others.conversation_id = conversations.id AND others.user_id <> $1
+                ), '[]') AS "members!",
+                (
+                    SELECT jsonb_build_object(
+                        'id', messages.id,
+                        'sender_id', messages.sender_id,
+                        'content', messages.content,
+                        'created_at', floor(extract(epoch FROM messages.created_at) * 1000)::bigint
+                    )
+                    FROM messages
+                    WHERE messages.conversation_id = conversations.id
+                        AND messages.created_at >= members.joined_at
+                        AND NOT EXISTS (
+                            SELECT 1 FROM hidden_messages hidden
+                            WHERE hidden.user_id = $1 AND hidden.message_id = messages.id
+                        )
+                    ORDER BY messages.created_at DESC, messages.id DESC
+                    LIMIT 1
+                ) AS last_message,
+                (
+                    SELECT COUNT(*) FROM messages
+                    WHERE messages.conversation_id = conversations.id
+                        AND messages.id > members.last_read_message_id
+                        AND messages.sender_id IS DISTINCT FROM $1
+                        AND messages.created_at >= members.joined_at
+                        AND NOT EXISTS (
+                            SELECT 1 FROM hidden_messages hidden
+                            WHERE hidden.user_id = $1 AND hidden.message_id = messages.id
+                        )
+                ) AS "unread!"
+            FROM conversation_members members
+                JOIN conversations ON conversations.id = members.conversation_id
+            WHERE members.user_id = $1
+                AND ($2::timestamptz IS NULL OR (conversations.last_message_at, conversations.id) < ($2, $3))
+            ORDER BY conversations.last_message_at DESC, conversations.id DESC
+            LIMIT $4"#,
+            user_id, last_message_at, id, page.fetch_limit()
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        Ok(Page::new(conversations, page, |e| (e.last_message_at, e.id)))
+    }
+
+    /// Messages in a conversation, newest first. Only those sent
+    /// since the user joined and which they haven't deleted.
+    pub async fn messages(pool: &PgPool, user_id: i64, conversation_id: i64, page: &PageRequest) -> Result<Page<Message>, Error> {
+        let (created_at, id) = page.cursor::<DateTime<Utc>>()?.unzip();
+        let joined_at = sqlx::query_scalar!(
+            "SELECT joined_at FROM conversation_members WHERE conversation_id = $1 AND user_id = $2",
+            conversation_id, user_id
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(Error::new)?
+        .ok_or(Self::not_found())?;
+
+        let messages = sqlx::query_as!(Message, r#"
+            SELECT messages.id, messages.conversation_id, messages.sender_id, messages.content, messages.created_at
+            FROM messages
+            WHERE messages.conversation_id = $1
+                AND messages.created_at >= $3
+                AND NOT EXISTS (
+                    SELECT 1 FROM hidden_messages hidden
+                    WHERE hidden.user_id = $2 AND hidden.message_id = messages.id
+                )
+                AND ($4::timestamptz IS NULL OR (messages.created_at, messages.id) < ($4, $5))
+            ORDER BY messages.created_at DESC, messages.id DESC
+            LIMIT $6"#,
+            conversation_id, user_id, joined_at, created_at, id, page.fetch_limit()
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        Ok(Page::new(messages, page, |e| (e.created_at, e.id)))
+    }
+
+    /// Marks the messages in a conversation as read, either all
+    /// of them or up to (and including) `up_to`. Messages which
+    /// haven't been sent yet can't be marked as read.
+    pub async fn mark_read(pool: &PgPool, user_id: i64, conversation_id: i64, up_to: Option<i64>) -> Result<(), Error> {
+        let result = sqlx::query!(r#"
+            UPDATE conversation_members SET last_read_message_id = GREATEST(
+                last_read_message_id,
+                LEAST(COALESCE($3, latest.id), latest.id)
+            )
+            FROM (
+                SELECT COALESCE(MAX(messages.id), 0) AS id
+                FROM messages WHERE messages.conversation_id = $1
+            ) AS latest
+            WHERE conversation_id = $1 AND user_id = $2"#,
+            conversation_id, user_id, up_to
+        )
+        .execute(pool)
+        .await
+        .map_err(Error::new)?;
+
+        match result.rows_affected() {
+            0 => Err(Self::not_found()),
+            _ => Ok(())
+        }
+    }
+
+    /// Leaves a conversation. The conversation is deleted once
+    /// everyone has left.
+    pub async fn leave(pool: &PgPool, user_id: i64, conversation_id: i64) -> Result<(), Error> {
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        let result = sqlx::query!(
+            "DELETE FROM conversation_members WHERE conversation_id = $1 AND user_id = $2",
+            conversation_id, user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+        if result.rows_affected() == 0 {
+            return Err(Self::not_found());
+        }
+
+        sqlx::query!(r#"
+            DELETE FROM conversations
+            WHERE conversations.id = $1 AND NOT EXISTS (
+                SELECT 1 FROM conversation_members members WHERE members.conversation_id = $1
+            )"#,
+            conversation_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        tx.commit().await.map_err(Error::new)
+    }
+
+    /// Deletes a message for the user only, everyone else
+    /// in the conversation can still see it
+    pub async fn delete_message(pool: &PgPool, user_id: i64, message_id: i64) -> Result<(), Error> {
+        let result = sqlx::query!(r#"
+            INSERT INTO hidden_messages (user_id, message_id)
+            SELECT $1, messages.id
+            FROM messages
+                JOIN conversation_members members
+                    ON members.conversation_id = messages.conversation_id AND members.user_id = $1
+            WHERE messages.id = $2 AND messages.created_at >= members.joined_at
+            ON CONFLICT DO NOTHING"#,
+            user_id, message_id
+        )
+        .execute(pool)
+        .await
+        .map_err(Error::new)?;
+
+        match result.rows_affected() {
+            0 => Err(Error::new_with_code("Message not found", StatusCode::NOT_FOUND)),
+            _ => Ok(())
+        }
+    }
+
+    /// Errors unless every user in `to` exists and neither blocked
+    /// nor is blocked by `sender_id`. When `starting` a conversation
+    /// they also have to accept messages from them (`DmPolicy`).
+    async fn can_message(
+        tx: &mut Transaction<'_, Postgres>, sender_id: i64,
+        to: &[i64], starting: bool
+    ) -> Result<(), Error> {
+        let recipients = sqlx::query!(r#"
+            SELECT
+                users.handle,
+                NOT EXISTS (
+                    SELECT 1 FROM blocks
+                    WHERE (blocks.blocker_id = users.id AND blocks.blocked_id = $1)
+                        OR (blocks.blocker_id = $1 AND blocks.blocked_id = users.id)
+                ) AND (
+                    NOT $3 OR users.dm_policy = 'everyone' OR EXISTS (
+                        SELECT 1 FROM follows
+                        WHERE follows.follower_id = users.id AND follows.followee_id = $1
+                    )
+                ) AS "allowed!"
+            FROM users
+            WHERE users.id = ANY($2)"#,
+            sender_id, to, starting
+        )
+        .fetch_all(&mut **tx)
+        .await
+        .map_err(Error::new)?;
+
+        if recipients.len() != to.len() {
+            return Err(Error::new_with_code("User not found", StatusCode::NOT_FOUND));
+        }
+        match recipients.iter().find(|e| !e.allowed) {
+            Some(e) => Err(Error::new_with_code(format!("@{} can't be messaged", e.handle), StatusCode::FORBIDDEN)),
+            None => Ok(())
+        }
+    }
+
+    /// Inserts a message, which the sender has read
+    async fn insert_message(
+        tx: &mut Transaction<'_, Postgres>, sender_id: i64,
+        conversation_id: i64, content: String
+    ) -> Result<Message, Error> {
+        let message = sqlx::query_as!(Message, r#"
+            INSERT INTO messages (conversation_id, sender_id, content) VALUES ($1, $2, $3)
+            RETURNING id, conversation_id, sender_id, content, created_at"#,
+            conversation_id, sender_id, content
+        )
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(Error::new)?;
+
+        sqlx::query!(
+            "UPDATE conversations SET last_message_at = $2 WHERE id = $1",
+            conversation_id, message.created_at
+        )
+        .execute(&mut **tx)
+        .await
+        .map_err(Error::new)?;
+
+        sqlx::query!(r#"
+            UPDATE conversation_members SET last_read_message_id = $3
+            WHERE conversation_id = $1 AND user_id = $2"#,
+            conversation_id, sender_id, message.id
+        )
+        .execute(&mut **tx)
+        .await
+        .map_err(Error::new)?;
+
+        Ok(message)
+    }
+
+    /// Tells the open streams of the other members. Best
+    /// effort, the message has already been sent.
+    async fn publish(pool: &PgPool, message: &Message) {
+        let recipients = sqlx::query_scalar!(
+            "SELECT user_id FROM conversation_members WHERE conversation_id = $1 AND user_id <> $2",
+            message.conversation_id, message.sender_id
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+
+        hub().publish(Event::Message {
+            conversation_id: message.conversation_id,
+            message_id: message.id,
+            sender_id: message.sender_id.unwrap_or_default(),
+            recipients
+        });
+    }
+
+    fn message_valid(content: &str) -> Result<(), Error> {
+        let len = UnicodeSegmentation::graphemes(content.trim(), true).count();
+        if len == 0 {
+            return Err(Error::new("Message can't be empty"));
+        }
+        if len > MESSAGE_MAX_LEN {
+            return Err(Error::new(format!("Message must be less than {} characters long", MESSAGE_MAX_LEN)));
+        }
+
+        Ok(())
+    }
+
+    /// Also what conversations the user isn't in look like
+    fn not_found() -> Error {
+        Error::new_with_code("Conversation not found", StatusCode::NOT_FOUND)
+    }
+}
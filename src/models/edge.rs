@@ -0,0 +1,351 @@
+//! Edges are the on / off relations from a user to a post or
+//! another user: likes, bookmarks, reposts, follows and blocks.
+//! Each one is a marker type implementing `Edge` with its own
+//! (compile-time checked) queries, and `Edge::set` toggles any
+//! of them the same way, e.g `edge::Like::set(pool, user_id,
+//! post_id, true)`.
+
+/* Imports */
+use actix_web::http::StatusCode;
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+use crate::{error::Error, utils::hub::{hub, Event}};
+use super::notification::{Notification, NotificationKind};
+
+/// What toggling an edge responds with
+#[derive(Serialize, Debug)]
+pub struct EdgeState {
+    /// If the edge exists now
+    pub on: bool,
+
+    /// The count the edge is part of, see `Edge::count`
+    pub count: i64,
+}
+
+pub trait Edge {
+    /// What `to` is, for errors ("Post" or "User")
+    const TARGET: &'static str;
+
+    /// Adds the edge, returns false if it already existed
+    async fn insert(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error>;
+
+    /// Removes the edge, returns false if it didn't exist
+    async fn delete(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error>;
+
+    /// Adds `delta` to the count the edge is part of and returns
+    /// it. `delta` is 0 if nothing changed, in which case the count
+    /// is only read so that no-op toggles don't lock any rows. Fails
+    /// with `RowNotFound` if `to` doesn't exist, or is a deleted post.
+    async fn count(conn: &mut PgConnection, from: i64, to: i64, delta: i64) -> Result<i64, sqlx::Error>;
+
+    /// Refuses edges which can't exist, like following yourself
+    fn check(from: i64, to: i64) -> Result<(), Error> { Ok(()) }
+
+    /// Runs in the same transaction once the edge has changed, for
+    /// notifications. Returns the events to publish once committed.
+    async fn changed(conn: &mut PgConnection, from: i64, to: i64, on: bool) -> Result<Vec<Event>, Error> { Ok(Vec::new()) }
+
+    /// Turns the edge from `from` to `to` on or off. The count is
+    /// changed in the same transaction as the edge, so toggling
+    /// concurrently can't make it drift, and only if the edge
+    /// actually changed.
+    async fn set(pool: &PgPool, from: i64, to: i64, on: bool) -> Result<EdgeState, Error> where Self: Sized {
+        Self::check(from, to)?;
+
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        let changed = match on {
+            true => Self::insert(&mut tx, from, to).await,
+            false => Self::delete(&mut tx, from, to).await
+        }
+        .map_err(Self::error)?;
+
+        let delta = match (changed, on) {
+            (false, _) => 0,
+            (true, true) => 1,
+            (true, false) => -1
+        };
+        let count = Self::count(&mut tx, from, to, delta).await.map_err(Self::error)?;
+        let events = match changed {
+            true => Self::changed(&mut tx, from, to, on).await?,
+            false => Vec::new()
+        };
+        tx.commit().await.map_err(Error::new)?;
+
+        hub().publish_all(events);
+        Ok(EdgeState { on, count })
+    }
+
+    /// `to` not existing is a 404
+    fn error(error: sqlx::Error) -> Error where Self: Sized {
+        let not_found = matches!(error, sqlx::Error::RowNotFound)
+            || error.as_database_error().is_some_and(|e| e.is_foreign_key_violation());
+
+        match not_found {
+            true => Error::new_with_code(format!("{} not found", Self::TARGET), StatusCode::NOT_FOUND),
+            false => Error::new(error)
+        }
+    }
+}
+
+/* Edges from a user to a post */
+pub struct Like;
+pub struct Bookmark;
+pub struct Repost;
+
+/* Edges from a user to a user */
+pub struct Follow;
+pub struct Block;
+
+/// Counted by the post's `total_likes`
+impl Edge for Like {
+    const TARGET: &'static str = "Post";
+
+    async fn insert(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO post_likes (user_id, post_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            from, to
+        )
+        .execute(conn)
+        .await
+        .map(|e| e.rows_affected() > 0)
+    }
+
+    async fn delete(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query!("DELETE FROM post_likes WHERE user_id = $1 AND post_id = $2", from, to)
+            .execute(conn)
+            .await
+            .map(|e| e.rows_affected() > 0)
+    }
+
+    async fn count(conn: &mut PgConnection, _from: i64, to: i64, delta: i64) -> Result<i64, sqlx::Error> {
+        match delta {
+            0 => sqlx::query_scalar!("SELECT total_likes FROM posts WHERE id = $1 AND deleted_at IS NULL", to)
+                .fetch_one(conn)
+                .await,
+            _ => sqlx::query_scalar!(
+                "UPDATE posts SET total_likes = total_likes + $2 WHERE id = $1 AND deleted_at IS NULL RETURNING total_likes",
+                to, delta
+            )
+            .fetch_one(conn)
+            .await
+        }
+    }
+
+    async fn changed(conn: &mut PgConnection, from: i64, to: i64, on: bool) -> Result<Vec<Event>, Error> {
+        let mut events = vec![counters(&mut *conn, to).await?];
+        match on {
+            true => events.extend(Notification::notify_poster(&mut *conn, NotificationKind::Like, from, to, None).await?),
+            false => Notification::retract(&mut *conn, NotificationKind::Like, from, None, Some(to)).await?
+        }
+
+        Ok(events)
+    }
+}
+
+/// Counted by how many have bookmarked the post
+impl Edge for Bookmark {
+    const TARGET: &'static str = "Post";
+
+    async fn insert(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO post_bookmarks (user_id, post_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            from, to
+        )
+        .execute(conn)
+        .await
+        .map(|e| e.rows_affected() > 0)
+    }
+
+    async fn delete(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query!("DELETE FROM post_bookmarks WHERE user_id = $1 AND post_id = $2", from, to)
+            .execute(conn)
+            .await
+            .map(|e| e.rows_affected() > 0)
+    }
+
+    async fn count(conn: &mut PgConnection, _from: i64, to: i64, _delta: i64) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!(r#"
+            SELECT (SELECT COUNT(*) FROM post_bookmarks WHERE post_bookmarks.post_id = posts.id) AS "count!"
+            FROM posts WHERE posts.id = $1 AND posts.deleted_at IS NULL"#,
+            to
+        )
+        .fetch_one(conn)
+        .await
+    }
+}
+
+/// Counted by the post's `total_reposts`
+impl Edge for Repost {
+    const TARGET: &'static str = "Post";
+
+    async fn insert(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO reposts (user_id, post_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            from, to
+        )
+        .execute(conn)
+        .await
+        .map(|e| e.rows_affected() > 0)
+    }
+
+    async fn delete(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query!("DELETE FROM reposts WHERE user_id = $1 AND post_id = $2", from, to)
+            .execute(conn)
+            .await
+            .map(|e| e.rows_affected() > 0)
+    }
+
+    async fn count(conn: &mut PgConnection, _from: i64, to: i64, delta: i64) -> Result<i64, sqlx::Error> {
+        match delta {
+            0 => sqlx::query_scalar!("SELECT total_reposts FROM posts WHERE id = $1 AND deleted_at IS NULL", to)
+                .fetch_one(conn)
+                .await,
+            _ => sqlx::query_scalar!(
+                "UPDATE posts SET total_reposts = total_reposts + $2 WHERE id = $1 AND deleted_at IS NULL RETURNING total_reposts",
+                to, delta
+            )
+            .fetch_one(conn)
+            .await
+        }
+    }
+
+    async fn changed(conn: &mut PgConnection, _from: i64, to: i64, _on: bool) -> Result<Vec<Event>, Error> {
+        Ok(vec![counters(conn, to).await?])
+    }
+}
+
+/// Counted by the followed user's `followers`
+/// (and the follower's `following`)
+impl Edge for Follow {
+    const TARGET: &'static str = "User";
+
+    async fn insert(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO follows (follower_id, followee_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            from, to
+        )
+        .execute(conn)
+        .await
+        .map(|e| e.rows_affected() > 0)
+    }
+
+    async fn delete(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query!("DELETE FROM follows WHERE follower_id = $1 AND followee_id = $2", from, to)
+            .execute(conn)
+            .await
+            .map(|e| e.rows_affected() > 0)
+    }
+
+    async fn count(conn: &mut PgConnection, from: i64, to: i64, delta: i64) -> Result<i64, sqlx::Error> {
+        if delta == 0 {
+            return sqlx::query_scalar!(
+                r#"SELECT followers::bigint AS "followers!" FROM users WHERE users.id = $1"#,
+                to
+            )
+            .fetch_one(conn)
+            .await;
+        }
+
+        // Both users get their counts changed. Locking them in the
+        // same order every time keeps two users following each other
+        // at the same time from deadlocking.
+        sqlx::query!(r#"
+            SELECT 1 AS locked FROM users
+            WHERE users.id IN ($1, $2)
+            ORDER BY users.id
+            FOR NO KEY UPDATE"#,
+            from, to
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE users SET following = following + $2 WHERE users.id = $1",
+            from, delta as i32
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query_scalar!(
+            r#"UPDATE users SET followers = followers + $2 WHERE users.id = $1 RETURNING followers::bigint AS "followers!""#,
+            to, delta as i32
+        )
+        .fetch_one(&mut *conn)
+        .await
+    }
+
+    fn check(from: i64, to: i64) -> Result<(), Error> {
+        match from == to {
+            true => Err(Error::new_with_code("You can't follow yourself", StatusCode::BAD_REQUEST)),
+            false => Ok(())
+        }
+    }
+
+    async fn changed(conn: &mut PgConnection, from: i64, to: i64, on: bool) -> Result<Vec<Event>, Error> {
+        let mut events = vec![Event::Follow { follower_id: from, followee_id: to, follow: on }];
+        match on {
+            true => events.extend(Notification::notify_user(&mut *conn, NotificationKind::Follow, to, from).await?),
+            false => Notification::retract(&mut *conn, NotificationKind::Follow, from, Some(to), None).await?
+        }
+
+        Ok(events)
+    }
+}
+
+/// Neither user can message the other while blocked. Counted
+/// by how many users the blocker has blocked, since how many
+/// have blocked someone isn't for anyone to know.
+impl Edge for Block {
+    const TARGET: &'static str = "User";
+
+    async fn insert(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO blocks (blocker_id, blocked_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            from, to
+        )
+        .execute(conn)
+        .await
+        .map(|e| e.rows_affected() > 0)
+    }
+
+    async fn delete(conn: &mut PgConnection, from: i64, to: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query!("DELETE FROM blocks WHERE blocker_id = $1 AND blocked_id = $2", from, to)
+            .execute(conn)
+            .await
+            .map(|e| e.rows_affected() > 0)
+    }
+
+    async fn count(conn: &mut PgConnection, from: i64, to: i64, _delta: i64) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar!(r#"
+            SELECT (SELECT COUNT(*) FROM blocks WHERE blocks.blocker_id = $1) AS "count!"
+            FROM users WHERE users.id = $2"#,
+            from, to
+        )
+        .fetch_one(conn)
+        .await
+    }
+
+    fn check(from: i64, to: i64) -> Result<(), Error> {
+        match from == to {
+            true => Err(Error::new_with_code("You can't block yourself", StatusCode::BAD_REQUEST)),
+            false => Ok(())
+        }
+    }
+}
+
+/// The event letting streams watching `post_id` know its new counters
+async fn counters(conn: &mut PgConnection, post_id: i64) -> Result<Event, Error> {
+    let counters = sqlx::query!(
+        "SELECT total_likes, total_replies, total_reposts FROM posts WHERE id = $1",
+        post_id
+    )
+    .fetch_one(conn)
+    .await
+    .map_err(Error::new)?;
+
+    Ok(Event::Counters {
+        post_id,
+        total_likes: counters.total_likes,
+        total_replies: counters.total_replies,
+        total_reposts: counters.total_reposts
+    })
+}
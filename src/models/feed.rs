@@ -0,0 +1,232 @@
+//! Feeds which reposts show up in. A post is listed either
+//! for being posted, or for being reposted by someone the
+//! feed is about (e.g someone the user follows) - and only
+//! once, at the most recent of those.
+
+/* Imports */
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use crate::error::Error;
+use super::{page::{Page, PageRequest}, post::PostWithUser};
+
+/// A post in a feed
+#[derive(Serialize)]
+pub struct FeedPost {
+    #[serde(flatten)]
+    pub post: PostWithUser,
+
+    /// Who reposted it, `{ user_id, handle, displayname }`.
+    /// `None` if it's listed for being posted.
+    pub reposted_by: Option<serde_json::Value>,
+
+    /// When it was posted or reposted, which is what
+    /// feeds are ordered by
+    #[serde(with = "ts_milliseconds")]
+    pub feed_at: DateTime<Utc>,
+}
+
+/// A post in a feed, before the post itself is fetched
+struct FeedEntry {
+    post_id: i64,
+    reposted_by: Option<serde_json::Value>,
+    feed_at: DateTime<Utc>,
+}
+
+/// Handles feed related things
+pub struct Feed;
+
+impl Feed {
+    /// The most recent posts (not replies), and posts reposted
+    /// by the user or anyone they follow
+    pub async fn newest(pool: &PgPool, user_id: i64, page: &PageRequest) -> Result<Page<FeedPost>, Error> {
+        let (feed_at, id) = page.cursor::<DateTime<Utc>>()?.unzip();
+        let entries = sqlx::query_as!(FeedEntry, r#"
+            WITH reposters AS (
+                SELECT $1::bigint AS id
+                UNION ALL
+                SELECT follows.followee_id FROM follows WHERE follows.follower_id = $1
+            )
+            SELECT
+                entries.post_id AS "post_id!", entries.feed_at AS "feed_at!",
+                CASE WHEN users.id IS NOT NULL THEN jsonb_build_object(
+                    'user_id', users.id,
+                    'handle', users.handle,
+                    'displayname', users.displayname
+                ) END AS "reposted_by?"
+            FROM (
+                (
+                    SELECT posts.id AS post_id, NULL::bigint AS reposter_id, posts.created_at AS feed_at
+                    FROM posts
+                    WHERE posts.deleted_at IS NULL
+                        AND (posts.replies_to IS NULL OR posts.citation IS NOT NULL)
+                        AND NOT EXISTS (
+                            SELECT 1 FROM reposts
+                            WHERE reposts.post_id = posts.id AND reposts.user_id IN (SELECT id FROM reposters)
+                        )
+                        AND ($2::timestamptz IS NULL OR (posts.created_at, posts.id) < ($2, $3))
+                    ORDER BY posts.created_at DESC, posts.id DESC
+                    LIMIT $4
+                )
+                UNION ALL
+                (
+                    SELECT reposts.post_id, reposts.user_id, reposts.created_at
+                    FROM reposts
+                    WHERE reposts.user_id IN (SELECT id FROM reposters)
+                        AND reposts.post_id IN (SELECT id FROM posts WHERE deleted_at IS NULL)
+                        AND NOT EXISTS (
+                            SELECT 1 FROM reposts newer
+                            WHERE newer.post_id = reposts.post_id
+                                AND newer.user_id IN (SELECT id FROM reposters)
+                                AND (newer.created_at, newer.user_id) > (reposts.created_at, reposts.user_id)
+                        )
+                        AND ($2::timestamptz IS NULL OR (reposts.created_at, reposts.post_id) < ($2, $3))
+                    ORDER BY reposts.created_at DESC, reposts.post_id DESC
+                    LIMIT $4
+                )
+            ) entries
+                LEFT JOIN users ON users.id = entries.reposter_id
+            ORDER BY entries.feed_at DESC, entries.post_id DESC
+            LIMIT $4"#,
+            user_id, feed_at, id, page.fetch_limit()
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        Self::with_posts(pool, user_id, Page::new(entries, page, |e| (e.feed_at, e.post_id))).await
+    }
+
+    /// Posts from people the user follows, posts they have
+    /// liked and posts they have reposted
+    pub async fn for_you(pool: &PgPool, user_id: i64, page: &PageRequest) -> Result<Page<FeedPost>, Error> {
+        let (feed_at, id) = page.cursor::<DateTime<Utc>>()?.unzip();
+        let entries = sqlx::query_as!(FeedEntry, r#"
+            WITH reposters AS (
+                SELECT follows.followee_id AS id FROM follows WHERE follows.follower_id = $1
+            )
+            SELECT
+                entries.post_id AS "post_id!", entries.feed_at AS "feed_at!",
+                CASE WHEN users.id IS NOT NULL THEN jsonb_build_object(
+                    'user_id', users.id,
+                    'handle', users.handle,
+                    'displayname', users.displayname
+                ) END AS "reposted_by?"
+            FROM (
+                (
+                    SELECT posts.id AS post_id, NULL::bigint AS reposter_id, posts.created_at AS feed_at
+                    FROM posts
+                    WHERE posts.deleted_at IS NULL
+                        AND (posts.poster_id IN (SELECT id FROM reposters)
+                        OR posts.id IN (
+                            SELECT post_id FROM post_likes
+                                WHERE user_id IN (SELECT id FROM reposters)
+                        )
+
+                        AND (posts.replies_to IS NULL
+                            OR posts.citation IS NOT NULL))
+                        AND NOT EXISTS (
+                            SELECT 1 FROM reposts
+                            WHERE reposts.post_id = posts.id AND reposts.user_id IN (SELECT id FROM reposters)
+                        )
+                        AND ($2::timestamptz IS NULL OR (posts.created_at, posts.id) < ($2, $3))
+                    ORDER BY posts.created_at DESC, posts.id DESC
+                    LIMIT $4
+                )
+                UNION ALL
+                (
+                    SELECT reposts.post_id, reposts.user_id, reposts.created_at
+                    FROM reposts
+                    WHERE reposts.user_id IN (SELECT id FROM reposters)
+                        AND reposts.post_id IN (SELECT id FROM posts WHERE deleted_at IS NULL)
+                        AND NOT EXISTS (
+                            SELECT 1 FROM reposts newer
+                            WHERE newer.post_id = reposts.post_id
+                                AND newer.user_id IN (SELECT id FROM reposters)
+                                AND (newer.created_at, newer.user_id) > (reposts.created_at, reposts.user_id)
+                        )
+                        AND ($2::timestamptz IS NULL OR (reposts.created_at, reposts.post_id) < ($2, $3))
+                    ORDER BY reposts.created_at DESC, reposts.post_id DESC
+                    LIMIT $4
+                )
+            ) entries
+                LEFT JOIN users ON users.id = entries.reposter_id
+            ORDER BY entries.feed_at DESC, entries.post_id DESC
+            LIMIT $4"#,
+            user_id, feed_at, id, page.fetch_limit()
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        Self::with_posts(pool, user_id, Page::new(entries, page, |e| (e.feed_at, e.post_id))).await
+    }
+
+    /// Everything `poster_id` has posted or reposted
+    pub async fn user_posts(pool: &PgPool, user_id: i64, poster_id: i64, page: &PageRequest) -> Result<Page<FeedPost>, Error> {
+        let (feed_at, id) = page.cursor::<DateTime<Utc>>()?.unzip();
+        let entries = sqlx::query_as!(FeedEntry, r#"
+            SELECT
+                entries.post_id AS "post_id!", entries.feed_at AS "feed_at!",
+                CASE WHEN users.id IS NOT NULL THEN jsonb_build_object(
+                    'user_id', users.id,
+                    'handle', users.handle,
+                    'displayname', users.displayname
+                ) END AS "reposted_by?"
+            FROM (
+                (
+                    SELECT posts.id AS post_id, NULL::bigint AS reposter_id, posts.created_at AS feed_at
+                    FROM posts
+                    WHERE posts.poster_id = $1 AND posts.deleted_at IS NULL
+                        AND NOT EXISTS (
+                            SELECT 1 FROM reposts
+                            WHERE reposts.post_id = posts.id AND reposts.user_id = $1
+                        )
+                        AND ($2::timestamptz IS NULL OR (posts.created_at, posts.id) < ($2, $3))
+                    ORDER BY posts.created_at DESC, posts.id DESC
+                    LIMIT $4
+                )
+                UNION ALL
+                (
+                    SELECT reposts.post_id, reposts.user_id, reposts.created_at
+                    FROM reposts
+                    WHERE reposts.user_id = $1
+                        AND reposts.post_id IN (SELECT id FROM posts WHERE deleted_at IS NULL)
+                        AND ($2::timestamptz IS NULL OR (reposts.created_at, reposts.post_id) < ($2, $3))
+                    ORDER BY reposts.created_at DESC, reposts.post_id DESC
+                    LIMIT $4
+                )
+            ) entries
+                LEFT JOIN users ON users.id = entries.reposter_id
+            ORDER BY entries.feed_at DESC, entries.post_id DESC
+            LIMIT $4"#,
+            poster_id, feed_at, id, page.fetch_limit()
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        Self::with_posts(pool, user_id, Page::new(entries, page, |e| (e.feed_at, e.post_id))).await
+    }
+
+    /// Fetches the posts of a page of entries, keeping their order
+    async fn with_posts(pool: &PgPool, user_id: i64, entries: Page<FeedEntry>) -> Result<Page<FeedPost>, Error> {
+        let ids = entries.items.iter().map(|e| e.post_id).collect::<Vec<i64>>();
+        let mut posts = sqlx::query_as!(PostWithUser, r#"
+            SELECT * FROM get_posts_default($1) WHERE id = ANY($2)"#,
+            user_id, &ids
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        let items = entries.items.into_iter()
+            .filter_map(|entry| {
+                let index = posts.iter().position(|e| e.id == Some(entry.post_id))?;
+                Some(FeedPost { post: posts.swap_remove(index), reposted_by: entry.reposted_by, feed_at: entry.feed_at })
+            })
+            .collect();
+
+        Ok(Page { items, next_cursor: entries.next_cursor })
+    }
+}
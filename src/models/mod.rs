@@ -3,3 +3,15 @@ pub mod opinion;
 pub mod post;
 pub mod user;
 pub mod pfp;
+pub mod session;
+pub mod user_token;
+pub mod two_factor;
+pub mod api_token;
+pub mod account;
+pub mod page;
+pub mod search;
+pub mod notification;
+pub mod conversation;
+pub mod feed;
+pub mod thread;
+pub mod edge;
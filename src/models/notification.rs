@@ -0,0 +1,298 @@
+//! Notifications, telling users when someone liked,
+//! replied to, quoted or voted on their posts, followed
+//! them or mentioned them. They're listed in groups, like
+//! "Alice and 4 others liked your post".
+
+/* Imports */
+use std::collections::BTreeMap;
+use chrono::serde::ts_milliseconds;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, PgPool, Postgres};
+use crate::{error::Error, utils::hub::Event};
+use super::page::{Page, PageRequest};
+
+/* Constants */
+/// How many of the most recent actors a group includes
+const GROUP_ACTORS: i32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    Like,
+    Reply,
+    Follow,
+    Mention,
+    Quote,
+    OpinionVote,
+}
+
+/// Notifications of the same kind about the same post which
+/// are all read or all unread, e.g everyone who recently liked
+/// a post. Replies, quotes and mentions each have their own
+/// source post, so they're never grouped.
+#[derive(Serialize)]
+pub struct NotificationGroup {
+    /// Id of the most recent notification in the group
+    pub id: i64,
+    pub kind: String,
+    pub post_id: Option<i64>,
+    pub source_post_id: Option<i64>,
+    pub unread: bool,
+
+    /// The most recent actors first, `[{ user_id, handle, displayname }]`
+    pub actors: serde_json::Value,
+
+    /// How many actors there are, e.g 5 in "Alice and 4 others"
+    pub total: i64,
+
+    #[serde(with = "ts_milliseconds")]
+    pub latest_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Handles notification related things
+pub struct Notification;
+
+impl NotificationKind {
+    pub const ALL: [Self; 6] = [
+        Self::Like, Self::Reply, Self::Follow,
+        Self::Mention, Self::Quote, Self::OpinionVote
+    ];
+
+    /// What it's stored as in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Like => "like",
+            Self::Reply => "reply",
+            Self::Follow => "follow",
+            Self::Mention => "mention",
+            Self::Quote => "quote",
+            Self::OpinionVote => "opinion_vote",
+        }
+    }
+}
+
+impl Notification {
+    /// Notifies whoever posted `post_id`, unless that's
+    /// the actor or they've muted `kind`. Like every `notify_`
+    /// function it returns the events which tell the recipients'
+    /// open streams, to be published once the transaction the
+    /// notification was inserted in has been committed.
+    pub async fn notify_poster<'a, E: Executor<'a, Database = Postgres>>(
+        executor: E, kind: NotificationKind, actor_id: i64,
+        post_id: i64, source_post_id: Option<i64>
+    ) -> Result<Vec<Event>, Error> {
+        sqlx::query!(r#"
+            INSERT INTO notifications (user_id, actor_id, kind, post_id, source_post_id)
+            SELECT posts.poster_id, $1::bigint, $2::text, posts.id, $4::bigint
+            FROM posts
+            WHERE posts.id = $3
+                AND posts.poster_id <> $1
+                AND NOT EXISTS (
+                    SELECT 1 FROM muted_notification_kinds muted
+                    WHERE muted.user_id = posts.poster_id AND muted.kind = $2
+                )
+            ON CONFLICT DO NOTHING
+            RETURNING id, user_id"#,
+            actor_id, kind.as_str(), post_id, source_post_id
+        )
+        .fetch_all(executor)
+        .await
+        .map(|rows| Self::events(kind, actor_id, rows.into_iter().map(|e| (e.id, e.user_id))))
+        .map_err(Error::new)
+    }
+
+    /// Notifies `user_id` about something which isn't about
+    /// a post (a follow), unless they've muted `kind`
+    pub async fn notify_user<'a, E: Executor<'a, Database = Postgres>>(
+        executor: E, kind: NotificationKind, user_id: i64, actor_id: i64
+    ) -> Result<Vec<Event>, Error> {
+        sqlx::query!(r#"
+            INSERT INTO notifications (user_id, actor_id, kind)
+            SELECT $1::bigint, $2::bigint, $3::text
+            WHERE $1 <> $2
+                AND NOT EXISTS (
+                    SELECT 1 FROM muted_notification_kinds muted
+                    WHERE muted.user_id = $1 AND muted.kind = $3
+                )
+            ON CONFLICT DO NOTHING
+            RETURNING id, user_id"#,
+            user_id, actor_id, kind.as_str()
+        )
+        .fetch_all(executor)
+        .await
+        .map(|rows| Self::events(kind, actor_id, rows.into_iter().map(|e| (e.id, e.user_id))))
+        .map_err(Error::new)
+    }
+
+    /// Notifies everyone mentioned in `post_id`, except those
+    /// who already were notified about it (e.g as the one
+    /// being replied to). Mentions have to be inserted first.
+    pub async fn notify_mentioned<'a, E: Executor<'a, Database = Postgres>>(
+        executor: E, actor_id: i64, post_id: i64
+    ) -> Result<Vec<Event>, Error> {
+        sqlx::query!(r#"
+            INSERT INTO notifications (user_id, actor_id, kind, source_post_id)
+            SELECT DISTINCT post_mentions.user_id, $1::bigint, $3::text, $2::bigint
+            FROM post_mentions
+            WHERE post_mentions.post_id = $2
+                AND post_mentions.user_id <> $1
+                AND NOT EXISTS (
+                    SELECT 1 FROM notifications
+                    WHERE notifications.source_post_id = $2
+                        AND notifications.user_id = post_mentions.user_id
+                )
+                AND NOT EXISTS (
+                    SELECT 1 FROM muted_notification_kinds muted
+                    WHERE muted.user_id = post_mentions.user_id AND muted.kind = $3
+                )
+            ON CONFLICT DO NOTHING
+            RETURNING id, user_id"#,
+            actor_id, post_id, NotificationKind::Mention.as_str()
+        )
+        .fetch_all(executor)
+        .await
+        .map(|rows| Self::events(NotificationKind::Mention, actor_id, rows.into_iter().map(|e| (e.id, e.user_id))))
+        .map_err(Error::new)
+    }
+
+    /// The events for the recipients' open streams,
+    /// `notifications` being `(notification id, recipient id)`
+    fn events(kind: NotificationKind, actor_id: i64, notifications: impl Iterator<Item = (i64, i64)>) -> Vec<Event> {
+        notifications
+            .map(|(notification_id, user_id)| Event::Notification { user_id, notification_id, kind, actor_id })
+            .collect()
+    }
+
+    /// Takes back a notification which hasn't been read yet, e.g
+    /// when unliking a post. `user_id` is who was notified, which
+    /// only has to be given if `post_id` is `None` (follows).
+    pub async fn retract<'a, E: Executor<'a, Database = Postgres>>(
+        executor: E, kind: NotificationKind, actor_id: i64,
+        user_id: Option<i64>, post_id: Option<i64>
+    ) -> Result<(), Error> {
+        sqlx::query!(r#"
+            DELETE FROM notifications
+            WHERE notifications.actor_id = $1
+                AND notifications.kind = $2
+                AND ($3::bigint IS NULL OR notifications.user_id = $3)
+                AND notifications.post_id IS NOT DISTINCT FROM $4
+                AND notifications.read_at IS NULL"#,
+            actor_id, kind.as_str(), user_id, post_id
+        )
+        .execute(executor)
+        .await
+        .map(|_| ())
+        .map_err(Error::new)
+    }
+
+    /// A user's notifications, grouped and newest first
+    pub async fn list(pool: &PgPool, user_id: i64, page: &PageRequest) -> Result<Page<NotificationGroup>, Error> {
+        let (latest_at, id) = page.cursor::<chrono::DateTime<chrono::Utc>>()?.unzip();
+        let groups = sqlx::query_as!(NotificationGroup, r#"
+            WITH groups AS (
+                SELECT
+                    notifications.kind,
+                    notifications.post_id,
+                    notifications.source_post_id,
+                    notifications.read_at IS NULL AS unread,
+                    MAX(notifications.id) AS id,
+                    MAX(notifications.created_at) AS latest_at,
+                    COUNT(*) AS total,
+                    (array_agg(notifications.actor_id
+                        ORDER BY notifications.created_at DESC, notifications.id DESC
+                    ))[1:$2] AS actor_ids
+                FROM notifications
+                WHERE notifications.user_id = $1
+                GROUP BY
+                    notifications.kind,
+                    notifications.post_id,
+                    notifications.source_post_id,
+                    notifications.read_at IS NULL
+            )
+            SELECT
+                groups.id AS "id!", groups.kind, groups.post_id, groups.source_post_id,
+                groups.unread AS "unread!", groups.latest_at AS "latest_at!", groups.total AS "total!",
+                COALESCE((
+                    SELECT jsonb_agg(jsonb_build_object(
+                        'user_id', users.id,
+                        'handle', users.handle,
+                        'displayname', users.displayname
+                    ) ORDER BY actors.ordinality)
+                    FROM unnest(groups.actor_ids) WITH ORDINALITY AS actors(id, ordinality)
+                        JOIN users ON users.id = actors.id
+                ), '[]') AS "actors!"
+            FROM groups
+            WHERE $3::timestamptz IS NULL OR (groups.latest_at, groups.id) < ($3, $4)
+            ORDER BY groups.latest_at DESC, groups.id DESC
+            LIMIT $5"#,
+            user_id, GROUP_ACTORS, latest_at, id, page.fetch_limit()
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        Ok(Page::new(groups, page, |e| (e.latest_at, e.id)))
+    }
+
+    /// How many groups (as listed) have unread notifications
+    pub async fn unread_count(pool: &PgPool, user_id: i64) -> Result<i64, Error> {
+        sqlx::query_scalar!(r#"
+            SELECT COUNT(DISTINCT (kind, post_id, source_post_id)) AS "count!"
+            FROM notifications
+            WHERE notifications.user_id = $1 AND notifications.read_at IS NULL"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(Error::new)
+    }
+
+    /// Marks every notification as read, or only those up to
+    /// (and including) `up_to` so that ones arriving after the
+    /// user looked aren't
+    pub async fn mark_read(pool: &PgPool, user_id: i64, up_to: Option<i64>) -> Result<(), Error> {
+        sqlx::query!(r#"
+            UPDATE notifications SET read_at = now()
+            WHERE notifications.user_id = $1
+                AND notifications.read_at IS NULL
+                AND ($2::bigint IS NULL OR notifications.id <= $2)"#,
+            user_id, up_to
+        )
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(Error::new)
+    }
+
+    /// Which kinds of notifications a user gets
+    pub async fn preferences(pool: &PgPool, user_id: i64) -> Result<BTreeMap<NotificationKind, bool>, Error> {
+        let muted = sqlx::query_scalar!(
+            "SELECT kind FROM muted_notification_kinds WHERE user_id = $1",
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        Ok(NotificationKind::ALL.into_iter()
+            .map(|e| (e, !muted.iter().any(|m| m == e.as_str())))
+            .collect())
+    }
+
+    /// Mutes or unmutes a kind of notification. Muting doesn't
+    /// remove the ones already received.
+    pub async fn set_preference(pool: &PgPool, user_id: i64, kind: NotificationKind, enabled: bool) -> Result<(), Error> {
+        match enabled {
+            true => sqlx::query!(
+                "DELETE FROM muted_notification_kinds WHERE user_id = $1 AND kind = $2",
+                user_id, kind.as_str()
+            ).execute(pool).await,
+            false => sqlx::query!(
+                "INSERT INTO muted_notification_kinds (user_id, kind) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                user_id, kind.as_str()
+            ).execute(pool).await
+        }
+        .map(|_| ())
+        .map_err(Error::new)
+    }
+}
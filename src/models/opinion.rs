@@ -1,8 +1,10 @@
 /* Imports */
+use actix_web::http::StatusCode;
 use serde::Serialize;
 use sqlx::PgPool;
 use unicode_segmentation::UnicodeSegmentation;
-use crate::error::Error;
+use crate::{error::Error, utils::hub::hub};
+use super::notification::{Notification, NotificationKind};
 
 /* Constants */
 const OPINION_MAX_LEN: usize = 12;
@@ -31,67 +33,71 @@ impl Opinion {
         Some(new_string)
     }
 
-    /// Vote for an opinion (max 1 per post per user)
+    /// Vote for an opinion (max 1 per post per user). The
+    /// opinion has to be one of `post_id`'s, or it's a 404
     pub async fn set_vote(
         pool: &PgPool, post_id: i64, opinion_id: i64,
         user_id: i64, wants_vote: bool
     ) -> Result<(), Error> {
-        let has_voted = sqlx::query!(r"
-            SELECT * FROM post_opinion_votes WHERE
-            post_opinion_votes.user_id = $1 AND
-            post_opinion_votes.opinion_id = $2",
-            user_id, opinion_id
-        )
-            .fetch_optional(pool)
-            .await
-            .map_err(Error::new)?
-            .is_some();
-    
-        // If we should increment the vote count or decrease it
-        let increment: bool;
-    
-        // If we want to vote but we've already done it or opposite
-        // (only for making code clear it's not neccesary)
-        if wants_vote && has_voted || !wants_vote && !has_voted {
-            return Ok(());
-        }else if wants_vote && !has_voted {
-            sqlx::query!(r#"
-                INSERT INTO post_opinion_votes
-                (user_id, post_id, opinion_id) VALUES ($1, $2, $3)"#,
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+
+        // The post of the vote that was inserted / deleted, which
+        // isn't any if they already had voted or not
+        let voted_post_id = match wants_vote {
+            true => sqlx::query_scalar!(r#"
+                INSERT INTO post_opinion_votes (user_id, post_id, opinion_id)
+                SELECT $1, post_opinions.post_id, post_opinions.id
+                FROM post_opinions
+                WHERE post_opinions.id = $3 AND post_opinions.post_id = $2
+                ON CONFLICT DO NOTHING
+                RETURNING post_id AS "post_id!""#,
                 user_id, post_id, opinion_id
-            )
-                .execute(pool)
-                .await
-                .map_err(Error::new)?;
-    
-            increment = true;
-        }else if !wants_vote && has_voted {
-            sqlx::query!(r#"
+            ).fetch_optional(&mut *tx).await,
+            false => sqlx::query_scalar!(r#"
                 DELETE FROM post_opinion_votes
                     WHERE post_opinion_votes.user_id = $1
-                    AND post_opinion_votes.opinion_id = $2"#,
-                user_id, opinion_id
-            )
-                .execute(pool)
-                .await
-                .map_err(Error::new)?;
-    
-            increment = false;
-        }else {
-            unreachable!()
+                    AND post_opinion_votes.post_id = $2
+                    AND post_opinion_votes.opinion_id = $3
+                RETURNING post_id AS "post_id!""#,
+                user_id, post_id, opinion_id
+            ).fetch_optional(&mut *tx).await
         }
-    
-        sqlx::query(&format!(r#"
+        .map_err(Error::new)?;
+
+        let Some(voted_post_id) = voted_post_id else {
+            let exists = sqlx::query_scalar!(
+                r#"SELECT EXISTS(SELECT 1 FROM post_opinions WHERE id = $1 AND post_id = $2) AS "exists!""#,
+                opinion_id, post_id
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(Error::new)?;
+
+            return match exists {
+                true => Ok(()),
+                false => Err(Error::new_with_code("Opinion not found", StatusCode::NOT_FOUND))
+            };
+        };
+
+        sqlx::query!(r#"
             UPDATE post_opinions
-                SET votes = votes {}
-                WHERE post_opinions.id = {}"#,
-            if increment { "+ 1" } else { "- 1" },
-            opinion_id
-        ))
-        .execute(pool)
+                SET votes = votes + $2
+                WHERE post_opinions.id = $1"#,
+            opinion_id, if wants_vote { 1 } else { -1 }
+        )
+        .execute(&mut *tx)
         .await
-        .map(|_| ())
-        .map_err(Error::new)
+        .map_err(Error::new)?;
+
+        let notifications = match wants_vote {
+            true => Notification::notify_poster(&mut *tx, NotificationKind::OpinionVote, user_id, voted_post_id, None).await?,
+            false => Notification::retract(&mut *tx, NotificationKind::OpinionVote, user_id, None, Some(voted_post_id)).await
+                .map(|_| Vec::new())?
+        };
+        tx.commit().await.map_err(Error::new)?;
+
+        hub().publish_all(notifications);
+        Ok(())
     }
 
     /// Get 5 highest voted opinions for a post
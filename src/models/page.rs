@@ -0,0 +1,77 @@
+//! Cursor based pagination for endpoints which list
+//! things. Lists are ordered by a sort key (e.g
+//! `created_at` or `total_likes`) and the id as a tie
+//! breaker, and the cursor is the `(key, id)` of the
+//! last item in a page - opaque to clients.
+
+/* Imports */
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::error::Error;
+
+/* Constants */
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+/// Query parameters of paginated endpoints, e.g
+/// `?limit=20&cursor=<next_cursor of previous page>`
+#[derive(Deserialize)]
+pub struct PageRequest {
+    limit: Option<i64>,
+    cursor: Option<String>,
+}
+
+/// What paginated endpoints respond with. `next_cursor`
+/// is `None` on the last page.
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cursor<K> { key: K, id: i64 }
+
+impl PageRequest {
+    /// How many items to return, at most `MAX_LIMIT`
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    /// What to pass as `LIMIT` in the query. One more than
+    /// `limit` so that `Page::new` can tell if there's more.
+    pub fn fetch_limit(&self) -> i64 {
+        self.limit() + 1
+    }
+
+    /// The `(key, id)` to continue after, `None` on the first page
+    pub fn cursor<K: DeserializeOwned>(&self) -> Result<Option<(K, i64)>, Error> {
+        let Some(cursor) = &self.cursor else {
+            return Ok(None);
+        };
+
+        URL_SAFE_NO_PAD.decode(cursor).ok()
+            .and_then(|e| serde_json::from_slice::<Cursor<K>>(&e).ok())
+            .map(|e| Some((e.key, e.id)))
+            .ok_or(Error::new("Invalid cursor"))
+    }
+}
+
+impl<T> Page<T> {
+    /// `items` should have been fetched with `fetch_limit`,
+    /// and `key` returns the `(key, id)` an item is sorted by
+    pub fn new<K: Serialize>(mut items: Vec<T>, request: &PageRequest, key: impl Fn(&T) -> (K, i64)) -> Self {
+        let limit = request.limit() as usize;
+        if items.len() <= limit {
+            return Self { items, next_cursor: None };
+        }
+
+        items.truncate(limit);
+        let next_cursor = items.last()
+            .map(key)
+            .and_then(|(key, id)| serde_json::to_vec(&Cursor { key, id }).ok())
+            .map(|e| URL_SAFE_NO_PAD.encode(e));
+
+        Self { items, next_cursor }
+    }
+}
@@ -4,7 +4,8 @@
 use actix_files::NamedFile;
 use actix_web::{http::header::ContentType, web, HttpRequest, HttpResponse, Responder};
 use image::{imageops::resize, EncodableLayout, ImageFormat};
-use crate::{error::Error, handlers::user::ProfileImageUpload};
+use std::path::PathBuf;
+use crate::{config::config, error::Error, handlers::user::ProfileImageUpload};
 
 /// Handles profile image related things
 pub struct ProfileImageHandler;
@@ -14,12 +15,11 @@ impl ProfileImageHandler {
     /// the found pfp or the default-user.jpg file.
     pub async fn get_image(req: HttpRequest, user_id: i64) -> HttpResponse {
         const DEFAULT_USER: &[u8] = include_bytes!("../../assets/images/default-user.jpg");
-        let path = String::from("/usr/src/app/assets/images/profile/") + &user_id.to_string() + ".jpg";
-        match tokio::fs::read(&path).await {
-            Ok(e) => HttpResponse::Ok()
+        match Self::read_image(user_id).await {
+            Some(e) => HttpResponse::Ok()
                 .content_type(ContentType::jpeg())
                 .body(e),
-            Err(_) => HttpResponse::Ok()
+            None => HttpResponse::Ok()
                 .content_type(ContentType::jpeg())
                 .body(DEFAULT_USER)
         }
@@ -37,20 +37,37 @@ impl ProfileImageHandler {
         dbg!("Setting image");
 
         let image = resize(&img, 200, 200, image::imageops::FilterType::Nearest);
-        let path = format!("/usr/src/app/assets/images/profile/{}.jpg", user_id);
         dbg!("Setting image");
         
-        image.save_with_format(path, ImageFormat::Jpeg)
+        image.save_with_format(Self::path(user_id), ImageFormat::Jpeg)
             .map_err(Error::new)
             .map(|_| HttpResponse::Ok().finish())
     }
 
     /// Remove profile image of user requesting
     pub async fn remove_image(user_id: i64) -> Result<HttpResponse, Error> {
-        let path = format!("/usr/src/app/assets/images/profile/{}.jpg", user_id);
-        tokio::fs::remove_file(path)
+        tokio::fs::remove_file(Self::path(user_id))
             .await
             .map_err(Error::new)
             .map(|_| HttpResponse::Ok().finish())
     }
+
+    /// The uploaded profile image of a user, `None` if they
+    /// haven't set one
+    pub async fn read_image(user_id: i64) -> Option<Vec<u8>> {
+        tokio::fs::read(Self::path(user_id)).await.ok()
+    }
+
+    /// Removes the profile image of a user if they have one,
+    /// used when deleting their account
+    pub async fn purge_image(user_id: i64) -> Result<(), Error> {
+        match tokio::fs::remove_file(Self::path(user_id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::new(e)),
+            _ => Ok(())
+        }
+    }
+
+    fn path(user_id: i64) -> PathBuf {
+        config().profile_image_dir.join(format!("{}.jpg", user_id))
+    }
 }
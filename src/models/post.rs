@@ -1,13 +1,31 @@
 /* Imports */
+use std::time::Duration;
 use regex::Regex;
 use serde::Serialize;
-use sqlx::{prelude::FromRow, types::chrono::NaiveDateTime, PgPool};
-use crate::error::Error;
-use super::{post_citation::PostCitation, user::{User, UserInfo}};
-use chrono::serde::ts_milliseconds_option;
+use actix_web::http::StatusCode;
+use sqlx::{prelude::FromRow, types::chrono::NaiveDateTime, PgConnection, PgPool};
+use unicode_segmentation::UnicodeSegmentation;
+use crate::{config::config, error::Error, utils::{hub::{hub, Event}, logger::log}};
+use super::{notification::{Notification, NotificationKind}, post_citation::PostCitation, user::{User, UserInfo}};
+use chrono::serde::{ts_milliseconds, ts_milliseconds_option};
 
-/* Post boolean for keeping track of liked, bookmarked or not */
-pub enum PostBoolean { Like, Bookmark }
+/* Constants */
+/// How many days deleted posts are kept before their content is purged
+const DELETED_POST_RETENTION_DAYS: i32 = 30;
+/// How often we look for deleted posts to purge
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How many posts can be published as one thread
+const THREAD_MAX_POSTS: usize = 25;
+
+/// An @mention in the content of a post. `start` and `end`
+/// are grapheme offsets (like in `CitationRequest`), `start`
+/// being the @ and `end` exclusive.
+#[derive(Debug, PartialEq)]
+pub struct Mention {
+    pub handle: String,
+    pub start: i32,
+    pub end: i32,
+}
 
 #[derive(FromRow, Debug, Default, sqlx::Type)]
 pub struct Post {
@@ -21,12 +39,20 @@ pub struct Post {
     // These will default to 0
     pub total_likes: i64,
     pub total_replies: i64,
+    pub total_reposts: i64,
     pub poster_id: i64,
 
     pub replies_to: Option<i64>,
     pub citation: Option<serde_json::Value>,
 
-    pub created_at: chrono::DateTime<chrono::Utc>
+    pub created_at: chrono::DateTime<chrono::Utc>,
+
+    /// When the content last was changed, see `Post::edit`
+    pub edited_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub revision_count: i32,
+
+    /// Deleted posts are kept as tombstones, see `Post::delete`
+    pub deleted_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 #[derive(Serialize, FromRow, sqlx::Type)]
 pub struct PostWithUser {
@@ -35,6 +61,7 @@ pub struct PostWithUser {
     pub content: Option<String>,
     pub total_likes: Option<i64>,
     pub total_replies: Option<i64>,
+    pub total_reposts: Option<i64>,
     pub poster_id: Option<i64>,
     pub replies_to: Option<Option<i64>>,
     pub citation: Option<Option<serde_json::Value>>,
@@ -42,6 +69,18 @@ pub struct PostWithUser {
     #[serde(with = "ts_milliseconds_option")]
     pub created_at: Option<chrono::DateTime<chrono::Utc>>,
 
+    /// `None` if the post never has been edited
+    #[serde(with = "ts_milliseconds_option")]
+    pub edited_at: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// How many times the post has been edited, which is
+    /// also the revision the content is
+    pub revision_count: Option<i32>,
+
+    /// If the post has been deleted, in which case only
+    /// its counters and place in the thread are kept
+    pub deleted: Option<bool>,
+
     /* User info */
     pub user_id: Option<i64>,
     pub displayname: Option<String>,
@@ -50,9 +89,39 @@ pub struct PostWithUser {
     /* Post metadata related to user */
     pub liked: Option<bool>,
     pub bookmarked: Option<bool>,
+    pub reposted: Option<bool>,
     pub is_followed: Option<bool>,
 
     pub top_opinions: Option<serde_json::Value>,
+
+    /// Mentioned users, `[{ user_id, handle, start, end }]`
+    /// where `start` and `end` are grapheme offsets into
+    /// `content`, like in `Mention`
+    pub mentions: Option<serde_json::Value>,
+}
+
+/// A version of the content of a post
+#[derive(Serialize)]
+pub struct PostRevision {
+    /// 0 is the original
+    pub revision: i32,
+    pub content: String,
+
+    /// When this version was published
+    #[serde(with = "ts_milliseconds")]
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl PostWithUser {
+    /// What lists ordered by newest first are paginated by
+    pub fn newest_key(&self) -> (chrono::DateTime<chrono::Utc>, i64) {
+        (self.created_at.unwrap_or_default(), self.id.unwrap_or_default())
+    }
+
+    /// What lists ordered by most liked first are paginated by
+    pub fn likes_key(&self) -> (i64, i64) {
+        (self.total_likes.unwrap_or_default(), self.id.unwrap_or_default())
+    }
 }
 
 impl Post {
@@ -69,9 +138,63 @@ impl Post {
         }
     }
 
-    /// Inserts into db, also inserts hashtags.
+    /// Inserts into db, also inserts hashtags, mentions and
+    /// notifications.
     pub async fn insert_into(&self, pool: &PgPool) -> Result<(), Error> {
-        let (hashtags, _) = self.hashtags_and_mentions();
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        let counters = match self.replies_to {
+            Some(replies_to) => Some(Self::add_reply(&mut tx, replies_to).await?),
+            None => None
+        };
+
+        let post_id = self.insert_row(&mut tx).await?;
+        let notifications = self.notify(&mut tx, post_id).await?;
+        tx.commit().await.map_err(Error::new)?;
+
+        hub().publish(Event::Post { post_id, poster_id: self.poster_id, replies_to: self.replies_to });
+        hub().publish_all(counters.into_iter().chain(notifications));
+        Ok(())
+    }
+
+    /// Inserts `parts` as a thread, each one replying to the one
+    /// before it, and the first one to `replies_to`. Either all of
+    /// them are inserted (with their notifications) or none are.
+    /// Returns the ids of the parts.
+    pub async fn insert_thread(pool: &PgPool, replies_to: Option<i64>, mut parts: Vec<Post>) -> Result<Vec<i64>, Error> {
+        if parts.is_empty() || parts.len() > THREAD_MAX_POSTS {
+            return Err(Error::new(format!("A thread must have between 1 and {} posts", THREAD_MAX_POSTS)));
+        }
+
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        let mut ids = Vec::with_capacity(parts.len());
+        let mut counters = Vec::new();
+        let mut notifications = Vec::new();
+        let mut previous = replies_to;
+        for part in parts.iter_mut() {
+            if let Some(replies_to) = previous {
+                counters.push(Self::add_reply(&mut tx, replies_to).await?);
+            }
+
+            part.replies_to = previous;
+            let post_id = part.insert_row(&mut tx).await?;
+            notifications.extend(part.notify(&mut tx, post_id).await?);
+            ids.push(post_id);
+            previous = Some(post_id);
+        }
+        tx.commit().await.map_err(Error::new)?;
+
+        for (part, post_id) in parts.iter().zip(&ids) {
+            hub().publish(Event::Post { post_id: *post_id, poster_id: part.poster_id, replies_to: part.replies_to });
+        }
+        hub().publish_all(counters.into_iter().chain(notifications));
+
+        Ok(ids)
+    }
+
+    /// Inserts the post itself with its hashtags and
+    /// mentions, and returns its id
+    async fn insert_row(&self, conn: &mut PgConnection) -> Result<i64, Error> {
+        let (hashtags, mentions) = self.hashtags_and_mentions();
 
         // Insert post
         let post_id: i64 = sqlx::query_scalar!(r#"
@@ -79,10 +202,298 @@ impl Post {
             (content, poster_id, replies_to, citation) VALUES ($1, $2, $3, $4)
             returning id"#,
             self.content, self.poster_id, self.replies_to, self.citation
-        ).fetch_one(pool)
+        ).fetch_one(&mut *conn)
         .await
         .map_err(Error::new)?;
 
+        Self::insert_hashtags_and_mentions(conn, post_id, hashtags, mentions).await?;
+        Ok(post_id)
+    }
+
+    /// Increases the total_replies of `post_id`, which has to
+    /// exist and not be deleted. The row stays locked until the
+    /// transaction ends, so it can't be deleted meanwhile either.
+    /// Returns the new counters of the post.
+    async fn add_reply(conn: &mut PgConnection, post_id: i64) -> Result<Event, Error> {
+        sqlx::query!(r#"
+            UPDATE posts
+            SET total_replies = total_replies + 1
+            WHERE id = $1 AND deleted_at IS NULL
+            RETURNING total_likes, total_replies, total_reposts"#,
+            post_id
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(Error::new)?
+        .map(|e| Event::Counters {
+            post_id,
+            total_likes: e.total_likes,
+            total_replies: e.total_replies,
+            total_reposts: e.total_reposts
+        })
+        .ok_or(Error::new_with_code("Post not found", StatusCode::NOT_FOUND))
+    }
+
+    /// Notifies whoever was replied to, quoted or mentioned in
+    /// the newly inserted `post_id`. Returns the events to publish
+    /// once the post has been committed.
+    async fn notify(&self, conn: &mut PgConnection, post_id: i64) -> Result<Vec<Event>, Error> {
+        let mut events = Vec::new();
+        if let Some(replies_to) = self.replies_to {
+            events.extend(Notification::notify_poster(&mut *conn, NotificationKind::Reply, self.poster_id, replies_to, Some(post_id)).await?);
+        }
+
+        let quoted = self.citation.as_ref()
+            .and_then(|e| e.get("post_id"))
+            .and_then(|e| e.as_i64());
+        if let Some(quoted) = quoted {
+            events.extend(Notification::notify_poster(&mut *conn, NotificationKind::Quote, self.poster_id, quoted, Some(post_id)).await?);
+        }
+
+        events.extend(Notification::notify_mentioned(&mut *conn, self.poster_id, post_id).await?);
+        Ok(events)
+    }
+
+    /// Changes the content of a post, which only its poster can
+    /// do within `config().post_edit_window` minutes of posting.
+    /// The previous version is kept as a revision, and hashtags
+    /// and mentions are extracted again.
+    pub async fn edit(pool: &PgPool, editor_id: i64, post_id: i64, content: String) -> Result<(), Error> {
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        let post = sqlx::query_as!(Post, r#"
+            SELECT
+                id, content, total_likes, total_replies, total_reposts, poster_id AS "poster_id!",
+                replies_to, citation, created_at, edited_at, revision_count, deleted_at
+            FROM posts
+            WHERE id = $1 AND poster_id = $2 AND deleted_at IS NULL
+            FOR UPDATE"#,
+            post_id, editor_id
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(Error::new)?
+        .ok_or(Error::new_with_code("Post not found", StatusCode::NOT_FOUND))?;
+
+        let window = config().post_edit_window;
+        if post.created_at < chrono::Utc::now() - chrono::Duration::minutes(window as i64) {
+            return Err(Error::new_with_code(
+                format!("Posts can only be edited within {} minutes of posting", window),
+                StatusCode::FORBIDDEN
+            ));
+        }
+        if post.content == content {
+            return Ok(());
+        }
+
+        sqlx::query!(r#"
+            INSERT INTO post_revisions (post_id, revision, content, created_at)
+            VALUES ($1, $2, $3, $4)"#,
+            post_id, post.revision_count, post.content, post.edited_at.unwrap_or(post.created_at)
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        sqlx::query!(r#"
+            UPDATE posts SET
+                content = $2,
+                edited_at = now(),
+                revision_count = revision_count + 1
+            WHERE id = $1"#,
+            post_id, content
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        Self::remove_hashtags(&mut tx, post_id).await?;
+        sqlx::query!("DELETE FROM post_mentions WHERE post_id = $1", post_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::new)?;
+
+        let (hashtags, mentions) = Post { content, ..post }.hashtags_and_mentions();
+        Self::insert_hashtags_and_mentions(&mut tx, post_id, hashtags, mentions).await?;
+
+        // Only those who weren't mentioned before get notified
+        let notifications = Notification::notify_mentioned(&mut *tx, editor_id, post_id).await?;
+        tx.commit().await.map_err(Error::new)?;
+
+        hub().publish_all(notifications);
+        Ok(())
+    }
+
+    /// Deletes a post of `user_id`'s. It's kept as a tombstone so
+    /// that the replies underneath survive, but stops counting as
+    /// a reply and loses its hashtags, mentions and notifications,
+    /// and quotes of it stop showing what it said.
+    /// The content is purged for good after a while, see
+    /// `spawn_purge_task`.
+    pub async fn delete(pool: &PgPool, user_id: i64, post_id: i64) -> Result<(), Error> {
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        let replies_to = sqlx::query_scalar!(r#"
+            UPDATE posts SET deleted_at = now()
+            WHERE id = $1 AND poster_id = $2 AND deleted_at IS NULL
+            RETURNING replies_to"#,
+            post_id, user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(Error::new)?
+        .ok_or(Error::new_with_code("Post not found", StatusCode::NOT_FOUND))?;
+
+        Self::remove_hashtags(&mut tx, post_id).await?;
+        sqlx::query!("DELETE FROM post_mentions WHERE post_id = $1", post_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::new)?;
+        sqlx::query!("DELETE FROM notifications WHERE post_id = $1 OR source_post_id = $1", post_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::new)?;
+
+        // Quotes keep pointing to the post, but stop showing its content
+        sqlx::query!(r#"
+            UPDATE posts SET citation = jsonb_set(citation, '{content_slice}', '""')
+            WHERE citation IS NOT NULL AND (citation->>'post_id')::bigint = $1
+                AND citation->>'content_slice' <> ''"#,
+            post_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        let counters = match replies_to {
+            Some(replies_to) => Some(sqlx::query!(r#"
+                UPDATE posts
+                SET total_replies = total_replies - 1
+                WHERE id = $1
+                RETURNING id, total_likes, total_replies, total_reposts"#,
+                replies_to
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(Error::new)?),
+            None => None
+        };
+        tx.commit().await.map_err(Error::new)?;
+
+        if let Some(counters) = counters {
+            hub().publish(Event::Counters {
+                post_id: counters.id,
+                total_likes: counters.total_likes,
+                total_replies: counters.total_replies,
+                total_reposts: counters.total_reposts
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Purges deleted posts every `PURGE_INTERVAL`, for
+    /// as long as the server runs
+    pub fn spawn_purge_task(pool: PgPool) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PURGE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = Self::purge_deleted(&pool).await {
+                    log::red("post_purge", e);
+                }
+            }
+        });
+    }
+
+    /// Removes the content of posts which were deleted more than
+    /// `DELETED_POST_RETENTION_DAYS` ago. Tombstones without any
+    /// replies left are deleted altogether, the others are kept
+    /// (empty) for the thread to hang together.
+    pub(crate) async fn purge_deleted(pool: &PgPool) -> Result<(), Error> {
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+
+        // Quotes of deleted posts are emptied when the post is
+        // deleted, this catches those that were published at the
+        // same time (and before the tombstones below are gone)
+        sqlx::query!(r#"
+            UPDATE posts SET citation = jsonb_set(citation, '{content_slice}', '""')
+            WHERE citation IS NOT NULL
+                AND (citation->>'post_id')::bigint IN (SELECT id FROM posts WHERE deleted_at IS NOT NULL)
+                AND citation->>'content_slice' <> ''"#
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        let deleted = sqlx::query!(r#"
+            DELETE FROM posts
+            WHERE deleted_at < now() - make_interval(days => $1)
+                AND NOT EXISTS (SELECT 1 FROM posts replies WHERE replies.replies_to = posts.id)"#,
+            DELETED_POST_RETENTION_DAYS
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?
+        .rows_affected();
+
+        let scrubbed = sqlx::query_scalar!(r#"
+            UPDATE posts SET content = '', citation = NULL, edited_at = NULL, revision_count = 0
+            WHERE deleted_at < now() - make_interval(days => $1)
+                AND (content <> '' OR citation IS NOT NULL OR revision_count <> 0)
+            RETURNING id"#,
+            DELETED_POST_RETENTION_DAYS
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        sqlx::query!("DELETE FROM post_revisions WHERE post_id = ANY($1)", &scrubbed)
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::new)?;
+        sqlx::query!("DELETE FROM post_opinions WHERE post_id = ANY($1)", &scrubbed)
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::new)?;
+
+        tx.commit().await.map_err(Error::new)?;
+        if deleted > 0 || !scrubbed.is_empty() {
+            log::yellow("post_purge", format!("Purged {} deleted posts", deleted as usize + scrubbed.len()));
+        }
+
+        Ok(())
+    }
+
+    /// Every version of a post, the current one first
+    pub async fn history(pool: &PgPool, post_id: i64) -> Result<Vec<PostRevision>, Error> {
+        let revisions = sqlx::query_as!(PostRevision, r#"
+            SELECT
+                posts.revision_count AS "revision!", posts.content AS "content!",
+                COALESCE(posts.edited_at, posts.created_at) AS "created_at!"
+            FROM posts WHERE posts.id = $1 AND posts.deleted_at IS NULL
+            UNION ALL
+            SELECT post_revisions.revision, post_revisions.content, post_revisions.created_at
+            FROM post_revisions
+            WHERE post_revisions.post_id = $1
+                AND NOT EXISTS (SELECT 1 FROM posts WHERE posts.id = $1 AND posts.deleted_at IS NOT NULL)
+            ORDER BY 1 DESC"#,
+            post_id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        match revisions.is_empty() {
+            true => Err(Error::new_with_code("Post not found", StatusCode::NOT_FOUND)),
+            false => Ok(revisions)
+        }
+    }
+
+    /// Connects a post with its hashtags (creating the ones
+    /// that don't exist) and mentioned users
+    async fn insert_hashtags_and_mentions(
+        conn: &mut PgConnection, post_id: i64,
+        hashtags: Vec<String>, mentions: Vec<Mention>
+    ) -> Result<(), Error> {
         // Insert hashtags
         for tag in hashtags {
             // Try to insert the hashtag, or get its ID if it exists
@@ -93,7 +504,7 @@ impl Post {
                 RETURNING id"#,
                 tag.to_lowercase()
             )
-            .fetch_one(pool)
+            .fetch_one(&mut *conn)
             .await
             .map_err(Error::new)?;
 
@@ -106,98 +517,55 @@ impl Post {
                 post_id,
                 hashtag_id
             )
-            .execute(pool)
+            .execute(&mut *conn)
             .await
             .map_err(Error::new)?;
         }
 
-        // Increase the total_replies of the post we replied to
-        if let Some(replies_to) = self.replies_to {
-            sqlx::query!(r#"
-                UPDATE posts
-                SET total_replies = total_replies + 1
-                WHERE id = $1"#,
-                replies_to
-            )
-            .execute(pool)
-            .await
-            .map_err(Error::new)?;
-        }
+        // Mentions of handles that don't exist are skipped
+        sqlx::query!(r#"
+            INSERT INTO post_mentions (post_id, user_id, start_offset, end_offset)
+            SELECT $1, users.id, mentions.start_offset, mentions.end_offset
+            FROM unnest($2::text[], $3::int[], $4::int[]) AS mentions(handle, start_offset, end_offset)
+                JOIN users ON users.handle = mentions.handle"#,
+            post_id,
+            &mentions.iter().map(|e| e.handle.clone()).collect::<Vec<String>>(),
+            &mentions.iter().map(|e| e.start).collect::<Vec<i32>>(),
+            &mentions.iter().map(|e| e.end).collect::<Vec<i32>>()
+        )
+        .execute(&mut *conn)
+        .await
+        .map_err(Error::new)?;
 
         Ok(())
     }
 
-    /// The toggler_id is the person who likes / unlikes and the post_id is the
-    /// post that will recieve a like if not already existing, same for bookmarks
-    pub async fn set_boolean(b: PostBoolean, pool: &PgPool, to: bool, toggler_id: i64, post_id: i32) -> Result<(), Error> {
-        let table_name = match b {
-            PostBoolean::Bookmark => "post_bookmarks",
-            PostBoolean::Like => "post_likes",
-        };
-
-        let is_enabled = sqlx::query(&format!(
-            r"SELECT * FROM {table_name} WHERE
-                {table_name}.post_id = {post_id} AND
-                {table_name}.user_id = {toggler_id}",
-            ))
-            .fetch_optional(pool)
-            .await
-            .map_err(Error::new)?
-            .is_some();
-
-        // If we should increment the boolean on the post.total_likes (only for like)
-        let increment: bool;
-
-        // If we want to like but we've already done it or opposite
-        // (only for making code clear it's not neccesary)
-        if to && is_enabled || !to && !is_enabled {
-            return Ok(());
-        }else if to && !is_enabled {
-            sqlx::query(&format!(r"
-                INSERT INTO {table_name} (user_id, post_id) VALUES ({toggler_id}, {post_id})
-            "))
-                .execute(pool)
-                .await
-                .map_err(Error::new)?;
-
-            increment = true;
-        }else if !to && is_enabled {
-            sqlx::query(&format!(r"
-                DELETE FROM {table_name}
-                    WHERE {table_name}.user_id = {toggler_id}
-                    AND {table_name}.post_id = {post_id}"
-                )
+    /// Disconnects a post from its hashtags, deleting the
+    /// ones no other post uses
+    async fn remove_hashtags(conn: &mut PgConnection, post_id: i64) -> Result<(), Error> {
+        sqlx::query!(r#"
+            WITH removed AS (
+                DELETE FROM post_hashtags WHERE post_id = $1
+                RETURNING hashtag_id
             )
-                .execute(pool)
-                .await
-                .map_err(Error::new)?;
-
-            increment = false;
-        }else {
-            unreachable!()
-        }
+            DELETE FROM hashtags
+            WHERE id IN (SELECT hashtag_id FROM removed)
+                AND NOT EXISTS (
+                    SELECT 1 FROM post_hashtags
+                    WHERE post_hashtags.hashtag_id = hashtags.id AND post_hashtags.post_id <> $1
+                )"#,
+            post_id
+        )
+        .execute(&mut *conn)
+        .await
+        .map_err(Error::new)?;
 
-        /* Only liked should increment */
-        if matches!(b, PostBoolean::Like) {
-            sqlx::query(&format!(r"
-                UPDATE posts
-                    SET total_likes = total_likes {}
-                    WHERE id = {}",
-                if increment { "+ 1" } else { "- 1" },
-                post_id
-            ))
-            .execute(pool)
-            .await
-            .map(|_| ())
-            .map_err(Error::new)
-        }else {
-            Ok(())
-        }
+        Ok(())
     }
 
     /// Looks through a string and returns (hashtags, mentions)
-    pub fn hashtags_and_mentions(&self) -> (Vec<String>, Vec<String>) {
-        const HASHTAG_REGEX: &'static str = "^[a-zA-Z0-9]+$";
+    pub fn hashtags_and_mentions(&self) -> (Vec<String>, Vec<Mention>) {
+        const HASHTAG_REGEX: &str = "^[a-zA-Z0-9]+$";
         let mut hashtags = Vec::new();
         let mut mentions = Vec::new();
         let rgx = Regex::new(HASHTAG_REGEX).unwrap();
@@ -207,10 +575,23 @@ impl Post {
         let mut ism = false;
         let mut curr_char_buf = String::new();
 
-        let set_seeking = &mut |cbuf: &mut String, ish: &mut bool, ism: &mut bool| {
+        // Grapheme index of the # or @ we're seeking from
+        let mut start = 0;
+
+        let set_seeking = &mut |cbuf: &mut String, ish: &mut bool, ism: &mut bool, start: usize| {
+            // Handles can contain dots, but a mention at
+            // the end of a sentence shouldn't
+            if *ism {
+                while cbuf.ends_with('.') { cbuf.pop(); }
+            }
+
             if !cbuf.is_empty() {
                 if *ish { hashtags.push(cbuf.to_lowercase()) }
-                else if *ism { mentions.push(cbuf.to_lowercase()) };
+                else if *ism { mentions.push(Mention {
+                    handle: cbuf.to_lowercase(),
+                    start: start as i32,
+                    end: (start + 1 + cbuf.chars().count()) as i32
+                }) };
             }
 
             *ish = false;
@@ -218,11 +599,25 @@ impl Post {
             cbuf.clear();
         };
 
-        for char in self.content.chars() {
+        let mut prev_char = None::<char>;
+        for (index, grapheme) in self.content.graphemes(true).enumerate() {
+            // Graphemes made of several chars (emoji sequences, letters
+            // with combining marks) can't be part of a hashtag or handle
+            let char = match grapheme.parse::<char>() {
+                Ok(char) => char,
+                Err(_) if grapheme.trim().is_empty() => ' ',
+                Err(_) => char::REPLACEMENT_CHARACTER,
+            };
+
             if char == '#' && !ism && !ish {
                 ish = true;
-            }else if char == '@' && !ism && !ish {
+                start = index;
+            }
+            
+            // Not in the middle of a word, like an email address
+            else if char == '@' && !ism && !ish && !prev_char.is_some_and(|e| e.is_alphanumeric()) {
                 ism = true;
+                start = index;
             }
             
             // If the user begins a new hashtag or mention inside of a
@@ -230,26 +625,84 @@ impl Post {
             // @user1@user2 or #ht1#ht2 or @user1#ht1 or #ht1@user2
             // we need to also treat them as separate
             else if char == '#' && (ism || ish) {
-                set_seeking(&mut curr_char_buf, &mut ish, &mut ism);
+                set_seeking(&mut curr_char_buf, &mut ish, &mut ism, start);
                 ish = true;
                 ism = false;
+                start = index;
             }else if char == '@' && (ism || ish) {
-                set_seeking(&mut curr_char_buf, &mut ish, &mut ism);
+                set_seeking(&mut curr_char_buf, &mut ish, &mut ism, start);
                 ish = false;
                 ism = true;
+                start = index;
             }
             
             else if char.is_whitespace() || char.is_ascii_whitespace() {
-                set_seeking(&mut curr_char_buf, &mut ish, &mut ism);
-            }else if rgx.is_match(&char.to_string()) {
+                set_seeking(&mut curr_char_buf, &mut ish, &mut ism, start);
+            }else if rgx.is_match(&char.to_string()) || (ism && char == '.') {
                 curr_char_buf.push(char.to_ascii_lowercase());
             }else {
-                set_seeking(&mut curr_char_buf, &mut ish, &mut ism);
+                set_seeking(&mut curr_char_buf, &mut ish, &mut ism, start);
             }
+
+            prev_char = grapheme.chars().next();
         }
 
-        if ish || ism { set_seeking(&mut curr_char_buf, &mut ish, &mut ism) }
+        if ish || ism { set_seeking(&mut curr_char_buf, &mut ish, &mut ism, start) }
 
         (hashtags, mentions)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> (Vec<String>, Vec<Mention>) {
+        Post::new(1, content.to_string(), None, None).hashtags_and_mentions()
+    }
+
+    fn mention(handle: &str, start: i32, end: i32) -> Mention {
+        Mention { handle: handle.to_string(), start, end }
+    }
+
+    #[test]
+    fn finds_hashtags_and_mentions() {
+        let (hashtags, mentions) = parse("Hi @Artur and @bob.smith. #Rust #web");
+        assert_eq!(hashtags, ["rust", "web"]);
+        assert_eq!(mentions, [mention("artur", 3, 9), mention("bob.smith", 14, 24)]);
+    }
+
+    #[test]
+    fn splits_adjacent_tags() {
+        let (hashtags, mentions) = parse("@a@b#c#d@e");
+        assert_eq!(hashtags, ["c", "d"]);
+        assert_eq!(mentions, [mention("a", 0, 2), mention("b", 2, 4), mention("e", 8, 10)]);
+    }
+
+    #[test]
+    fn skips_email_addresses() {
+        let (_, mentions) = parse("mail me@example.com or @me");
+        assert_eq!(mentions, [mention("me", 23, 26)]);
+    }
+
+    #[test]
+    fn offsets_are_graphemes() {
+        // A family emoji is 7 chars, 1 grapheme
+        let (_, mentions) = parse("👨‍👩‍👧‍👦 @artur");
+        assert_eq!(mentions, [mention("artur", 2, 8)]);
+
+        // "é" written as e + combining acute accent
+        let (hashtags, mentions) = parse("cafe\u{301} #caf\u{e9} @bob");
+        assert_eq!(hashtags, ["caf"]);
+        assert_eq!(mentions, [mention("bob", 11, 15)]);
+
+        let (_, mentions) = parse("line\r\n@artur");
+        assert_eq!(mentions, [mention("artur", 5, 11)]);
+    }
+
+    #[test]
+    fn skips_mentions_after_accented_letters() {
+        let (_, mentions) = parse("cafe\u{301}@bob");
+        assert!(mentions.is_empty());
+    }
+}
@@ -1,23 +1,38 @@
-use actix_web::Responder;
+use actix_web::{http::StatusCode, Responder};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::prelude::FromRow;
+use sqlx::{prelude::FromRow, PgPool};
+use unicode_segmentation::UnicodeSegmentation;
+use crate::error::Error;
 
 
 /// How many characters we'll include from
 /// left to right of the referenced slice
-const REFERENCE_PADDING: i32 = 15;
+const REFERENCE_PADDING: usize = 15;
+
+/// What a client sends to cite part of a post. `start`
+/// and `end` are grapheme offsets into the content of
+/// the cited post, `end` being exclusive.
+#[derive(Deserialize, Debug)]
+pub struct CitationRequest {
+    pub post_id: i64,
+    pub start: i32,
+    pub end: i32,
+}
 
 /// Returns a reference to a section of a post.
-/// 
+///
 /// Imagine this post content:
 /// "Hello my name is Artur and I like cookies".
-/// Referencing index 27-40 will highlight the
-/// string "I like cookies" and show a more 
+/// Referencing index 27-41 will highlight the
+/// string "I like cookies" and show a more
 /// detailed "view" with 15 characters around
 /// it which will return:
 /// ..."e is Artur and **I like cookies**"
-#[derive(Serialize, sqlx::Type, Debug, Default, Deserialize)]
+///
+/// Always built from the cited post (see `new`), never
+/// taken from the client. Characters are graphemes.
+#[derive(Serialize, sqlx::Type, Debug, Default)]
 pub struct PostCitation {
     /// The window of (PADDING citation PADDING)
     content_slice: String,
@@ -33,38 +48,121 @@ pub struct PostCitation {
     ellipsis_left: bool,
     ellipsis_right: bool,
 
-    post_id: i32,
-    user_id: i32,
+    post_id: i64,
+    user_id: i64,
     displayname: String,
     handle: String,
+
+    /// Which version of the post was cited, since it
+    /// might be edited afterwards (see `Post::edit`)
+    revision: i32,
 }
 
 impl PostCitation {
-    // pub fn new(content: String, start_ref: i32, end_ref: i32) -> Self {
-    //     let start_slice_index = (start_ref - REFERENCE_PADDING).max(0) as i32;
-    //     let end_slice_index = (end_ref + REFERENCE_PADDING).min(content.len() as i32) as i32;
-    //     let beginning = REFERENCE_PADDING - (REFERENCE_PADDING - start_ref).max(0) as i32;
-    //     let end = beginning + end_ref - start_ref as i32;
-    //     let content_slice = &content[(start_slice_index as usize)..(end_slice_index as usize)];
-    //     let mut ellipsis_left = false;
-    //     let mut ellipsis_right = false;
-
-    //     if start_ref >= REFERENCE_PADDING + 1{
-    //         ellipsis_left = true;
-    //     }if end_ref + REFERENCE_PADDING < content.len() as i32 {
-    //         ellipsis_right = true;
-    //     }
-
-    //     Self {
-    //         content_slice: content_slice.to_string(),
-    //         beginning,
-    //         end,
-    //         ellipsis_right,
-    //         ellipsis_left
-    //     }
-    // }
+    /// Loads the cited post and builds the citation of
+    /// `start..end` in its content
+    pub async fn new(pool: &PgPool, request: CitationRequest) -> Result<Self, Error> {
+        let post = sqlx::query!(r#"
+            SELECT posts.content, posts.revision_count, users.id AS user_id, users.displayname, users.handle
+            FROM posts JOIN users ON users.id = posts.poster_id
+            WHERE posts.id = $1 AND posts.deleted_at IS NULL"#,
+            request.post_id
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(Error::new)?
+        .ok_or(Error::new_with_code("Cited post not found", StatusCode::NOT_FOUND))?;
+
+        Ok(Self {
+            post_id: request.post_id,
+            user_id: post.user_id,
+            displayname: post.displayname,
+            handle: post.handle,
+            revision: post.revision_count,
+            ..Self::slice(&post.content, request.start, request.end)?
+        })
+    }
+
+    /// The window and highlight of `start..end` (graphemes)
+    /// in `content`, without anything about the cited post
+    fn slice(content: &str, start: i32, end: i32) -> Result<Self, Error> {
+        let graphemes = content.graphemes(true).collect::<Vec<&str>>();
+        let (start, end) = match (usize::try_from(start), usize::try_from(end)) {
+            (Ok(start), Ok(end)) if start < end && end <= graphemes.len() => (start, end),
+            _ => return Err(Error::new("Invalid citation range"))
+        };
+
+        let slice_start = start.saturating_sub(REFERENCE_PADDING);
+        let slice_end = (end + REFERENCE_PADDING).min(graphemes.len());
+        let beginning = start - slice_start;
+
+        Ok(Self {
+            content_slice: graphemes[slice_start..slice_end].concat(),
+            beginning: beginning as i32,
+            end: (beginning + end - start) as i32,
+            ellipsis_left: slice_start > 0,
+            ellipsis_right: slice_end < graphemes.len(),
+            ..Default::default()
+        })
+    }
 
     pub fn to_json(&self) -> Option<Value> {
         serde_json::to_value(self).ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The highlighted part of a citation
+    fn highlight(citation: &PostCitation) -> String {
+        citation.content_slice.graphemes(true)
+            .skip(citation.beginning as usize)
+            .take((citation.end - citation.beginning) as usize)
+            .collect()
+    }
+
+    #[test]
+    fn pads_the_highlight() {
+        let citation = PostCitation::slice("Hello my name is Artur and I like cookies", 27, 41).unwrap();
+        assert_eq!(citation.content_slice, "e is Artur and I like cookies");
+        assert_eq!(highlight(&citation), "I like cookies");
+        assert!(citation.ellipsis_left);
+        assert!(!citation.ellipsis_right);
+
+        let citation = PostCitation::slice("Short post", 0, 5).unwrap();
+        assert_eq!((citation.content_slice.as_str(), citation.beginning, citation.end), ("Short post", 0, 5));
+        assert!(!citation.ellipsis_left && !citation.ellipsis_right);
+    }
+
+    #[test]
+    fn slices_emoji_whole() {
+        // A family emoji is 7 chars (25 bytes), 1 grapheme
+        let content = "👨‍👩‍👧‍👦 and 🇸🇪 flags";
+        let citation = PostCitation::slice(content, 0, 1).unwrap();
+        assert_eq!(highlight(&citation), "👨‍👩‍👧‍👦");
+
+        let citation = PostCitation::slice(content, 6, 7).unwrap();
+        assert_eq!(highlight(&citation), "🇸🇪");
+        assert_eq!(citation.content_slice, content);
+    }
+
+    #[test]
+    fn keeps_combining_marks() {
+        // "é" written as e + combining acute accent
+        let citation = PostCitation::slice("cafe\u{301} au lait", 0, 4).unwrap();
+        assert_eq!(highlight(&citation), "cafe\u{301}");
+        assert_eq!((citation.beginning, citation.end), (0, 4));
+    }
+
+    #[test]
+    fn refuses_out_of_range_bounds() {
+        let content = "👍 five";
+        for (start, end) in [(0, 7), (-1, 2), (3, 3), (4, 2), (7, 8), (0, i32::MAX)] {
+            assert!(PostCitation::slice(content, start, end).is_err(), "{start}..{end}");
+        }
+        assert!(PostCitation::slice(content, 0, 6).is_ok());
+        assert!(PostCitation::slice("", 0, 0).is_err());
+    }
+}
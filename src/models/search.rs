@@ -0,0 +1,333 @@
+//! Searching posts. Queries are written in a small
+//! language which is parsed here, and turned into a full
+//! text search over `posts.search_vector` plus filters:
+//!
+//! - `word` and `"a phrase"` have to be in the post
+//! - `-word` and `-"a phrase"` must not be
+//! - `from:handle` posted by someone (can be repeated)
+//! - `#tag` has the hashtag (can be repeated)
+//! - `since:2025-01-31` posted on or after a date (UTC)
+//! - `until:2025-02-01` posted before a date (UTC)
+//! - `min_likes:10` has at least that many likes
+//! - `is:reply` / `-is:reply` only replies / no replies
+
+/* Imports */
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use crate::error::Error;
+use super::{page::{Page, PageRequest}, post::PostWithUser};
+
+/* Constants */
+const MAX_QUERY_LENGTH: usize = 512;
+
+/// A parsed search query
+#[derive(Default, Debug)]
+pub struct SearchQuery {
+    /// Words and phrases which have to be in / must not
+    /// be in the post, in the syntax of postgres'
+    /// `websearch_to_tsquery`. Kept apart since `ts_rank`
+    /// ranks every post with an excluded word as 0.
+    include: String,
+    exclude: String,
+    from: Vec<String>,
+    hashtags: Vec<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    min_likes: Option<i64>,
+    is_reply: Option<bool>,
+}
+
+/// A post matching a search
+#[derive(Serialize)]
+pub struct PostSearchHit {
+    #[serde(flatten)]
+    pub post: PostWithUser,
+
+    /// The most relevant parts of the content, HTML escaped
+    /// with the matching words wrapped in `<mark>`
+    pub headline: String,
+    pub rank: f32,
+}
+
+impl SearchQuery {
+    pub fn parse(query: &str) -> Result<Self, Error> {
+        if query.len() > MAX_QUERY_LENGTH {
+            return Err(Error::new("Search query too long"));
+        }
+
+        let mut search = Self::default();
+        let mut chars = query.chars().peekable();
+        loop {
+            while chars.next_if(|e| e.is_whitespace()).is_some() {}
+            let negated = chars.next_if_eq(&'-').is_some();
+
+            match chars.peek() {
+                None => break,
+                Some('"') => {
+                    chars.next();
+                    let phrase = chars.by_ref().take_while(|e| *e != '"').collect::<String>();
+                    search.push_text(&phrase, true, negated);
+                },
+                Some(_) => {
+                    let word = std::iter::from_fn(|| chars.next_if(|e| !e.is_whitespace())).collect::<String>();
+                    search.push_word(&word, negated)?;
+                }
+            }
+        }
+
+        Ok(search)
+    }
+
+    /// If there's nothing to search for
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty() && self.from.is_empty() && self.hashtags.is_empty()
+            && self.since.is_none() && self.until.is_none()
+            && self.min_likes.is_none() && self.is_reply.is_none()
+    }
+
+    /// Matching posts, most relevant first. Posts are only
+    /// ranked by the words and phrases, so a query of just
+    /// filters lists the newest posts first.
+    pub async fn posts(&self, pool: &PgPool, user_id: i64, page: &PageRequest) -> Result<Page<PostSearchHit>, Error> {
+        let (rank, id) = page.cursor::<f32>()?.unzip();
+        // The queries are repeated rather than put in a CTE so that
+        // postgres can tell if they're empty when planning, otherwise
+        // it can't use the index on search_vector
+        let hits = sqlx::query!(r#"
+            SELECT
+                hits.id AS "id!", hits.rank AS "rank!",
+                ts_headline(
+                    'english',
+                    replace(replace(replace(hits.content, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'),
+                    websearch_to_tsquery('english', $1),
+                    'StartSel=<mark>, StopSel=</mark>, MinWords=15, MaxWords=35'
+                ) AS "headline!"
+            FROM (
+                SELECT
+                    posts.id, posts.content,
+                    ts_rank(posts.search_vector, websearch_to_tsquery('english', $1)) AS rank
+                FROM posts
+                WHERE posts.deleted_at IS NULL
+                    AND (numnode(websearch_to_tsquery('english', $1)) = 0
+                        OR posts.search_vector @@ websearch_to_tsquery('english', $1))
+                    AND (numnode(websearch_to_tsquery('english', $2)) = 0
+                        OR NOT posts.search_vector @@ websearch_to_tsquery('english', $2))
+                    AND (cardinality($3::text[]) = 0 OR posts.poster_id IN (
+                        SELECT users.id FROM users WHERE users.handle = ANY($3)
+                    ))
+                    AND (cardinality($4::text[]) = 0 OR NOT EXISTS (
+                        SELECT unnest($4)
+                        EXCEPT
+                        SELECT hashtags.tag FROM hashtags
+                            JOIN post_hashtags ON post_hashtags.hashtag_id = hashtags.id
+                        WHERE post_hashtags.post_id = posts.id
+                    ))
+                    AND ($5::timestamptz IS NULL OR posts.created_at >= $5)
+                    AND ($6::timestamptz IS NULL OR posts.created_at < $6)
+                    AND ($7::bigint IS NULL OR posts.total_likes >= $7)
+                    AND ($8::bool IS NULL OR (posts.replies_to IS NOT NULL) = $8)
+                    AND ($9::real IS NULL OR (
+                        ts_rank(posts.search_vector, websearch_to_tsquery('english', $1)), posts.id
+                    ) < ($9, $10))
+                ORDER BY rank DESC, posts.id DESC
+                LIMIT $11
+            ) hits
+            ORDER BY hits.rank DESC, hits.id DESC"#,
+            self.include, self.exclude, &self.from, &self.hashtags, self.since, self.until,
+            self.min_likes, self.is_reply, rank, id, page.fetch_limit()
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        let hits = Page::new(hits, page, |e| (e.rank, e.id));
+        let ids = hits.items.iter().map(|e| e.id).collect::<Vec<i64>>();
+        let mut posts = sqlx::query_as!(PostWithUser, r#"
+            SELECT * FROM get_posts_default($1) WHERE id = ANY($2)"#,
+            user_id, &ids
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        // Keep the order of the hits
+        let items = hits.items.into_iter()
+            .filter_map(|hit| {
+                let index = posts.iter().position(|e| e.id == Some(hit.id))?;
+                Some(PostSearchHit { post: posts.swap_remove(index), headline: hit.headline, rank: hit.rank })
+            })
+            .collect();
+
+        Ok(Page { items, next_cursor: hits.next_cursor })
+    }
+
+    /// A word, which might be an operator like `from:`
+    fn push_word(&mut self, word: &str, negated: bool) -> Result<(), Error> {
+        if let Some((operator, value)) = word.split_once(':') {
+            match (operator, negated) {
+                ("is", _) if value == "reply" => {
+                    self.is_reply = Some(!negated);
+                    return Ok(());
+                },
+                ("from" | "since" | "until" | "min_likes", true) => {
+                    return Err(Error::new(format!("-{operator}: is not supported")));
+                },
+                ("from", false) => {
+                    let handle = value.trim_start_matches('@');
+                    if handle.is_empty() {
+                        return Err(Error::new("Invalid from: expected a handle"));
+                    }
+                    self.from.push(handle.to_lowercase());
+                    return Ok(());
+                },
+                ("since", false) => {
+                    self.since = Some(Self::parse_date(operator, value)?);
+                    return Ok(());
+                },
+                ("until", false) => {
+                    self.until = Some(Self::parse_date(operator, value)?);
+                    return Ok(());
+                },
+                ("min_likes", false) => {
+                    self.min_likes = Some(value.parse().map_err(|_|
+                        Error::new("Invalid min_likes: expected a number")
+                    )?);
+                    return Ok(());
+                },
+                _ => ()
+            }
+        }
+
+        match word.strip_prefix('#') {
+            Some(tag) if !negated && !tag.is_empty() => self.hashtags.push(tag.to_lowercase()),
+            _ => self.push_text(word, false, negated)
+        }
+
+        Ok(())
+    }
+
+    /// Adds a word or phrase to `include` or `exclude`. Quotes
+    /// and leading dashes are removed so they can't change the
+    /// meaning of the query.
+    fn push_text(&mut self, text: &str, phrase: bool, negated: bool) {
+        let text = text.replace('"', " ");
+        let text = text.trim().trim_start_matches('-');
+        if text.is_empty() {
+            return;
+        }
+
+        // Excluding any of the words excludes the post
+        let (query, separator) = match negated {
+            true => (&mut self.exclude, " or "),
+            false => (&mut self.include, " ")
+        };
+        if !query.is_empty() {
+            query.push_str(separator);
+        }
+        match phrase {
+            true => query.push_str(&format!("\"{text}\"")),
+            false => query.push_str(text)
+        }
+    }
+
+    /// Dates are written as `YYYY-MM-DD` and are in UTC
+    fn parse_date(operator: &str, value: &str) -> Result<DateTime<Utc>, Error> {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .map(|e| e.and_time(chrono::NaiveTime::MIN).and_utc())
+            .map_err(|_| Error::new(format!("Invalid {operator}: expected a date like 2025-01-31")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> SearchQuery {
+        SearchQuery::parse(query).unwrap()
+    }
+
+    fn date(date: &str) -> Option<DateTime<Utc>> {
+        Some(NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap().and_time(chrono::NaiveTime::MIN).and_utc())
+    }
+
+    #[test]
+    fn words_and_phrases() {
+        let query = parse("  rust \"async await\"  web ");
+        assert_eq!(query.include, "rust \"async await\" web");
+        assert_eq!(query.exclude, "");
+    }
+
+    #[test]
+    fn negation() {
+        let query = parse("rust -spam -\"buy now\" --ads");
+        assert_eq!(query.include, "rust");
+        assert_eq!(query.exclude, "spam or \"buy now\" or ads");
+    }
+
+    #[test]
+    fn quotes_in_words_are_dropped() {
+        let query = parse("say\"hi\" -\"\"");
+        assert_eq!(query.include, "say hi");
+        assert_eq!(query.exclude, "");
+    }
+
+    #[test]
+    fn unclosed_quote_runs_to_the_end() {
+        assert_eq!(parse("rust \"async await").include, "rust \"async await\"");
+        assert_eq!(parse("-\"async await").exclude, "\"async await\"");
+    }
+
+    #[test]
+    fn from() {
+        assert_eq!(parse("from:@Artur from:bob").from, ["artur", "bob"]);
+        assert!(SearchQuery::parse("from:").is_err());
+        assert!(SearchQuery::parse("from:@").is_err());
+        assert!(SearchQuery::parse("-from:bob").is_err());
+    }
+
+    #[test]
+    fn hashtags() {
+        let query = parse("#Rust #web -#spam #");
+        assert_eq!(query.hashtags, ["rust", "web"]);
+        assert_eq!(query.include, "#");
+        assert_eq!(query.exclude, "#spam");
+    }
+
+    #[test]
+    fn dates() {
+        let query = parse("since:2025-01-31 until:2025-02-01");
+        assert_eq!(query.since, date("2025-01-31"));
+        assert_eq!(query.until, date("2025-02-01"));
+
+        for invalid in ["since:2025-13-01", "until:2025-02-30", "since:yesterday", "until:", "-since:2025-01-31"] {
+            assert!(SearchQuery::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn min_likes() {
+        assert_eq!(parse("min_likes:10").min_likes, Some(10));
+        for invalid in ["min_likes:ten", "min_likes:", "min_likes:1.5", "-min_likes:10"] {
+            assert!(SearchQuery::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn is_reply() {
+        assert_eq!(parse("is:reply").is_reply, Some(true));
+        assert_eq!(parse("-is:reply").is_reply, Some(false));
+
+        // Anything else is just a word
+        let query = parse("is:quote");
+        assert_eq!((query.is_reply, query.include.as_str()), (None, "is:quote"));
+    }
+
+    #[test]
+    fn empty() {
+        assert!(parse("").is_empty());
+        assert!(parse("   - \"\"").is_empty());
+        assert!(!parse("is:reply").is_empty());
+        assert!(SearchQuery::parse(&"a".repeat(MAX_QUERY_LENGTH + 1)).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Server side sessions. A session is created every
+//! time someone signs in, and both the refresh token
+//! and every access token (JWT) issued for it point
+//! back to it - so revoking the session logs out that
+//! device for good.
+
+/* Imports */
+use actix_web::http::StatusCode;
+use serde::Serialize;
+use sqlx::PgPool;
+use crate::{config::config, error::Error, middleware::auth::UserClaims, utils::token};
+
+/* Constants */
+const REFRESH_SECRET_LEN: usize = 48;
+
+/// What the client receives after signing in
+/// or refreshing a session
+#[derive(Serialize, Debug)]
+pub struct TokenPair {
+    /// Short lived JWT, sent as `Authorization: Bearer ..`
+    pub token: String,
+
+    /// Long lived single use token which is swapped for
+    /// a new `TokenPair` at `/auth/refresh`. Formatted as
+    /// `<session id>.<secret>`
+    pub refresh_token: String,
+}
+
+/// Handles session related things
+pub struct Session;
+
+impl Session {
+    /// Creates a new session for a user that has just
+    /// proven who they are (login / sign up). Signing in
+    /// cancels a pending account deletion.
+    pub async fn create(pool: &PgPool, user_id: i64, handle: String) -> Result<TokenPair, Error> {
+        sqlx::query!(r#"
+            UPDATE users SET deletion_requested_at = NULL
+            WHERE users.id = $1 AND users.deletion_requested_at IS NOT NULL"#,
+            user_id
+        )
+        .execute(pool)
+        .await
+        .map_err(Error::new)?;
+
+        let secret = token::generate(REFRESH_SECRET_LEN);
+        let session_id = sqlx::query_scalar!(r#"
+            INSERT INTO sessions
+            (user_id, refresh_hash, expires_at)
+            VALUES ($1, $2, now() + make_interval(days => $3))
+            RETURNING id"#,
+            user_id, token::hash(&secret), config().jwt_token_maxage as i32
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(|_| Error::new("Could not create session"))?;
+
+        Self::token_pair(handle, user_id, session_id, &secret)
+    }
+
+    /// Swaps a refresh token for a new token pair. The old
+    /// refresh token stops working (rotation), and if it's
+    /// ever presented again we assume it was stolen and
+    /// revoke the whole session.
+    pub async fn refresh(pool: &PgPool, refresh_token: &str) -> Result<TokenPair, Error> {
+        let (session_id, secret) = Self::parse_refresh_token(refresh_token)?;
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+
+        let session = sqlx::query!(r#"
+            SELECT
+                sessions.user_id, sessions.refresh_hash, users.handle,
+                (sessions.revoked_at IS NULL AND sessions.expires_at > now()) AS "active!: bool"
+            FROM sessions
+                JOIN users ON users.id = sessions.user_id
+            WHERE sessions.id = $1
+            FOR UPDATE OF sessions"#,
+            session_id
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(Error::new)?
+        .ok_or_else(Self::unauthorized)?;
+
+        if !session.active {
+            return Err(Self::unauthorized());
+        }
+
+        // An already rotated refresh token was replayed
+        if session.refresh_hash != token::hash(&secret) {
+            sqlx::query!("UPDATE sessions SET revoked_at = now() WHERE id = $1", session_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(Error::new)?;
+            tx.commit().await.map_err(Error::new)?;
+
+            return Err(Self::unauthorized());
+        }
+
+        let new_secret = token::generate(REFRESH_SECRET_LEN);
+        sqlx::query!(r#"
+            UPDATE sessions SET
+                refresh_hash = $2,
+                last_used_at = now(),
+                expires_at = now() + make_interval(days => $3)
+            WHERE id = $1"#,
+            session_id, token::hash(&new_secret), config().jwt_token_maxage as i32
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+        tx.commit().await.map_err(Error::new)?;
+
+        Self::token_pair(session.handle, session.user_id, session_id, &new_secret)
+    }
+
+    /// Revokes the session that a refresh token belongs to
+    /// (logging out a single device)
+    pub async fn revoke(pool: &PgPool, refresh_token: &str) -> Result<(), Error> {
+        let (session_id, secret) = Self::parse_refresh_token(refresh_token)?;
+        let result = sqlx::query!(r#"
+            UPDATE sessions SET revoked_at = now()
+            WHERE id = $1 AND refresh_hash = $2 AND revoked_at IS NULL"#,
+            session_id, token::hash(&secret)
+        )
+        .execute(pool)
+        .await
+        .map_err(Error::new)?;
+
+        match result.rows_affected() {
+            0 => Err(Self::unauthorized()),
+            _ => Ok(())
+        }
+    }
+
+    /// Revokes every session of a user (logging out everywhere)
+    pub async fn revoke_all<'a, E>(executor: E, user_id: i64) -> Result<(), Error>
+    where E: sqlx::Executor<'a, Database = sqlx::Postgres>
+    {
+        sqlx::query!(r#"
+            UPDATE sessions SET revoked_at = now()
+            WHERE user_id = $1 AND revoked_at IS NULL"#,
+            user_id
+        )
+        .execute(executor)
+        .await
+        .map(|_| ())
+        .map_err(Error::new)
+    }
+
+    /// If the session exists, belongs to the user and
+    /// has neither expired nor been revoked
+    pub async fn is_active(pool: &PgPool, user_id: i64, session_id: i64) -> Result<bool, Error> {
+        sqlx::query_scalar!(r#"
+            SELECT EXISTS(
+                SELECT 1 FROM sessions
+                WHERE sessions.id = $1
+                AND sessions.user_id = $2
+                AND sessions.revoked_at IS NULL
+                AND sessions.expires_at > now()
+            ) AS "active!: bool""#,
+            session_id, user_id
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(Error::new)
+    }
+
+    /// Builds the JWT + refresh token which are sent to the client
+    fn token_pair(handle: String, user_id: i64, session_id: i64, secret: &str) -> Result<TokenPair, Error> {
+        let token = UserClaims::new(handle, user_id, session_id)
+            .encode()
+            .ok_or(Error::new("Could not create JWT token"))?;
+
+        Ok(TokenPair { token, refresh_token: format!("{session_id}.{secret}") })
+    }
+
+    /// Splits `<session id>.<secret>`
+    fn parse_refresh_token(refresh_token: &str) -> Result<(i64, String), Error> {
+        refresh_token.split_once('.')
+            .and_then(|(id, secret)| Some((id.parse::<i64>().ok()?, secret.to_string())))
+            .ok_or_else(Self::unauthorized)
+    }
+
+    fn unauthorized() -> Error {
+        Error::new_with_code("Invalid or expired refresh token", StatusCode::UNAUTHORIZED)
+    }
+}
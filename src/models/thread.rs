@@ -0,0 +1,183 @@
+//! The conversation around a post: the chain of posts it
+//! replies to, and the tree of replies underneath it. In
+//! every level of the tree, replies by the author of the
+//! thread (the poster of its root) come first, then the
+//! most liked.
+
+/* Imports */
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use actix_web::http::StatusCode;
+use sqlx::PgPool;
+use crate::error::Error;
+use super::{page::{Page, PageRequest}, post::PostWithUser};
+
+/* Constants */
+/// How many levels of replies are included by default
+const DEFAULT_DEPTH: i32 = 3;
+const MAX_DEPTH: i32 = 10;
+/// How many replies to a reply are included. Only the direct
+/// replies of the post are paginated, anything below that is
+/// fetched by requesting the thread of that reply.
+const NESTED_REPLIES_LIMIT: i64 = 5;
+
+/// Query parameters of the thread endpoint
+#[derive(Deserialize)]
+pub struct ThreadRequest {
+    /// How many levels of replies to include
+    depth: Option<i32>,
+}
+
+/// A post and the conversation around it
+#[derive(Serialize)]
+pub struct Thread {
+    /// The posts `post` is a reply to, the root first
+    pub ancestors: Vec<PostWithUser>,
+    pub post: PostWithUser,
+    pub replies: Page<ThreadNode>,
+}
+
+/// A reply in the tree, and the replies to it
+#[derive(Serialize)]
+pub struct ThreadNode {
+    #[serde(flatten)]
+    pub post: PostWithUser,
+
+    /// At most `NESTED_REPLIES_LIMIT`, compare with
+    /// `total_replies` to tell if there are more
+    pub replies: Vec<ThreadNode>,
+}
+
+/// A post in the tree, before the post itself is fetched
+struct TreeEntry {
+    id: i64,
+    replies_to: Option<i64>,
+}
+
+impl ThreadRequest {
+    pub fn depth(&self) -> i32 {
+        self.depth.unwrap_or(DEFAULT_DEPTH).clamp(1, MAX_DEPTH)
+    }
+}
+
+impl Thread {
+    /// The thread around `post_id`, as seen by `user_id`. The
+    /// page is of the direct replies to `post_id`, each with
+    /// `depth - 1` levels of replies below it.
+    pub async fn get(pool: &PgPool, user_id: i64, post_id: i64, depth: i32, page: &PageRequest) -> Result<Self, Error> {
+        let mut ancestors = sqlx::query_as!(PostWithUser, r#"
+            WITH RECURSIVE chain AS (
+                SELECT posts.id, posts.replies_to, 0 AS distance
+                FROM posts WHERE posts.id = $2
+                UNION ALL
+                SELECT posts.id, posts.replies_to, chain.distance + 1
+                FROM posts JOIN chain ON posts.id = chain.replies_to
+            )
+            SELECT posts.* FROM chain
+                JOIN get_posts_default($1) posts ON posts.id = chain.id
+            ORDER BY chain.distance DESC"#,
+            user_id, post_id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        let post = ancestors.pop().ok_or(Error::new_with_code("Post not found", StatusCode::NOT_FOUND))?;
+        let root_id = ancestors.first().unwrap_or(&post).id;
+
+        // Sorted by (replied by the author, likes), id
+        let (key, id) = page.cursor::<(bool, i64)>()?.unzip();
+        let (by_author, total_likes) = key.unzip();
+        let replies = sqlx::query!(r#"
+            WITH author AS (SELECT posts.poster_id FROM posts WHERE posts.id = $1)
+            SELECT
+                posts.id, posts.total_likes,
+                COALESCE(posts.poster_id = (SELECT poster_id FROM author), false) AS "by_author!"
+            FROM posts
+            WHERE posts.replies_to = $2
+                AND (posts.deleted_at IS NULL OR posts.total_replies > 0)
+                AND ($3::bool IS NULL OR (
+                    COALESCE(posts.poster_id = (SELECT poster_id FROM author), false), posts.total_likes, posts.id
+                ) < ($3, $4, $5))
+            ORDER BY 3 DESC, posts.total_likes DESC, posts.id DESC
+            LIMIT $6"#,
+            root_id, post_id, by_author, total_likes, id, page.fetch_limit()
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+        let replies = Page::new(replies, page, |e| ((e.by_author, e.total_likes), e.id));
+
+        // The replies of the page and the levels below them, each
+        // level ordered like the page is
+        let entries = sqlx::query_as!(TreeEntry, r#"
+            WITH RECURSIVE author AS (
+                SELECT posts.poster_id FROM posts WHERE posts.id = $1
+            ), tree AS (
+                SELECT posts.id, posts.replies_to, 1 AS depth, 0::bigint AS rank
+                FROM posts WHERE posts.id = ANY($2)
+                UNION ALL
+                SELECT children.id, children.replies_to, tree.depth + 1, children.rank
+                FROM tree CROSS JOIN LATERAL (
+                    SELECT
+                        posts.id, posts.replies_to,
+                        row_number() OVER (ORDER BY
+                            COALESCE(posts.poster_id = (SELECT poster_id FROM author), false) DESC,
+                            posts.total_likes DESC, posts.id DESC
+                        ) AS rank
+                    FROM posts
+                    WHERE posts.replies_to = tree.id
+                        AND (posts.deleted_at IS NULL OR posts.total_replies > 0)
+                    ORDER BY rank
+                    LIMIT $4
+                ) children
+                WHERE tree.depth < $3
+            )
+            SELECT tree.id AS "id!", tree.replies_to
+            FROM tree
+            WHERE tree.depth > 1
+            ORDER BY tree.depth, tree.replies_to, tree.rank"#,
+            root_id, &replies.items.iter().map(|e| e.id).collect::<Vec<i64>>(), depth, NESTED_REPLIES_LIMIT
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?;
+
+        let ids = replies.items.iter().map(|e| e.id)
+            .chain(entries.iter().map(|e| e.id))
+            .collect::<Vec<i64>>();
+        let mut posts = sqlx::query_as!(PostWithUser, r#"
+            SELECT * FROM get_posts_default($1) WHERE id = ANY($2)"#,
+            user_id, &ids
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(Error::new)?
+        .into_iter()
+        .filter_map(|e| Some((e.id?, e)))
+        .collect::<HashMap<i64, PostWithUser>>();
+
+        let mut children = HashMap::<i64, Vec<i64>>::new();
+        for entry in &entries {
+            if let Some(replies_to) = entry.replies_to {
+                children.entry(replies_to).or_default().push(entry.id);
+            }
+        }
+
+        let items = replies.items.iter()
+            .filter_map(|e| Self::node(e.id, &mut posts, &children))
+            .collect();
+
+        Ok(Self { ancestors, post, replies: Page { items, next_cursor: replies.next_cursor } })
+    }
+
+    /// Takes the post `id` and its replies out of `posts`
+    fn node(id: i64, posts: &mut HashMap<i64, PostWithUser>, children: &HashMap<i64, Vec<i64>>) -> Option<ThreadNode> {
+        let post = posts.remove(&id)?;
+        let replies = children.get(&id)
+            .map(|ids| ids.iter().filter_map(|e| Self::node(*e, posts, children)).collect())
+            .unwrap_or_default();
+
+        Some(ThreadNode { post, replies })
+    }
+}
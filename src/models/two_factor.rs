@@ -0,0 +1,195 @@
+//! TOTP (RFC 6238) two factor authentication and
+//! the one time recovery codes that come with it.
+
+/* Imports */
+use std::time::SystemTime;
+use serde::Serialize;
+use sqlx::PgPool;
+use totp_rs::{Algorithm, Secret, TOTP};
+use crate::{error::Error, utils::token};
+
+/* Constants */
+const ISSUER: &str = "Twitter2";
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+
+/// What the client gets when beginning enrollment. The url
+/// is usually shown as a QR code for the authenticator app.
+#[derive(Serialize)]
+pub struct TotpSetup {
+    pub secret: String,
+    pub otpauth_url: String,
+}
+
+/// Handles two factor authentication related things
+pub struct TwoFactor;
+
+impl TwoFactor {
+    /// Generates a new secret for the user. It isn't required
+    /// for logging in until it's been confirmed with a code.
+    pub async fn setup(pool: &PgPool, user_id: i64, handle: &str) -> Result<TotpSetup, Error> {
+        let secret = Secret::generate_secret().to_encoded().to_string();
+        let totp = Self::totp(&secret, handle)?;
+
+        let result = sqlx::query!(r#"
+            UPDATE users SET totp_secret = $2, totp_last_step = NULL
+            WHERE users.id = $1 AND users.totp_enabled = false"#,
+            user_id, secret
+        )
+        .execute(pool)
+        .await
+        .map_err(Error::new)?;
+
+        match result.rows_affected() {
+            0 => Err(Error::new("Two factor authentication is already enabled")),
+            _ => Ok(TotpSetup { otpauth_url: totp.get_url(), secret })
+        }
+    }
+
+    /// Enables two factor authentication if `code` matches the
+    /// secret from `setup`, and returns fresh recovery codes.
+    /// These are only ever shown this one time.
+    pub async fn confirm(pool: &PgPool, user_id: i64, code: &str) -> Result<Vec<String>, Error> {
+        let enabled = sqlx::query_scalar!("SELECT totp_enabled FROM users WHERE users.id = $1", user_id)
+            .fetch_one(pool)
+            .await
+            .map_err(Error::new)?;
+
+        if enabled {
+            return Err(Error::new("Two factor authentication is already enabled"));
+        }
+        if !Self::check_totp(pool, user_id, code).await? {
+            return Err(Error::failed_attempt("Invalid code"));
+        }
+
+        let codes = (0..RECOVERY_CODE_COUNT)
+            .map(|_| token::generate(RECOVERY_CODE_LEN).to_lowercase())
+            .collect::<Vec<String>>();
+        let hashes = codes.iter()
+            .map(|e| token::hash(e))
+            .collect::<Vec<Vec<u8>>>();
+
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        sqlx::query!("UPDATE users SET totp_enabled = true WHERE users.id = $1", user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::new)?;
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::new)?;
+        sqlx::query!(r#"
+            INSERT INTO recovery_codes (user_id, code_hash)
+            SELECT $1, * FROM UNNEST($2::bytea[])"#,
+            user_id, &hashes
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+        tx.commit().await.map_err(Error::new)?;
+
+        Ok(codes.into_iter()
+            .map(|e| format!("{}-{}", &e[..RECOVERY_CODE_LEN / 2], &e[RECOVERY_CODE_LEN / 2..]))
+            .collect())
+    }
+
+    /// Checks the second factor, which is either a code from the
+    /// authenticator app or an unused recovery code (which is
+    /// used up by this).
+    pub async fn verify(pool: &PgPool, user_id: i64, code: &str) -> Result<(), Error> {
+        let code = code.chars()
+            .filter(|e| e.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+
+        let valid = if code.len() == DIGITS {
+            Self::check_totp(pool, user_id, &code).await?
+        }else {
+            sqlx::query!(r#"
+                UPDATE recovery_codes SET used_at = now()
+                WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL"#,
+                user_id, token::hash(&code)
+            )
+            .execute(pool)
+            .await
+            .map_err(Error::new)?
+            .rows_affected() > 0
+        };
+
+        match valid {
+            true => Ok(()),
+            false => Err(Error::failed_attempt("Invalid code"))
+        }
+    }
+
+    /// Turns two factor authentication off. Requires a valid
+    /// code so that a stolen session can't do it.
+    pub async fn disable(pool: &PgPool, user_id: i64, code: &str) -> Result<(), Error> {
+        Self::verify(pool, user_id, code).await?;
+
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        sqlx::query!(r#"
+            UPDATE users SET totp_enabled = false, totp_secret = NULL, totp_last_step = NULL
+            WHERE users.id = $1"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(Error::new)?;
+        tx.commit().await.map_err(Error::new)
+    }
+
+    /// If `code` is valid for the current time step (or the
+    /// ones next to it, for clock drift). Every step can only
+    /// be used once.
+    async fn check_totp(pool: &PgPool, user_id: i64, code: &str) -> Result<bool, Error> {
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        let user = sqlx::query!(r#"
+            SELECT handle, totp_secret, totp_last_step FROM users
+            WHERE users.id = $1 FOR UPDATE"#,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        let secret = user.totp_secret
+            .ok_or(Error::new("Two factor authentication has not been set up"))?;
+        let totp = Self::totp(&secret, &user.handle)?;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(Error::new)?
+            .as_secs() / STEP;
+
+        let step = [now - 1, now, now + 1].into_iter()
+            .filter(|step| user.totp_last_step.is_none_or(|last| *step as i64 > last))
+            .find(|step| totp.generate(step * STEP) == code);
+
+        match step {
+            Some(step) => {
+                sqlx::query!("UPDATE users SET totp_last_step = $2 WHERE users.id = $1", user_id, step as i64)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(Error::new)?;
+                tx.commit().await.map_err(Error::new)?;
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    fn totp(secret: &str, handle: &str) -> Result<TOTP, Error> {
+        let bytes = Secret::Encoded(secret.to_string())
+            .to_bytes()
+            .map_err(|_| Error::new("Invalid TOTP secret"))?;
+
+        TOTP::new(Algorithm::SHA1, DIGITS, 1, STEP, bytes, Some(ISSUER.to_string()), handle.to_string())
+            .map_err(Error::new)
+    }
+}
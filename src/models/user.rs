@@ -1,26 +1,31 @@
 /* Imports */
-use std::{future::{ready, Future, Ready}, pin::Pin, sync::Arc};
+use std::{future::Future, pin::Pin, sync::Arc};
 use actix_files::NamedFile;
 use actix_web::{http::{header::ContentType, StatusCode}, web::{self, Data}, FromRequest, HttpRequest, HttpResponse, Responder};
-use rand::{thread_rng, Rng};
+use argon2::{password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString}, Algorithm, Argon2, Params, Version};
+use rand::rngs::OsRng;
 use regex::Regex;
 use serde::Serialize;
 use sha2::{Sha256, Digest};
-use sqlx::{prelude::FromRow, types::chrono::{self, NaiveDateTime}, PgPool};
+use sqlx::{prelude::FromRow, types::chrono::{self, NaiveDateTime}, PgConnection, PgPool};
 use unicode_segmentation::UnicodeSegmentation;
-use crate::{error::Error, middleware::auth::UserClaims, utils::logger::log, AppData};
-use super::pfp::ProfileImageHandler;
+use crate::{config::config, error::Error, middleware::auth::{Auth, MfaClaims, UserClaims}, utils::{logger::log, mailer::{Mail, Mailer}}, AppData};
+use super::{api_token::{ApiScope, ApiToken}, conversation::DmPolicy, pfp::ProfileImageHandler, session::{Session, TokenPair}, user_token::{TokenPurpose, UserToken}};
 
 /* Constants */
-const EMAIL_REGEX: &'static str = r#"^[\w\.-]+@[\w\.-]+\.\w+$"#;
-const HANDLE_REGEX: &'static str = "^[a-z0-9.]+$";
+const EMAIL_REGEX: &str = r#"^[\w\.-]+@[\w\.-]+\.\w+$"#;
+const HANDLE_REGEX: &str = "^[a-z0-9.]+$";
 const HANDLE_MAX_LEN: usize = 25;
 const HANDLE_MIN_LEN: usize = 3;
 const DISPLAYNAME_MAX_LEN: usize = 50;
 const DISPLAYNAME_MIN_LEN: usize = 1;
 const PASSWORD_MAX_LEN: usize = 45;
 const PASSWORD_MIN_LEN: usize = 7;
-const PEPPER: &'static str = env!("PEPPER");
+lazy_static::lazy_static! {
+    /// Argon2id cost parameters (see `Config`), already
+    /// validated when the config was loaded
+    static ref ARGON2_PARAMS: Params = config().argon2_params().unwrap();
+}
 
 #[derive(Debug)]
 pub struct User {
@@ -36,13 +41,47 @@ pub struct User {
     joined: chrono::DateTime<chrono::Utc>,
     email: String,
 
-    /// SHA-256(pass + salt + pepper)
-    /// #[serde(skip)]
-    hash: Vec<u8>,
-    salt: String,
+    /// Legacy SHA-256(pass + salt + pepper), only set for
+    /// accounts which haven't logged in since the switch
+    /// to Argon2id
+    hash: Option<Vec<u8>>,
+    salt: Option<String>,
 
     followers: i32,
     following: i32,
+
+    /// Argon2id PHC string (peppered via the argon2 secret)
+    password_hash: Option<String>,
+
+    /// If the user has opened the verification link
+    /// that was mailed to `email`
+    email_verified: bool,
+
+    /// Base32 TOTP secret, set once enrollment has begun
+    totp_secret: Option<String>,
+
+    /// If logging in requires a TOTP / recovery code
+    totp_enabled: bool,
+    totp_last_step: Option<i64>,
+
+    /// When the user asked for their account to be deleted,
+    /// see `Account::request_deletion`
+    deletion_requested_at: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Who can start conversations with the user, see `DmPolicy`
+    dm_policy: String,
+}
+
+/// What logging in with email and password results in
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Tokens(TokenPair),
+
+    /// The password was correct, but the user has two factor
+    /// authentication enabled. The `mfa_token` is swapped for
+    /// a token pair at `/auth/2fa/verify` together with a code.
+    MfaRequired { mfa_required: bool, mfa_token: String }
 }
 
 /// The version of the user struct that does not 
@@ -76,7 +115,7 @@ impl User {
     /// - Password invalid or too weak
     async fn try_create(
         pool: &PgPool, handle: String, displayname: String,
-        email: String, password: String, pepper: &str
+        email: String, password: String
     ) -> Result<Self, Error> {
         Self::handle_valid(pool, &handle).await?;
         Self::displayname_valid(&displayname)?;
@@ -84,8 +123,7 @@ impl User {
         Self::password_valid(&password)?;
 
         let id = 0;
-        let salt = Self::generate_salt();
-        let hash = Self::hash_password(&password, &salt);
+        let password_hash = Self::hash_password(password).await?;
 
         Ok(Self {
             // Not yet known, will be determined
@@ -98,13 +136,20 @@ impl User {
             handle,
             displayname,
             email,
-            hash,
-            salt,
+            hash: None,
+            salt: None,
+            password_hash: Some(password_hash),
+            email_verified: false,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: None,
+            deletion_requested_at: None,
+            dm_policy: DmPolicy::Everyone.as_str().to_string(),
         })
     }
 
     /// To non-sensitive data
-    pub fn to_non_sensitive(self) -> UserInfo {
+    pub fn into_non_sensitive(self) -> UserInfo {
         UserInfo {
             user_id: self.id,
             handle: self.handle,
@@ -128,30 +173,108 @@ impl User {
     }
 
     /// Create account
-    /// Ok yields JWT, Err yields error message
+    /// Ok yields the token pair of the new session, Err yields error message
     pub async fn create_account(
-        pool: &PgPool, handle: String, displayname: String,
-        email: String, password: String, 
-    ) -> Result<String, Error> {
+        pool: &PgPool, mailer: &dyn Mailer, handle: String,
+        displayname: String, email: String, password: String, 
+    ) -> Result<TokenPair, Error> {
         log::bright_green("create_account", format!("Creating account for @{}", &handle));
-        let user = Self::try_create(pool, handle.clone(), displayname, email, password, PEPPER).await?;
+        let user = Self::try_create(pool, handle.clone(), displayname, email, password).await?;
         log::bright_green("create_account", "Inserting");
 
-        sqlx::query_scalar!(r#"
+        let user_id = sqlx::query_scalar!(r#"
             INSERT INTO users
-            (handle, displayname, email, hash, salt)
-            VALUES ($1, $2, $3, $4, $5) RETURNING users.id"#,
-            user.handle, user.displayname, user.email, user.hash, user.salt
+            (handle, displayname, email, password_hash)
+            VALUES ($1, $2, $3, $4) RETURNING users.id"#,
+            user.handle, user.displayname, user.email, user.password_hash
         )
         .fetch_one(pool)
         .await
-        .map_err(|_| Error::new("Could not create account"))
+        .map_err(|_| Error::new("Could not create account"))?;
 
-        /* For Result::Ok(_) */
-        .and_then(|user_id| {
-            UserClaims::new(handle, user_id)
-                .to_string().ok_or(Error::new("Could not create JWT token"))
-        })
+        // The account is usable without a verified email,
+        // so failing to send shouldn't fail the sign up
+        if let Err(e) = Self::send_verification_email(pool, mailer, user_id, &user.email).await {
+            log::red("create_account", format!("Could not send verification email: {e}"));
+        }
+
+        Session::create(pool, user_id, handle).await
+    }
+
+    /// Mails a link for verifying `email` to the user
+    pub async fn send_verification_email(pool: &PgPool, mailer: &dyn Mailer, user_id: i64, email: &str) -> Result<(), Error> {
+        let token = UserToken::issue(pool, user_id, TokenPurpose::VerifyEmail, email).await?;
+        mailer.send(Mail {
+            to: email.to_string(),
+            subject: String::from("Verify your email address"),
+            body: format!(
+                "Open this link to verify your email address:\n\n{}/verify-email?token={}\n\nThe link expires in 24 hours.",
+                config().frontend_url, token
+            )
+        }).await
+    }
+
+    /// Marks the email address which a verification
+    /// token was sent to as verified
+    pub async fn verify_email(pool: &PgPool, token: &str) -> Result<(), Error> {
+        let (user_id, email) = UserToken::consume(pool, token, TokenPurpose::VerifyEmail).await?;
+        let result = sqlx::query!(r#"
+            UPDATE users SET email_verified = true
+            WHERE users.id = $1 AND users.email = $2"#,
+            user_id, email
+        )
+        .execute(pool)
+        .await
+        .map_err(Error::new)?;
+
+        match result.rows_affected() {
+            0 => Err(Error::new("The email address has changed since this link was sent")),
+            _ => Ok(())
+        }
+    }
+
+    /// Mails a password reset link if there is an account with
+    /// `email`. Runs in the background so that the response time
+    /// doesn't tell whether the account exists or not.
+    pub fn request_password_reset(pool: PgPool, mailer: Arc<dyn Mailer>, email: String) {
+        actix_web::rt::spawn(async move {
+            let user_id = match sqlx::query_scalar!("SELECT id FROM users WHERE users.email = $1", email)
+                .fetch_optional(&pool).await {
+                Ok(Some(e)) => e,
+                Ok(None) => return,
+                Err(e) => return log::red("password_reset", e)
+            };
+
+            let result = match UserToken::issue(&pool, user_id, TokenPurpose::ResetPassword, &email).await {
+                Ok(token) => mailer.send(Mail {
+                    to: email,
+                    subject: String::from("Reset your password"),
+                    body: format!(
+                        "Open this link to choose a new password:\n\n{}/reset-password?token={}\n\n\
+                        The link expires in 1 hour. If you didn't ask for this you can ignore this email.",
+                        config().frontend_url, token
+                    )
+                }).await,
+                Err(e) => Err(e)
+            };
+
+            if let Err(e) = result {
+                log::red("password_reset", format!("Could not send password reset email: {e}"));
+            }
+        });
+    }
+
+    /// Sets a new password using a password reset token. Every
+    /// session and API token of the user is revoked, since
+    /// someone else might have known the old password.
+    pub async fn reset_password(pool: &PgPool, token: &str, password: String) -> Result<(), Error> {
+        Self::password_valid(&password)?;
+
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        let (user_id, _) = UserToken::consume(&mut *tx, token, TokenPurpose::ResetPassword).await?;
+        Self::set_password(&mut *tx, user_id, password).await?;
+        Self::revoke_credentials(&mut tx, user_id).await?;
+        tx.commit().await.map_err(Error::new)
     }
 
     /// Try login with password
@@ -159,10 +282,10 @@ impl User {
     /// ?: differentiate between invalid password and invalid
     /// ?: email - because that can help attackers brute
     /// ?: forcing passwords / getting email addresses
-    pub async fn login(pool: &PgPool, email: &String, password: &String) -> Result<String, Error> {
+    pub async fn login(pool: &PgPool, email: &str, password: &str) -> Result<LoginResponse, Error> {
         log::bright_green("login", "Logging in");
 
-        let invalid_pass_or_email = Error::new("Invalid email or password");
+        let invalid_pass_or_email = Error::failed_attempt("Invalid email or password");
         let user = match sqlx::query_as!(Self,
             "SELECT * FROM users WHERE users.email = $1", email
         ).fetch_optional(pool).await {
@@ -174,105 +297,206 @@ impl User {
         };
 
         log::bright_green("login", "Checking hash");
-        let hash = Self::hash_password(password, &user.salt);
-        if user.hash == hash {
-            log::bright_green("login", "Hash matched - trying to return JWT");
-            let claims = UserClaims::new(user.handle, user.id);
-            match claims.to_string() {
-                Some(e) => Ok(e),
-                None => Err(Error::new("Could not generate JWT token"))
+        if user.check_password(password).await {
+            log::bright_green("login", "Hash matched - creating session");
+
+            // Legacy SHA-256 hash or outdated Argon2 parameters.
+            // Failing to upgrade shouldn't prevent the login
+            if user.password_hash.as_deref().is_none_or(Self::needs_rehash) {
+                log::bright_green("login", "Upgrading password hash");
+                if let Err(e) = Self::set_password(pool, user.id, password.to_string()).await {
+                    log::red("login", format!("Could not upgrade password hash: {e}"));
+                }
+            }
+
+            if user.totp_enabled {
+                log::bright_green("login", "Two factor authentication required");
+                return MfaClaims::new(user.handle, user.id)
+                    .encode()
+                    .map(|mfa_token| LoginResponse::MfaRequired { mfa_required: true, mfa_token })
+                    .ok_or(Error::new("Could not create JWT token"));
             }
+
+            Session::create(pool, user.id, user.handle).await
+                .map(LoginResponse::Tokens)
         }else {
             Err(invalid_pass_or_email)
         }        
     }
 
-    /// Set following user to true or not
-    pub async fn set_following(
-        pool: &PgPool, follower_id: i64, followee_id: i64,
-        wants_follow: bool
+    /// Changes the password of a user who knows their current
+    /// one. Every session and API token is revoked (someone might
+    /// have known the old password), so the device changing it
+    /// gets a new token pair back.
+    pub async fn change_password(&self, pool: &PgPool, current: &str, password: String) -> Result<TokenPair, Error> {
+        if !self.check_password(current).await {
+            return Err(Error::failed_attempt("Invalid password"));
+        }
+        Self::password_valid(&password)?;
+
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+        Self::set_password(&mut *tx, self.id, password).await?;
+        Self::revoke_credentials(&mut tx, self.id).await?;
+        tx.commit().await.map_err(Error::new)?;
+
+        Session::create(pool, self.id, self.handle.clone()).await
+    }
+
+    /// Mails a confirmation link to `email`. The address isn't
+    /// changed until the link has been opened (see
+    /// `confirm_email_change`), so a typo can't lock anyone out.
+    pub async fn request_email_change(
+        &self, pool: &PgPool, mailer: &dyn Mailer,
+        email: String, password: &str
     ) -> Result<(), Error> {
-        let is_following = sqlx::query!(r"
-            SELECT * FROM follows WHERE
-            follows.follower_id = $1 AND
-            follows.followee_id = $2",
-            follower_id, followee_id
+        if !self.check_password(password).await {
+            return Err(Error::failed_attempt("Invalid password"));
+        }
+        Self::email_valid(pool, &email).await?;
+
+        let token = UserToken::issue(pool, self.id, TokenPurpose::ChangeEmail, &email).await?;
+        mailer.send(Mail {
+            to: email,
+            subject: String::from("Confirm your new email address"),
+            body: format!(
+                "Open this link to start using this email address for @{}:\n\n{}/confirm-email?token={}\n\n\
+                The link expires in 24 hours. If you didn't ask for this you can ignore this email.",
+                self.handle, config().frontend_url, token
+            )
+        }).await
+    }
+
+    /// Switches the email of a user to the (now verified) address
+    /// that an email change token was sent to. The old address
+    /// is told about it, in case it wasn't the owner who did it.
+    pub async fn confirm_email_change(pool: &PgPool, mailer: &dyn Mailer, token: &str) -> Result<(), Error> {
+        let (user_id, email) = UserToken::consume(pool, token, TokenPurpose::ChangeEmail).await?;
+        let old_email = sqlx::query_scalar!(r#"
+            UPDATE users SET email = $2, email_verified = true
+            FROM (SELECT email FROM users WHERE users.id = $1 FOR UPDATE) AS old
+            WHERE users.id = $1
+            RETURNING old.email"#,
+            user_id, email
         )
-            .fetch_optional(pool)
-            .await
-            .map_err(Error::new)?
-            .is_some();
+        .fetch_one(pool)
+        .await
+        .map_err(|e| match e.as_database_error().is_some_and(|e| e.is_unique_violation()) {
+            true => Error::new("Email already in use"),
+            false => Error::new(e)
+        })?;
+
+        // Reset links sent to the old address shouldn't work anymore
+        UserToken::invalidate(pool, user_id, TokenPurpose::ResetPassword).await?;
+
+        if let Err(e) = mailer.send(Mail {
+            to: old_email,
+            subject: String::from("Your email address was changed"),
+            body: format!(
+                "The email address of your account was changed to {}.\n\n\
+                If you didn't do this, reset your password at {}/forgot-password right away.",
+                email, config().frontend_url
+            )
+        }).await {
+            log::red("confirm_email_change", format!("Could not notify old email address: {e}"));
+        }
 
-        // If we should increment the following count or decrease it
-        let increment: bool;
+        Ok(())
+    }
 
-        // If we want to like but we've already done it or opposite
-        // (only for making code clear it's not neccesary)
-        if wants_follow && is_following || !wants_follow && !is_following {
+    /// Changes the @handle of a user
+    pub async fn set_handle(&self, pool: &PgPool, handle: String) -> Result<(), Error> {
+        if handle == self.handle {
             return Ok(());
-        }else if wants_follow && !is_following {
-            sqlx::query!(r#"
-                INSERT INTO follows
-                (follower_id, followee_id) VALUES ($1, $2)"#,
-                follower_id, followee_id
-            )
-                .execute(pool)
-                .await
-                .map_err(Error::new)?;
-
-            increment = true;
-        }else if !wants_follow && is_following {
-            sqlx::query!(r#"
-                DELETE FROM follows
-                    WHERE follows.follower_id = $1
-                    AND follows.followee_id = $2"#,
-                follower_id, followee_id
-            )
-                .execute(pool)
-                .await
-                .map_err(Error::new)?;
+        }
+        Self::handle_valid(pool, &handle).await?;
 
-            increment = false;
-        }else {
-            unreachable!()
+        sqlx::query!("UPDATE users SET handle = $2 WHERE users.id = $1", self.id, handle)
+            .execute(pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| match e.as_database_error().is_some_and(|e| e.is_unique_violation()) {
+                true => Error::new("Handle occupied"),
+                false => Error::new(e)
+            })
+    }
+
+    /// Changes the displayname of a user
+    pub async fn set_displayname(pool: &PgPool, user_id: i64, displayname: String) -> Result<(), Error> {
+        Self::displayname_valid(&displayname)?;
+
+        sqlx::query!("UPDATE users SET displayname = $2 WHERE users.id = $1", user_id, displayname)
+            .execute(pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::new)
+    }
+
+    /// Changes who can start conversations with a user
+    pub async fn set_dm_policy(pool: &PgPool, user_id: i64, dm_policy: DmPolicy) -> Result<(), Error> {
+        sqlx::query!("UPDATE users SET dm_policy = $2 WHERE users.id = $1", user_id, dm_policy.as_str())
+            .execute(pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::new)
+    }
+
+    /// If `password` is the password of the user. Accounts which
+    /// haven't logged in since the switch to Argon2id are checked
+    /// against their legacy hash.
+    pub async fn check_password(&self, password: &str) -> bool {
+        match (&self.password_hash, &self.hash, &self.salt) {
+            (Some(phc), _, _) => Self::verify_password(password.to_string(), phc.clone()).await,
+            (None, Some(hash), Some(salt)) => *hash == Self::legacy_hash_password(password, salt),
+            _ => false
         }
+    }
 
-        let op_str = if increment { "+ 1" } else { "- 1" };
-
-        /* Followers count */
-        sqlx::query(&format!(r#"
-            UPDATE users
-                SET followers = followers {}
-                WHERE users.id = {}"#,
-            if increment { "+ 1" } else { "- 1" },
-            followee_id
-        ))
-        .execute(pool).await
-        .map(|_| ()).map_err(Error::new)?;
-
-        /* Following count */
-        sqlx::query(&format!(r#"
-            UPDATE users
-                SET following = following {}
-                WHERE users.id = {}"#,
-            if increment { "+ 1" } else { "- 1" },
-            follower_id
-        ))
-        .execute(pool).await
-        .map(|_| ()).map_err(Error::new)
-    }
-
-    /// Check if JWT is valid and return user if found via appdata postgres pool
-    async fn from_appdata(pool: &AppData, jwt: String) -> Result<Self, Error> {
-        let user_claims = UserClaims::is_valid(&jwt)?;
-        let id = user_claims.claims.id;
+    /// Replaces the password hash of a user with a fresh
+    /// Argon2id hash (and clears the legacy columns)
+    async fn set_password<'a, E>(executor: E, user_id: i64, password: String) -> Result<(), Error>
+    where E: sqlx::Executor<'a, Database = sqlx::Postgres>
+    {
+        let password_hash = Self::hash_password(password).await?;
+        sqlx::query!(r#"
+            UPDATE users SET
+                password_hash = $2,
+                hash = NULL,
+                salt = NULL
+            WHERE users.id = $1"#,
+            user_id, password_hash
+        )
+        .execute(executor)
+        .await
+        .map(|_| ())
+        .map_err(Error::new)
+    }
+
+    /// Signs the user out everywhere after their password has
+    /// changed: sessions, API tokens and pending reset links
+    async fn revoke_credentials(conn: &mut PgConnection, user_id: i64) -> Result<(), Error> {
+        UserToken::invalidate(&mut *conn, user_id, TokenPurpose::ResetPassword).await?;
+        Session::revoke_all(&mut *conn, user_id).await?;
+        ApiToken::revoke_all(&mut *conn, user_id).await
+    }
+
+    /// Returns the user which sent the request, as long as
+    /// they are allowed to read (see `Auth`)
+    async fn from_auth(req: &HttpRequest) -> Result<Self, Error> {
+        let auth = Auth::from_request(req).await?;
+        auth.require(ApiScope::Read)?;
+
+        let appdata = req.app_data::<web::Data<AppData>>()
+            .ok_or(Error::new_with_code(
+                "Internal server error (appdata retrieval from request)",
+                StatusCode::INTERNAL_SERVER_ERROR
+            ))?;
 
         sqlx::query_as!(Self,
-            "SELECT * FROM users WHERE users.id = $1", id
+            "SELECT * FROM users WHERE users.id = $1", auth.user_id
         )
-        .fetch_optional(&pool.db).await
+        .fetch_optional(&appdata.db).await
         .map_err(Error::new)
-        .and_then(|e| e.ok_or(Error::new("User not found")))
+        .and_then(|e| e.ok_or(Error::new_with_code("User not found", StatusCode::UNAUTHORIZED)))
     }
 
     /// Length checks and char checks for handle (username)
@@ -283,7 +507,8 @@ impl User {
         let len = handle.len();
         if len < HANDLE_MIN_LEN {
             return Err(Error::new("Handle must be at least 3 characters long"))
-        }if len > HANDLE_MAX_LEN {
+        }
+        if len > HANDLE_MAX_LEN {
             return Err(Error::new("Handle must be less than 15 characters long"))
         }
 
@@ -323,22 +548,24 @@ impl User {
     }
 
     /// Displayname length checks
-    fn displayname_valid(displayname: &String) -> Result<(), Error> {
-        let len = UnicodeSegmentation::graphemes(displayname.as_str(), true).count();
+    fn displayname_valid(displayname: &str) -> Result<(), Error> {
+        let len = UnicodeSegmentation::graphemes(displayname, true).count();
         if len < DISPLAYNAME_MIN_LEN {
             return Err(Error::new(format!("Displayname must be at least {} characters long", DISPLAYNAME_MIN_LEN)))
-        }if len > DISPLAYNAME_MAX_LEN {
+        }
+        if len > DISPLAYNAME_MAX_LEN {
             return Err(Error::new(format!("Displayname must be less than {} characters long", DISPLAYNAME_MAX_LEN)))
         }
 
         Ok(())
     }
     /// Password length checks
-    fn password_valid(password: &String) -> Result<(), Error> {
-        let len = UnicodeSegmentation::graphemes(password.as_str(), true).count();
+    fn password_valid(password: &str) -> Result<(), Error> {
+        let len = UnicodeSegmentation::graphemes(password, true).count();
         if len < PASSWORD_MIN_LEN {
             return Err(Error::new(format!("Password must be at least {} characters long", PASSWORD_MIN_LEN)))
-        }if len > PASSWORD_MAX_LEN {
+        }
+        if len > PASSWORD_MAX_LEN {
             return Err(Error::new(format!("Password must be less than {} characters long", PASSWORD_MAX_LEN)))
         }
 
@@ -348,7 +575,7 @@ impl User {
     /// Email regex checks
     async fn email_valid(pool: &PgPool, email: &String) -> Result<(), Error> {
         let regex = Regex::new(EMAIL_REGEX).unwrap();
-        match regex.is_match(&email) {
+        match regex.is_match(email) {
             true => {
                 match Self::email_occupied(pool, email).await? {
                     true => Err(Error::new("Email already in use")),
@@ -359,30 +586,70 @@ impl User {
         }
     }
 
-    /// Hashes `password` with salt and pepper
-    fn hash_password(password: &String, salt: &String) -> Vec<u8> {
-        let inner = password.to_owned() + salt + PEPPER;
-        let mut hasher = Sha256::new();
-        hasher.update(inner);
-        hasher.finalize()[..].to_owned()
+    /// Hashes `password` with Argon2id into a PHC string. Runs
+    /// on the blocking thread pool because it's slow on purpose.
+    async fn hash_password(password: String) -> Result<String, Error> {
+        web::block(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Self::argon2()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|e| e.to_string())
+        })
+        .await
+        .map_err(Error::new)?
+        .map_err(|_| Error::new("Could not hash password"))
+    }
+
+    /// Checks `password` against an Argon2 PHC string
+    async fn verify_password(password: String, phc: String) -> bool {
+        web::block(move || {
+            PasswordHash::new(&phc)
+                .map(|hash| Self::argon2().verify_password(password.as_bytes(), &hash).is_ok())
+                .unwrap_or(false)
+        })
+        .await
+        .unwrap_or(false)
     }
 
-    /// Generates a salt which is stored in the user table
-    fn generate_salt() -> String {
-        let chars = "abcdefghijklmnopqrstuvwxyz1234567890+-.,;:!\"#€%&/()=?*".chars().collect::<Vec<char>>();
-        let mut rng = thread_rng();
-        let length = rng.gen_range(25..=35);
-        let mut end = String::with_capacity(length);
-        for _ in 0..length {
-            end.push(chars[rng.gen_range(0..chars.len())])
+    /// If a PHC string was made with other parameters than
+    /// the ones we currently use
+    fn needs_rehash(phc: &str) -> bool {
+        match PasswordHash::new(phc) {
+            Ok(hash) => hash.algorithm != Algorithm::Argon2id.ident()
+                || Params::try_from(&hash).map_or(true, |params|
+                    params.m_cost() != ARGON2_PARAMS.m_cost()
+                    || params.t_cost() != ARGON2_PARAMS.t_cost()
+                    || params.p_cost() != ARGON2_PARAMS.p_cost()
+                ),
+            Err(_) => true
         }
-        end
+    }
+
+    fn argon2() -> Argon2<'static> {
+        Argon2::new_with_secret(
+            config().pepper.as_bytes(),
+            Algorithm::Argon2id,
+            Version::V0x13,
+            ARGON2_PARAMS.clone()
+        ).unwrap()
+    }
+
+    /// The old way of hashing passwords: SHA-256(pass + salt + pepper).
+    /// Only used to verify accounts that haven't been upgraded yet.
+    fn legacy_hash_password(password: &str, salt: &str) -> Vec<u8> {
+        let inner = password.to_owned() + salt + &config().pepper;
+        let mut hasher = Sha256::new();
+        hasher.update(inner);
+        hasher.finalize()[..].to_owned()
     }
 
     // Getters
     pub fn id(&self) -> i64 { self.id }
     pub fn displayname(&self) -> &String { &self.displayname }
     pub fn handle(&self) -> &String { &self.handle }
+    pub fn email(&self) -> &String { &self.email }
+    pub fn email_verified(&self) -> bool { self.email_verified }
+    pub fn totp_enabled(&self) -> bool { self.totp_enabled }
 }
 
 impl FromRequest for User {
@@ -390,46 +657,21 @@ impl FromRequest for User {
     type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
     fn from_request(req: &actix_web::HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
-        let auth_header = req.headers().get("Authorization").cloned();
-        let appdata = match req.app_data::<web::Data<AppData>>() {
-            Some(e) => e.clone(),
-            None => return Box::pin(async { Err(Error::new_with_code(
-                "Internal server error (appdata retrieval from request)",
-                StatusCode::INTERNAL_SERVER_ERROR
-            ))})
-        };
-
-        Box::pin(async move {
-            if let Some(header_value) = auth_header {
-                if let Ok(header_str) = header_value.to_str() {
-                    if let Some(token) = header_str.strip_prefix("Bearer ") {
-                        return User::from_appdata(appdata.get_ref(), token.to_string()).await
-                    }
-                }
-            }
-            
-            Err(Error::new_with_code("Unauthorized", StatusCode::UNAUTHORIZED))
-        })
+        let req = req.clone();
+        Box::pin(async move { User::from_auth(&req).await })
     }
 }
 
 impl FromRequest for UserIdReq {
     type Error = Error;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
     fn from_request(req: &actix_web::HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
-        let auth_header = req.headers().get("Authorization").cloned();
-        if let Some(header_value) = auth_header {
-            if let Ok(header_str) = header_value.to_str() {
-                if let Some(token) = header_str.strip_prefix("Bearer ") {
-                    return match UserClaims::is_valid(&token) {
-                        Ok(e) => ready(Ok(Self(e.claims.id))),
-                        Err(e) => ready(Err(e))
-                    }
-                }
-            }
-        }
-        
-        ready(Err(Error::new_with_code("Unauthorized", StatusCode::UNAUTHORIZED)))
+        let req = req.clone();
+        Box::pin(async move {
+            let auth = Auth::from_request(&req).await?;
+            auth.require(ApiScope::Read)?;
+            Ok(Self(auth.user_id))
+        })
     }
 }
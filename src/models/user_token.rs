@@ -0,0 +1,99 @@
+//! Single use, expiring tokens which are mailed
+//! to users - e.g email verification links and
+//! password resets. Only the SHA-256 of a token
+//! is stored.
+
+/* Imports */
+use sqlx::PgPool;
+use crate::{error::Error, utils::token};
+
+/* Constants */
+const TOKEN_LEN: usize = 40;
+
+/// What a token can be used for
+#[derive(Debug, Clone, Copy)]
+pub enum TokenPurpose { VerifyEmail, ResetPassword, ChangeEmail }
+
+impl TokenPurpose {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::VerifyEmail => "verify_email",
+            Self::ResetPassword => "reset_password",
+            Self::ChangeEmail => "change_email",
+        }
+    }
+
+    /// How many minutes a token is valid for
+    fn lifetime(&self) -> i32 {
+        match self {
+            Self::VerifyEmail => 24 * 60,
+            Self::ResetPassword => 60,
+            Self::ChangeEmail => 24 * 60,
+        }
+    }
+}
+
+/// Handles user token related things
+pub struct UserToken;
+
+impl UserToken {
+    /// Creates a new token which was sent to `email`, and
+    /// returns it. Earlier unused tokens of the same purpose
+    /// stop working so only the newest email is valid.
+    pub async fn issue(pool: &PgPool, user_id: i64, purpose: TokenPurpose, email: &str) -> Result<String, Error> {
+        let secret = token::generate(TOKEN_LEN);
+        let mut tx = pool.begin().await.map_err(Error::new)?;
+
+        Self::invalidate(&mut *tx, user_id, purpose).await?;
+        sqlx::query!(r#"
+            INSERT INTO user_tokens
+            (user_id, purpose, token_hash, email, expires_at)
+            VALUES ($1, $2, $3, $4, now() + make_interval(mins => $5))"#,
+            user_id, purpose.as_str(), token::hash(&secret), email, purpose.lifetime()
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::new)?;
+
+        tx.commit().await.map_err(Error::new)?;
+        Ok(secret)
+    }
+
+    /// Makes every unused token of `purpose` that the user
+    /// has been sent stop working
+    pub async fn invalidate<'a, E>(executor: E, user_id: i64, purpose: TokenPurpose) -> Result<(), Error>
+    where E: sqlx::Executor<'a, Database = sqlx::Postgres>
+    {
+        sqlx::query!(r#"
+            UPDATE user_tokens SET used_at = now()
+            WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL"#,
+            user_id, purpose.as_str()
+        )
+        .execute(executor)
+        .await
+        .map(|_| ())
+        .map_err(Error::new)
+    }
+
+    /// Marks a token as used and returns `(user_id, email)` of
+    /// it. Fails if it doesn't exist, has expired or has been
+    /// used before.
+    pub async fn consume<'a, E>(executor: E, secret: &str, purpose: TokenPurpose) -> Result<(i64, String), Error>
+    where E: sqlx::Executor<'a, Database = sqlx::Postgres>
+    {
+        sqlx::query!(r#"
+            UPDATE user_tokens SET used_at = now()
+            WHERE token_hash = $1
+                AND purpose = $2
+                AND used_at IS NULL
+                AND expires_at > now()
+            RETURNING user_id, email"#,
+            token::hash(secret), purpose.as_str()
+        )
+        .fetch_optional(executor)
+        .await
+        .map_err(Error::new)?
+        .map(|e| (e.user_id, e.email))
+        .ok_or(Error::failed_attempt("Invalid or expired token"))
+    }
+}
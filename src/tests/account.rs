@@ -0,0 +1,121 @@
+//! Purging an account takes everything of the user's with
+//! it, but nothing of anyone else's.
+
+/* Imports */
+use sqlx::PgPool;
+use crate::models::{account::Account, page::PageRequest, post::Post, thread::Thread};
+use super::{post, users};
+
+/// Replies to `replies_to` as `poster_id`, returns the id
+async fn reply(pool: &PgPool, poster_id: i64, replies_to: i64) -> i64 {
+    Post::new(poster_id, "Reply".to_string(), Some(replies_to), None).insert_into(pool).await.unwrap();
+    sqlx::query_scalar!(
+        r#"SELECT MAX(id) AS "id!" FROM posts WHERE poster_id = $1 AND replies_to = $2"#,
+        poster_id, replies_to
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+#[sqlx::test]
+async fn replies_survive_purge(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, 3).await;
+    let (a, b, c) = (users[0], users[1], users[2]);
+
+    // A's post is replied to by B (and B by C), and by A themselves
+    let post_id = post(pool, a).await;
+    let reply_b = reply(pool, b, post_id).await;
+    let reply_c = reply(pool, c, reply_b).await;
+    let own_reply = reply(pool, a, post_id).await;
+    let own_reply_b = reply(pool, a, reply_b).await;
+    let lonely = post(pool, a).await;
+
+    sqlx::query!(
+        "UPDATE users SET deletion_requested_at = now() - interval '60 days' WHERE id = $1",
+        a
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    assert!(Account::purge(pool, a).await.unwrap());
+
+    // Everyone else's replies are where they were
+    let survivors = sqlx::query!(
+        "SELECT id, replies_to FROM posts WHERE id = ANY($1) ORDER BY id",
+        &[reply_b, reply_c]
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        survivors.iter().map(|e| (e.id, e.replies_to)).collect::<Vec<_>>(),
+        vec![(reply_b, Some(post_id)), (reply_c, Some(reply_b))]
+    );
+
+    // A's post is an empty tombstone without a poster, counting
+    // only B's reply, and the rest of A's posts are gone
+    let tombstone = sqlx::query!(
+        "SELECT content, poster_id, deleted_at, total_replies FROM posts WHERE id = $1",
+        post_id
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap();
+    assert_eq!(tombstone.content, "");
+    assert_eq!(tombstone.poster_id, None);
+    assert!(tombstone.deleted_at.is_some());
+    assert_eq!(tombstone.total_replies, 1);
+
+    let reply_b_replies = sqlx::query_scalar!("SELECT total_replies FROM posts WHERE id = $1", reply_b)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    assert_eq!(reply_b_replies, 1);
+
+    let gone = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM posts WHERE id = ANY($1)"#,
+        &[own_reply, own_reply_b, lonely]
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap();
+    assert_eq!(gone, 0);
+}
+
+/// Threads rooted at a purged account's post have no author
+/// to put first, but load (and paginate) all the same
+#[sqlx::test]
+async fn threads_survive_purge(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, 2).await;
+    let (a, b) = (users[0], users[1]);
+
+    let post_id = post(pool, a).await;
+    let reply_b = reply(pool, b, post_id).await;
+    let replies = [reply(pool, b, reply_b).await, reply(pool, b, reply_b).await];
+
+    sqlx::query!(
+        "UPDATE users SET deletion_requested_at = now() - interval '60 days' WHERE id = $1",
+        a
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    assert!(Account::purge(pool, a).await.unwrap());
+
+    let page = serde_json::from_value::<PageRequest>(serde_json::json!({ "limit": 1 })).unwrap();
+    let first = Thread::get(pool, b, reply_b, 2, &page).await.unwrap();
+    assert_eq!(first.ancestors.len(), 1);
+
+    let page = serde_json::from_value::<PageRequest>(serde_json::json!({
+        "limit": 1, "cursor": first.replies.next_cursor.unwrap()
+    })).unwrap();
+    let second = Thread::get(pool, b, reply_b, 2, &page).await.unwrap();
+    let mut seen = first.replies.items.iter().chain(&second.replies.items)
+        .filter_map(|e| e.post.id)
+        .collect::<Vec<_>>();
+    seen.sort_unstable();
+    assert_eq!(seen, replies);
+}
@@ -0,0 +1,332 @@
+//! Toggles hammered from many connections at once. Whatever
+//! order they end up running in, the counters have to match
+//! the rows they count.
+
+/* Imports */
+use std::future::Future;
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
+use sqlx::PgPool;
+use crate::{error::Error, models::{edge::{Block, Bookmark, Edge, EdgeState, Follow, Like, Repost}, opinion::Opinion, post::Post}};
+use super::{all, post, users};
+
+/* Constants */
+/// How many users toggle at the same time
+const USERS: usize = 10;
+/// How many times each user toggles
+const ROUNDS: usize = 6;
+
+/// Every edge is turned on at the same time (each of them
+/// several times), then on and off, then off. `check` runs
+/// after each of those.
+async fn hammer<S, F, C, CF>(pool: &PgPool, edges: &[(i64, i64)], set: S, check: C)
+where
+    S: Fn(PgPool, i64, i64, bool) -> F,
+    F: Future<Output = Result<EdgeState, Error>> + Send + 'static,
+    C: Fn() -> CF,
+    CF: Future<Output = ()>
+{
+    for on in [Some(true), None, Some(false)] {
+        let toggles = edges.iter().flat_map(|(from, to)| (0..ROUNDS).map(|round| {
+            let on = on.unwrap_or(round % 2 == 0);
+            let toggle = set(pool.clone(), *from, *to, on);
+            async move { (on, toggle.await) }
+        })).collect::<Vec<_>>();
+        for (on, result) in all(toggles).await {
+            assert_eq!(result.unwrap().on, on);
+        }
+
+        check().await;
+    }
+}
+
+/// Asserts that the counters of a post match its rows
+async fn assert_post_counters(pool: &PgPool, post_id: i64) {
+    let post = sqlx::query!(r#"
+        SELECT
+            posts.total_likes, posts.total_reposts, posts.total_replies,
+            (SELECT COUNT(*) FROM post_likes WHERE post_likes.post_id = $1) AS "likes!",
+            (SELECT COUNT(*) FROM reposts WHERE reposts.post_id = $1) AS "reposts!",
+            (
+                SELECT COUNT(*) FROM posts replies
+                WHERE replies.replies_to = $1 AND replies.deleted_at IS NULL
+            ) AS "replies!"
+        FROM posts WHERE posts.id = $1"#,
+        post_id
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap();
+
+    assert_eq!(post.total_likes, post.likes);
+    assert_eq!(post.total_reposts, post.reposts);
+    assert_eq!(post.total_replies, post.replies);
+}
+
+/// Asserts that everyone liking the post (except its
+/// poster) has a notification for it, and no one else
+async fn assert_like_notifications(pool: &PgPool, post_id: i64) {
+    let counts = sqlx::query!(r#"
+        SELECT
+            (
+                SELECT COUNT(*) FROM post_likes
+                WHERE post_likes.post_id = $1 AND post_likes.user_id <> posts.poster_id
+            ) AS "likes!",
+            (
+                SELECT COUNT(*) FROM notifications
+                WHERE notifications.post_id = $1 AND notifications.kind = 'like'
+            ) AS "notifications!"
+        FROM posts WHERE posts.id = $1"#,
+        post_id
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap();
+
+    assert_eq!(counts.likes, counts.notifications);
+}
+
+/// Asserts that the follower / following counts
+/// of every user match the follows
+async fn assert_follow_counts(pool: &PgPool) {
+    let drifted = sqlx::query_scalar!(r#"
+        SELECT users.id FROM users
+        WHERE users.followers <> (SELECT COUNT(*) FROM follows WHERE follows.followee_id = users.id)
+            OR users.following <> (SELECT COUNT(*) FROM follows WHERE follows.follower_id = users.id)"#
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap();
+
+    assert_eq!(drifted, Vec::<i64>::new());
+}
+
+/// Every user with the post
+fn post_edges(users: &[i64], post_id: i64) -> Vec<(i64, i64)> {
+    users.iter().map(|user_id| (*user_id, post_id)).collect()
+}
+
+/// Every user with every other user, so pairs
+/// of users toggle each other at the same time
+fn user_edges(users: &[i64]) -> Vec<(i64, i64)> {
+    users.iter()
+        .flat_map(|a| users.iter().map(move |b| (*a, *b)))
+        .filter(|(a, b)| a != b)
+        .collect()
+}
+
+#[sqlx::test]
+async fn likes_are_counted_once(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, USERS).await;
+    let post_id = post(pool, users[0]).await;
+
+    hammer(pool, &post_edges(&users, post_id), |pool, from, to, on| async move {
+        Like::set(&pool, from, to, on).await
+    }, || async move {
+        assert_post_counters(pool, post_id).await;
+        assert_like_notifications(pool, post_id).await;
+    }).await;
+}
+
+#[sqlx::test]
+async fn reposts_are_counted_once(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, USERS).await;
+    let post_id = post(pool, users[0]).await;
+
+    hammer(pool, &post_edges(&users, post_id), |pool, from, to, on| async move {
+        Repost::set(&pool, from, to, on).await
+    }, || assert_post_counters(pool, post_id)).await;
+}
+
+#[sqlx::test]
+async fn bookmarks_are_kept_once(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, USERS).await;
+    let post_id = post(pool, users[0]).await;
+
+    hammer(pool, &post_edges(&users, post_id), |pool, from, to, on| async move {
+        Bookmark::set(&pool, from, to, on).await
+    }, || async {}).await;
+    assert_eq!(Bookmark::set(pool, users[0], post_id, false).await.unwrap().count, 0);
+}
+
+#[sqlx::test]
+async fn follows_are_counted_once(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, USERS).await;
+
+    hammer(pool, &user_edges(&users), |pool, from, to, on| async move {
+        Follow::set(&pool, from, to, on).await
+    }, || assert_follow_counts(pool)).await;
+}
+
+#[sqlx::test]
+async fn blocks_are_kept_once(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, USERS).await;
+
+    hammer(pool, &user_edges(&users), |pool, from, to, on| async move {
+        Block::set(&pool, from, to, on).await
+    }, || async {}).await;
+    assert_eq!(Block::set(pool, users[0], users[1], false).await.unwrap().count, 0);
+}
+
+#[sqlx::test]
+async fn opinion_votes_are_counted_once(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, USERS).await;
+    let post_id = post(pool, users[0]).await;
+    let opinion_id = sqlx::query_scalar!(
+        "INSERT INTO post_opinions (user_id, post_id, opinion) VALUES ($1, $2, 'nice') RETURNING id",
+        users[0], post_id
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap();
+
+    for to in [Some(true), None, Some(false)] {
+        let toggles = users.iter().flat_map(|user_id| (0..ROUNDS).map(move |round| {
+            let (pool, user_id) = (pool.clone(), *user_id);
+            let to = to.unwrap_or(round % 2 == 0);
+            async move { Opinion::set_vote(&pool, post_id, opinion_id, user_id, to).await }
+        }));
+        for result in all(toggles).await {
+            result.unwrap();
+        }
+
+        let opinion = sqlx::query!(r#"
+            SELECT
+                post_opinions.votes,
+                (SELECT COUNT(*) FROM post_opinion_votes WHERE post_opinion_votes.opinion_id = $1) AS "count!"
+            FROM post_opinions WHERE post_opinions.id = $1"#,
+            opinion_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        assert_eq!(opinion.votes as i64, opinion.count);
+    }
+}
+
+/// Votes sent with the id of another post than the
+/// opinion's are refused, and never counted
+#[sqlx::test]
+async fn opinion_votes_need_their_post(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, USERS).await;
+    let post_id = post(pool, users[0]).await;
+    let other_post_ids = [post(pool, users[1]).await, post(pool, users[2]).await];
+    let opinion_id = sqlx::query_scalar!(
+        "INSERT INTO post_opinions (user_id, post_id, opinion) VALUES ($1, $2, 'nice') RETURNING id",
+        users[0], post_id
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap();
+    let votes = || async move {
+        sqlx::query!(r#"
+            SELECT
+                post_opinions.votes,
+                (SELECT COUNT(*) FROM post_opinion_votes WHERE post_opinion_votes.opinion_id = $1) AS "count!"
+            FROM post_opinions WHERE post_opinions.id = $1"#,
+            opinion_id
+        )
+        .fetch_one(pool)
+        .await
+        .map(|e| (e.votes as i64, e.count))
+        .unwrap()
+    };
+
+    for to in [true, false] {
+        let toggles = users.iter().flat_map(|user_id| (0..ROUNDS).map(move |round| {
+            let (pool, user_id) = (pool.clone(), *user_id);
+            let post_id = match round % 3 {
+                0 => post_id,
+                round => other_post_ids[round - 1]
+            };
+            async move { (post_id, Opinion::set_vote(&pool, post_id, opinion_id, user_id, to).await) }
+        }));
+        for (sent_post_id, result) in all(toggles).await {
+            match sent_post_id == post_id {
+                true => result.unwrap(),
+                false => assert_eq!(result.unwrap_err().status_code(), StatusCode::NOT_FOUND)
+            }
+        }
+
+        let expected = if to { USERS as i64 } else { 0 };
+        assert_eq!(votes().await, (expected, expected));
+
+        // Only the poster of the opinion's post hears about it
+        let misdirected = sqlx::query_scalar!(r#"
+            SELECT COUNT(*) AS "count!" FROM notifications
+            WHERE notifications.kind = 'opinion_vote' AND notifications.user_id <> $1"#,
+            users[0]
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        assert_eq!(misdirected, 0);
+    }
+}
+
+/// Replies are posted and deleted at the same time, and
+/// then the post itself is deleted while being replied to
+#[sqlx::test]
+async fn replies_are_counted_once(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, USERS).await;
+    let post_id = post(pool, users[0]).await;
+
+    let replies = users.iter().flat_map(|user_id| (0..ROUNDS).map(move |_| {
+        let (pool, user_id) = (pool.clone(), *user_id);
+        async move { Post::new(user_id, "Reply #tag".to_string(), Some(post_id), None).insert_into(&pool).await }
+    }));
+    for result in all(replies).await {
+        result.unwrap();
+    }
+    assert_post_counters(pool, post_id).await;
+
+    // Every other reply is deleted while more are posted
+    let reply_ids = sqlx::query!(
+        r#"SELECT id, poster_id AS "poster_id!" FROM posts WHERE replies_to = $1 ORDER BY id"#,
+        post_id
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap();
+    let deletes = reply_ids.iter().step_by(2).map(|reply| {
+        let (pool, user_id, reply_id) = (pool.clone(), reply.poster_id, reply.id);
+        tokio::spawn(async move { Post::delete(&pool, user_id, reply_id).await })
+    }).collect::<Vec<_>>();
+    let replies = users.iter().map(|user_id| {
+        let (pool, user_id) = (pool.clone(), *user_id);
+        async move { Post::new(user_id, "Reply".to_string(), Some(post_id), None).insert_into(&pool).await }
+    });
+    for result in all(replies).await {
+        result.unwrap();
+    }
+    for delete in deletes {
+        delete.await.unwrap().unwrap();
+    }
+    assert_post_counters(pool, post_id).await;
+
+    // Replies racing the deletion either make it in before
+    // it or are refused, never counted on a deleted post
+    let delete = tokio::spawn({
+        let (pool, poster_id) = (pool.clone(), users[0]);
+        async move { Post::delete(&pool, poster_id, post_id).await }
+    });
+    let replies = users.iter().map(|user_id| {
+        let (pool, user_id) = (pool.clone(), *user_id);
+        async move { Post::new(user_id, "Late reply".to_string(), Some(post_id), None).insert_into(&pool).await }
+    });
+    for result in all(replies).await {
+        if let Err(e) = result {
+            assert_eq!(e.status_code(), StatusCode::NOT_FOUND);
+        }
+    }
+    delete.await.unwrap().unwrap();
+    assert_post_counters(pool, post_id).await;
+}
@@ -0,0 +1,100 @@
+//! Deleted posts disappear from the quotes of them too,
+//! not only from where they were posted, and can't be
+//! liked, reposted or bookmarked anymore.
+
+/* Imports */
+use actix_web::{http::StatusCode, ResponseError};
+use sqlx::PgPool;
+use crate::models::{edge::{Bookmark, Edge, Like, Repost}, post::Post, post_citation::{CitationRequest, PostCitation}};
+use super::{post, users};
+
+/// Quotes `post_id` as `poster_id`, returns the id of the quote
+async fn quote(pool: &PgPool, poster_id: i64, post_id: i64) -> i64 {
+    let citation = PostCitation::new(pool, CitationRequest { post_id, start: 0, end: 5 }).await.unwrap();
+    Post::new(poster_id, "Quote".to_string(), None, Some(citation)).insert_into(pool).await.unwrap();
+    sqlx::query_scalar!(
+        r#"SELECT MAX(id) AS "id!" FROM posts WHERE poster_id = $1 AND citation IS NOT NULL"#,
+        poster_id
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+/// What a quote shows of the post it cites
+async fn content_slice(pool: &PgPool, quote_id: i64) -> String {
+    sqlx::query_scalar!(
+        r#"SELECT citation->>'content_slice' AS "content_slice!" FROM posts WHERE id = $1"#,
+        quote_id
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+#[sqlx::test]
+async fn quotes_of_deleted_posts_are_emptied(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, 2).await;
+    let post_id = post(pool, users[0]).await;
+    let quote_id = quote(pool, users[1], post_id).await;
+    assert_eq!(content_slice(pool, quote_id).await, "Hello");
+
+    Post::delete(pool, users[0], post_id).await.unwrap();
+    assert_eq!(content_slice(pool, quote_id).await, "");
+}
+
+/// A quote published while its post was being deleted still
+/// has the content, until the next purge
+#[sqlx::test]
+async fn purge_empties_quotes_of_purged_posts(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, 2).await;
+    let post_id = post(pool, users[0]).await;
+    let quote_id = quote(pool, users[1], post_id).await;
+    sqlx::query!("UPDATE posts SET deleted_at = now() - interval '60 days' WHERE id = $1", post_id)
+        .execute(pool)
+        .await
+        .unwrap();
+
+    Post::purge_deleted(pool).await.unwrap();
+    assert_eq!(content_slice(pool, quote_id).await, "");
+    let purged = sqlx::query_scalar!(r#"SELECT NOT EXISTS(SELECT 1 FROM posts WHERE id = $1) AS "purged!""#, post_id)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    assert!(purged);
+}
+
+#[sqlx::test]
+async fn deleted_posts_have_no_edges(pool: PgPool) {
+    let pool = &pool;
+    let users = users(pool, 2).await;
+    let post_id = post(pool, users[0]).await;
+    Like::set(pool, users[1], post_id, true).await.unwrap();
+    Post::delete(pool, users[0], post_id).await.unwrap();
+
+    for on in [true, false] {
+        let results = [
+            Like::set(pool, users[1], post_id, on).await,
+            Repost::set(pool, users[1], post_id, on).await,
+            Bookmark::set(pool, users[1], post_id, on).await,
+        ];
+        for result in results {
+            assert_eq!(result.unwrap_err().status_code(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    let post = sqlx::query!(r#"
+        SELECT
+            posts.total_likes, posts.total_reposts,
+            (SELECT COUNT(*) FROM notifications WHERE notifications.post_id = $1) AS "notifications!",
+            (SELECT COUNT(*) FROM post_bookmarks WHERE post_bookmarks.post_id = $1) AS "bookmarks!"
+        FROM posts WHERE posts.id = $1"#,
+        post_id
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap();
+    assert_eq!((post.total_likes, post.total_reposts, post.notifications, post.bookmarks), (1, 0, 0, 0));
+}
@@ -0,0 +1,59 @@
+//! Tests which need a database. Every `#[sqlx::test]` gets a
+//! fresh one with all migrations applied, created on the
+//! server `DATABASE_URL` points to.
+
+/* Modules */
+mod account;
+mod concurrency;
+mod deletion;
+
+/* Imports */
+use std::future::Future;
+use sqlx::PgPool;
+use tokio::task::JoinSet;
+
+/// Creates `n` users, returns their ids
+async fn users(pool: &PgPool, n: usize) -> Vec<i64> {
+    let mut ids = Vec::with_capacity(n);
+    for _ in 0..n {
+        let id = sqlx::query_scalar!(r#"
+            INSERT INTO users (handle, displayname, email)
+            SELECT 'user' || n, 'User', 'user' || n || '@example.com'
+            FROM (SELECT COUNT(*) AS n FROM users) AS count
+            RETURNING id"#
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        ids.push(id);
+    }
+
+    ids
+}
+
+/// Creates a post, returns its id
+async fn post(pool: &PgPool, poster_id: i64) -> i64 {
+    sqlx::query_scalar!(
+        "INSERT INTO posts (content, poster_id) VALUES ('Hello', $1) RETURNING id",
+        poster_id
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+/// Runs every future at the same time, and returns
+/// their outputs once all of them are done
+async fn all<F>(futures: impl IntoIterator<Item = F>) -> Vec<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static
+{
+    let mut set = futures.into_iter().collect::<JoinSet<_>>();
+    let mut outputs = Vec::new();
+    while let Some(output) = set.join_next().await {
+        outputs.push(output.unwrap());
+    }
+
+    outputs
+}
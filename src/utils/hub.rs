@@ -0,0 +1,183 @@
+//! Live updates. Anything which should reach clients
+//! instantly (see `handlers::stream`) is published here
+//! as an `Event` and broadcast to every open stream, which
+//! picks out what concerns its user.
+//!
+//! With `stream_fanout` events go through postgres
+//! `LISTEN/NOTIFY` instead, so that streams on every
+//! instance get them, not only the one they happened on.
+//! They're all sent from one task, so that they arrive in
+//! the order they were published in (e.g an older
+//! `Counters` never overtakes a newer one).
+
+/* Imports */
+use std::{sync::OnceLock, time::Duration};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgListener, PgPool};
+use tokio::sync::{broadcast, mpsc};
+use crate::{models::notification::NotificationKind, utils::logger::log};
+
+/* Constants */
+/// How many events a slow stream can fall behind before
+/// it starts missing them
+const CAPACITY: usize = 1024;
+const CHANNEL: &str = "twitter2_events";
+const LISTENER_RETRY: Duration = Duration::from_secs(1);
+/// Most events sent to postgres in one query
+const PUBLISH_BATCH: usize = 64;
+static HUB: OnceLock<Hub> = OnceLock::new();
+
+/// Something that happened
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// `user_id` got a notification
+    Notification {
+        user_id: i64,
+        notification_id: i64,
+        kind: NotificationKind,
+        actor_id: i64,
+    },
+
+    /// A post was published
+    Post {
+        post_id: i64,
+        poster_id: i64,
+        replies_to: Option<i64>,
+    },
+
+    /// The like, reply or repost count of a post changed
+    Counters {
+        post_id: i64,
+        total_likes: i64,
+        total_replies: i64,
+        total_reposts: i64,
+    },
+
+    /// A direct message was sent to `recipients`
+    Message {
+        conversation_id: i64,
+        message_id: i64,
+        sender_id: i64,
+        recipients: Vec<i64>,
+    },
+
+    /// Open streams of the follower need to know
+    /// whose posts to push from now on
+    Follow {
+        follower_id: i64,
+        followee_id: i64,
+        follow: bool,
+    },
+}
+
+pub struct Hub {
+    sender: broadcast::Sender<Event>,
+
+    /// To the task sending events to postgres,
+    /// set if they're fanned out
+    outbox: Option<mpsc::UnboundedSender<Event>>,
+}
+
+impl Hub {
+    /// Creates the hub, and starts listening for events from
+    /// postgres if `fanout`. Should be called in main, before
+    /// anything is published.
+    pub fn init(pool: &PgPool, fanout: bool) -> &'static Self {
+        HUB.get_or_init(|| {
+            let sender = broadcast::channel(CAPACITY).0;
+            let outbox = fanout.then(|| {
+                let (outbox, receiver) = mpsc::unbounded_channel();
+                Self::spawn_publisher(pool.clone(), receiver);
+                Self::spawn_listener(pool.clone(), sender.clone());
+                outbox
+            });
+
+            Self { sender, outbox }
+        })
+    }
+
+    /// Sends an event to every stream (on every instance if
+    /// fanned out). Never fails, nobody might be listening.
+    pub fn publish(&self, event: Event) {
+        match &self.outbox {
+            Some(outbox) => { let _ = outbox.send(event); },
+            None => { let _ = self.sender.send(event); }
+        }
+    }
+
+    /// Publishes every event, in order
+    pub fn publish_all(&self, events: impl IntoIterator<Item = Event>) {
+        for event in events {
+            self.publish(event);
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Sends published events to postgres, in order. Whatever
+    /// has piled up while the previous query ran is sent in
+    /// the next one.
+    fn spawn_publisher(pool: PgPool, mut receiver: mpsc::UnboundedReceiver<Event>) {
+        tokio::spawn(async move {
+            let mut events = Vec::with_capacity(PUBLISH_BATCH);
+            while receiver.recv_many(&mut events, PUBLISH_BATCH).await > 0 {
+                let payloads = events.drain(..)
+                    .filter_map(|event| serde_json::to_string(&event)
+                        .map_err(|e| log::red("hub", e))
+                        .ok()
+                    )
+                    .collect::<Vec<String>>();
+
+                if let Err(e) = sqlx::query(r#"
+                    SELECT pg_notify($1, events.payload)
+                    FROM unnest($2::text[]) WITH ORDINALITY AS events(payload, position)
+                    ORDER BY events.position"#
+                )
+                .bind(CHANNEL)
+                .bind(&payloads)
+                .execute(&pool)
+                .await
+                {
+                    log::red("hub", format!("Could not publish {} events: {e}", payloads.len()));
+                }
+            }
+        });
+    }
+
+    /// Forwards events from postgres to the local streams.
+    /// `PgListener` reconnects by itself if the connection
+    /// drops, events sent meanwhile are lost.
+    fn spawn_listener(pool: PgPool, sender: broadcast::Sender<Event>) {
+        tokio::spawn(async move {
+            let mut listener = match PgListener::connect_with(&pool).await {
+                Ok(e) => e,
+                Err(e) => return log::red("hub", format!("Could not connect listener: {e}"))
+            };
+            if let Err(e) = listener.listen(CHANNEL).await {
+                return log::red("hub", format!("Could not listen: {e}"));
+            }
+
+            loop {
+                match listener.recv().await {
+                    Ok(notification) => match serde_json::from_str::<Event>(notification.payload()) {
+                        Ok(event) => { let _ = sender.send(event); },
+                        Err(e) => log::red("hub", format!("Invalid event: {e}"))
+                    },
+                    Err(e) => {
+                        log::red("hub", format!("Listener error: {e}"));
+                        tokio::time::sleep(LISTENER_RETRY).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// The hub. Only broadcasts locally if `Hub::init` hasn't
+/// been called (e.g in tests).
+pub fn hub() -> &'static Hub {
+    HUB.get_or_init(|| Hub { sender: broadcast::channel(CAPACITY).0, outbox: None })
+}
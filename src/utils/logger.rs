@@ -7,7 +7,7 @@ pub enum Color {
 /* Constants */
 const TITLE_WIDTH: usize = 16;
 lazy_static::lazy_static! {
-    static ref DEBUG: bool = env!("DEBUG_LOG").parse::<bool>().unwrap();
+    static ref DEBUG: bool = crate::config::config().debug_log;
 }
 
 /// Diffrent methods for printing debug stuff
@@ -16,23 +16,23 @@ pub mod log {
     use super::Color;
 
     /// Logs if in debug mode
-    pub fn log(color: Color, title: &str, text: impl Display) -> () {
-        let padding = (super::TITLE_WIDTH - 2).checked_sub(title.len()).unwrap_or(0);
+    pub fn log(color: Color, title: &str, text: impl Display) {
+        let padding = (super::TITLE_WIDTH - 2).saturating_sub(title.len());
 
         if *super::DEBUG {
             println!("[{}]{} {}", get_colored(color, title), " ".repeat(padding), text)
         }
     }
-    pub fn blue(title: &str, text: impl Display) -> () { log(Color::Blue, title, text) }
-    pub fn red(title: &str, text: impl Display) -> () { log(Color::Red, title, text) }
-    pub fn green(title: &str, text: impl Display) -> () { log(Color::Green, title, text) }
-    pub fn yellow(title: &str, text: impl Display) -> () { log(Color::Yellow, title, text) }
-    pub fn cyan(title: &str, text: impl Display) -> () { log(Color::Cyan, title, text) }
-    pub fn magenta(title: &str, text: impl Display) -> () { log(Color::Magenta, title, text) }
-    pub fn white(title: &str, text: impl Display) -> () { log(Color::White, title, text) }
-    pub fn black(title: &str, text: impl Display) -> () { log(Color::Black, title, text) }
-    pub fn bright_red(title: &str, text: impl Display) -> () { log(Color::BrightRed, title, text) }
-    pub fn bright_green(title: &str, text: impl Display) -> () { log(Color::BrightGreen, title, text) }
+    pub fn blue(title: &str, text: impl Display) { log(Color::Blue, title, text) }
+    pub fn red(title: &str, text: impl Display) { log(Color::Red, title, text) }
+    pub fn green(title: &str, text: impl Display) { log(Color::Green, title, text) }
+    pub fn yellow(title: &str, text: impl Display) { log(Color::Yellow, title, text) }
+    pub fn cyan(title: &str, text: impl Display) { log(Color::Cyan, title, text) }
+    pub fn magenta(title: &str, text: impl Display) { log(Color::Magenta, title, text) }
+    pub fn white(title: &str, text: impl Display) { log(Color::White, title, text) }
+    pub fn black(title: &str, text: impl Display) { log(Color::Black, title, text) }
+    pub fn bright_red(title: &str, text: impl Display) { log(Color::BrightRed, title, text) }
+    pub fn bright_green(title: &str, text: impl Display) { log(Color::BrightGreen, title, text) }
 
     fn get_colored(color: Color, text: &str) -> String {
         let color_code = match color {
@@ -49,6 +49,6 @@ pub mod log {
             Color::Reset => "\x1b[0m",
         };
 
-        return format!("{}{}{}", color_code, text, "\x1b[0m");
+        format!("{}{}{}", color_code, text, "\x1b[0m")
     }
 }
@@ -0,0 +1,86 @@
+//! The migrations in `/migrations`, embedded into the
+//! binary. They're checked against the database on every
+//! start, and applied with `--migrate` (or `migrate = true`
+//! in the config).
+
+/* Imports */
+use std::collections::HashSet;
+use sqlx::{migrate::Migrator, PgConnection, PgPool};
+use crate::{error::Error, utils::logger::log};
+
+/* Constants */
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+/// Key of the advisory lock held while checking and applying
+/// migrations, so replicas booting at the same time take
+/// turns instead of racing each other
+const LOCK_KEY: i64 = 0x7477_6974_7465_7232;
+
+/// Makes sure the database schema matches what this binary
+/// expects. Fails if the database has migrations we don't know
+/// about (it was migrated by a newer version), or is missing
+/// some and `apply` is false.
+pub async fn run(pool: &PgPool, apply: bool) -> Result<(), Error> {
+    let mut conn = pool.acquire().await.map_err(Error::new)?;
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(LOCK_KEY)
+        .execute(&mut *conn)
+        .await
+        .map_err(Error::new)?;
+
+    let result = check_and_apply(&mut conn, apply).await;
+
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(LOCK_KEY)
+        .execute(&mut *conn)
+        .await
+        .map_err(Error::new)?;
+    result
+}
+
+async fn check_and_apply(conn: &mut PgConnection, apply: bool) -> Result<(), Error> {
+    let applied = applied_versions(conn).await?;
+    let known = MIGRATOR.iter()
+        .filter(|e| !e.migration_type.is_down_migration())
+        .map(|e| e.version)
+        .collect::<HashSet<i64>>();
+
+    if let Some(version) = applied.iter().filter(|e| !known.contains(e)).max() {
+        return Err(Error::new(format!(
+            "The database has migration {version} which this binary doesn't know about, \
+            it was probably migrated by a newer version. Refusing to start."
+        )));
+    }
+
+    let pending = known.iter().filter(|e| !applied.contains(e)).count();
+    if pending == 0 {
+        return Ok(());
+    }
+    if !apply {
+        return Err(Error::new(format!(
+            "The database is missing {pending} migration(s). Start with --migrate \
+            (or set MIGRATE=true) to apply them."
+        )));
+    }
+
+    log::yellow("Migrations", format!("Applying {pending} migration(s)"));
+    MIGRATOR.run(&mut *conn).await.map_err(Error::new)
+}
+
+/// Versions of every successfully applied migration. Checked
+/// at runtime since `_sqlx_migrations` doesn't exist in a fresh
+/// database.
+async fn applied_versions(conn: &mut PgConnection) -> Result<HashSet<i64>, Error> {
+    let exists = sqlx::query_scalar::<_, bool>("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(Error::new)?;
+    if !exists {
+        return Ok(HashSet::new());
+    }
+
+    sqlx::query_scalar::<_, i64>("SELECT version FROM _sqlx_migrations WHERE success")
+        .fetch_all(&mut *conn)
+        .await
+        .map(|e| e.into_iter().collect())
+        .map_err(Error::new)
+}
@@ -1 +1,5 @@
 pub mod logger;
+pub mod mailer;
+pub mod token;
+pub mod migrate;
+pub mod hub;
@@ -0,0 +1,22 @@
+//! Random secrets that are handed out to clients
+//! (refresh tokens, emailed links etc) and the
+//! hashes we store instead of them.
+
+/* Imports */
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha2::{Digest, Sha256};
+
+/// Random alphanumeric string of `len` characters
+pub fn generate(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// SHA-256 of a secret. These secrets are long and random
+/// so a fast hash is fine, unlike for passwords
+pub fn hash(secret: &str) -> Vec<u8> {
+    Sha256::digest(secret.as_bytes()).to_vec()
+}